@@ -0,0 +1,38 @@
+//! Program error types.
+
+use solana_program::program_error::ProgramError;
+
+/// Errors that can be returned by the PayTube escrow program.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum PayTubeEscrowError {
+    /// The final ledger doesn't cover every deposit in the channel.
+    #[error("final ledger doesn't cover every deposit")]
+    DepositCountMismatch,
+    /// The same deposit appears more than once in the final ledger.
+    #[error("deposit appears more than once in the final ledger")]
+    DuplicateDeposit,
+    /// The final ledger pays out more of a mint than was deposited.
+    #[error("final ledger pays out more than was deposited")]
+    LedgerMismatch,
+    /// An account doesn't belong to the expected owner.
+    #[error("account owner mismatch")]
+    OwnerMismatch,
+    /// The signer isn't the channel's operator.
+    #[error("signer is not the channel operator")]
+    OperatorMismatch,
+    /// A deposit of zero was made.
+    #[error("deposit amount must be greater than zero")]
+    ZeroDeposit,
+    /// A deposit was withdrawn before the channel expired.
+    #[error("channel has not expired")]
+    ChannelNotExpired,
+    /// A channel was closed after it expired.
+    #[error("channel has expired")]
+    ChannelExpired,
+}
+
+impl From<PayTubeEscrowError> for ProgramError {
+    fn from(error: PayTubeEscrowError) -> Self {
+        ProgramError::Custom(error as u32)
+    }
+}
@@ -0,0 +1,181 @@
+//! Program instructions.
+
+use solana_program::program_error::ProgramError;
+
+/// Instructions supported by the PayTube escrow program.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PayTubeEscrowInstruction {
+    /// Open a new channel, which the operator must close before the unix
+    /// timestamp `expires_at`. From then on, the channel can no longer be
+    /// closed, and each depositor may withdraw their deposit in full.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[w, s]` The operator, who pays for the channel account.
+    /// 1. `[w]` The channel account.
+    /// 2. `[]` The system program.
+    OpenChannel { channel_id: u64, expires_at: i64 },
+    /// Create the vault holding a channel's escrowed tokens of a mint.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[w, s]` The operator, who pays for the vault.
+    /// 1. `[]` The channel account.
+    /// 2. `[w]` The vault.
+    /// 3. `[]` The mint.
+    /// 4. `[]` The system program.
+    /// 5. `[]` The SPL Token program.
+    CreateVault,
+    /// Deposit SOL into a channel.
+    ///
+    /// The operator co-signs every deposit, so nobody can change the set of
+    /// deposits the final ledger must cover without their consent.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[w, s]` The depositor.
+    /// 1. `[s]` The operator.
+    /// 2. `[w]` The channel account.
+    /// 3. `[w]` The depositor's deposit account for SOL.
+    /// 4. `[]` The system program.
+    DepositNative { amount: u64 },
+    /// Deposit tokens into a channel, co-signed by the operator as for
+    /// `DepositNative`.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[w, s]` The depositor.
+    /// 1. `[s]` The operator.
+    /// 2. `[w]` The channel account.
+    /// 3. `[w]` The depositor's deposit account for the mint.
+    /// 4. `[w]` The depositor's token account.
+    /// 5. `[w]` The channel's vault for the mint.
+    /// 6. `[]` The mint.
+    /// 7. `[]` The system program.
+    /// 8. `[]` The SPL Token program.
+    DepositToken { amount: u64 },
+    /// Close a channel, paying out every deposit according to the operator's
+    /// final ledger. Fails once the channel has expired, so closing can't
+    /// race depositors' withdrawals.
+    ///
+    /// `final_amounts` holds the amount owed to each deposit's owner, in the
+    /// same order as the deposits are provided. They may total less than was
+    /// deposited of a mint, but never more.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[w, s]` The operator, who receives the rent for the channel
+    ///    account and every vault, along with any SOL left in the channel.
+    /// 1. `[w]` The channel account.
+    /// 2. `[]` The SPL Token program.
+    /// 3. For each deposit:
+    ///    * `[w]` The deposit account.
+    ///    * `[w]` The deposit's owner, who receives the deposit account's rent
+    ///      and any SOL owed.
+    ///    * `[w]` The owner's token account for the deposit's mint, which
+    ///      receives any tokens owed. For SOL deposits, the owner again.
+    /// 4. For every vault in the channel:
+    ///    * `[w]` The vault.
+    ///    * `[w]` The operator's token account for the vault's mint, which
+    ///      receives any tokens left in the vault once every deposit is paid
+    ///      out. It only needs to exist if there are any.
+    CloseChannel { final_amounts: Vec<u64> },
+    /// Withdraw a deposit in full from a channel which has expired without
+    /// being closed.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[w, s]` The depositor, who receives the deposit account's rent and
+    ///    any SOL deposited.
+    /// 1. `[w]` The channel account.
+    /// 2. `[w]` The depositor's deposit account.
+    /// 3. For token deposits only:
+    ///    * `[w]` The depositor's token account for the deposit's mint.
+    ///    * `[w]` The channel's vault for the mint.
+    ///    * `[]` The SPL Token program.
+    Withdraw,
+}
+
+impl PayTubeEscrowInstruction {
+    /// Unpack an instruction from its serialized form.
+    pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
+        let (&tag, rest) = input
+            .split_first()
+            .ok_or(ProgramError::InvalidInstructionData)?;
+        Ok(match tag {
+            0 => {
+                if rest.len() != 16 {
+                    return Err(ProgramError::InvalidInstructionData);
+                }
+                let (channel_id, expires_at) = rest.split_at(8);
+                Self::OpenChannel {
+                    channel_id: unpack_u64(channel_id)?,
+                    expires_at: unpack_u64(expires_at)? as i64,
+                }
+            }
+            1 => Self::CreateVault,
+            2 => Self::DepositNative {
+                amount: unpack_u64(rest)?,
+            },
+            3 => Self::DepositToken {
+                amount: unpack_u64(rest)?,
+            },
+            4 => {
+                let count = rest
+                    .get(..4)
+                    .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+                    .ok_or(ProgramError::InvalidInstructionData)?;
+                let final_amounts = rest[4..]
+                    .chunks_exact(8)
+                    .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+                    .collect::<Vec<_>>();
+                if final_amounts.len() != count as usize || rest[4..].len() % 8 != 0 {
+                    return Err(ProgramError::InvalidInstructionData);
+                }
+                Self::CloseChannel { final_amounts }
+            }
+            5 => Self::Withdraw,
+            _ => return Err(ProgramError::InvalidInstructionData),
+        })
+    }
+
+    /// Pack an instruction into its serialized form.
+    pub fn pack(&self) -> Vec<u8> {
+        let mut data = vec![];
+        match self {
+            Self::OpenChannel {
+                channel_id,
+                expires_at,
+            } => {
+                data.push(0);
+                data.extend_from_slice(&channel_id.to_le_bytes());
+                data.extend_from_slice(&expires_at.to_le_bytes());
+            }
+            Self::CreateVault => data.push(1),
+            Self::DepositNative { amount } => {
+                data.push(2);
+                data.extend_from_slice(&amount.to_le_bytes());
+            }
+            Self::DepositToken { amount } => {
+                data.push(3);
+                data.extend_from_slice(&amount.to_le_bytes());
+            }
+            Self::CloseChannel { final_amounts } => {
+                data.push(4);
+                data.extend_from_slice(&(final_amounts.len() as u32).to_le_bytes());
+                for amount in final_amounts {
+                    data.extend_from_slice(&amount.to_le_bytes());
+                }
+            }
+            Self::Withdraw => data.push(5),
+        }
+        data
+    }
+}
+
+fn unpack_u64(input: &[u8]) -> Result<u64, ProgramError> {
+    input
+        .try_into()
+        .map(u64::from_le_bytes)
+        .map_err(|_| ProgramError::InvalidInstructionData)
+}
@@ -0,0 +1,33 @@
+//! PayTube escrow program.
+//!
+//! The on-chain companion to a PayTube channel. When a channel opens, each
+//! participant deposits SOL or SPL tokens into escrow, held by a channel
+//! account derived from the channel's operator. When the channel closes, the
+//! operator signs a single final ledger, stating how much of the escrowed
+//! funds each depositor is owed, and the program pays everyone out.
+//!
+//! The final ledger must account for every deposit, and can never pay out
+//! more of a mint than was deposited. Whatever depositors aren't paid back
+//! goes to the operator, who settles what they owe to anyone outside the
+//! escrow.
+//!
+//! The operator co-signs every deposit, so nobody else can add a deposit
+//! which the final ledger doesn't account for, and block the channel from
+//! closing until it expires.
+//!
+//! Only SOL and SPL Token mints can be escrowed. Token-2022 mints are
+//! rejected, so channels transacting in them must lock deposits by
+//! delegation instead.
+//!
+//! Every channel has an expiry. If the operator hasn't closed the channel by
+//! then, each depositor can withdraw their deposit in full, so funds are
+//! never stuck in escrow with an unresponsive operator.
+
+#[cfg(not(feature = "no-entrypoint"))]
+mod entrypoint;
+pub mod error;
+pub mod instruction;
+pub mod processor;
+pub mod state;
+
+solana_program::declare_id!("Ckmh8Wknf6u9pCMB5hahgYEckjVXzXQxEhZAfgskjNQU");
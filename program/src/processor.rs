@@ -0,0 +1,640 @@
+//! Program instruction processor.
+
+use {
+    crate::{
+        error::PayTubeEscrowError,
+        instruction::PayTubeEscrowInstruction,
+        state::{
+            channel_seeds, deposit_seeds, find_channel_address, find_deposit_address,
+            find_vault_address, vault_seeds, Channel, Deposit,
+        },
+    },
+    solana_program::{
+        account_info::{next_account_info, AccountInfo},
+        clock::Clock,
+        entrypoint::ProgramResult,
+        msg,
+        program::{invoke, invoke_signed},
+        program_error::ProgramError,
+        program_pack::Pack,
+        pubkey::Pubkey,
+        rent::Rent,
+        system_instruction, system_program,
+        sysvar::Sysvar,
+    },
+};
+
+/// Process a PayTube escrow instruction.
+pub fn process_instruction(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    input: &[u8],
+) -> ProgramResult {
+    match PayTubeEscrowInstruction::unpack(input)? {
+        PayTubeEscrowInstruction::OpenChannel {
+            channel_id,
+            expires_at,
+        } => {
+            msg!("Instruction: OpenChannel");
+            process_open_channel(program_id, accounts, channel_id, expires_at)
+        }
+        PayTubeEscrowInstruction::CreateVault => {
+            msg!("Instruction: CreateVault");
+            process_create_vault(program_id, accounts)
+        }
+        PayTubeEscrowInstruction::DepositNative { amount } => {
+            msg!("Instruction: DepositNative");
+            process_deposit_native(program_id, accounts, amount)
+        }
+        PayTubeEscrowInstruction::DepositToken { amount } => {
+            msg!("Instruction: DepositToken");
+            process_deposit_token(program_id, accounts, amount)
+        }
+        PayTubeEscrowInstruction::CloseChannel { final_amounts } => {
+            msg!("Instruction: CloseChannel");
+            process_close_channel(program_id, accounts, &final_amounts)
+        }
+        PayTubeEscrowInstruction::Withdraw => {
+            msg!("Instruction: Withdraw");
+            process_withdraw(program_id, accounts)
+        }
+    }
+}
+
+fn process_open_channel(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    channel_id: u64,
+    expires_at: i64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let operator_info = next_account_info(account_info_iter)?;
+    let channel_info = next_account_info(account_info_iter)?;
+    let _system_program_info = next_account_info(account_info_iter)?;
+
+    if !operator_info.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let (channel_address, bump) = find_channel_address(operator_info.key, channel_id);
+    if *channel_info.key != channel_address {
+        return Err(ProgramError::InvalidSeeds);
+    }
+    if !channel_info.data_is_empty() {
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    create_pda_account(
+        operator_info,
+        channel_info,
+        Channel::LEN,
+        program_id,
+        &channel_seeds(operator_info.key, &channel_id.to_le_bytes(), &[bump]),
+    )?;
+
+    Channel::pack(
+        Channel {
+            is_initialized: true,
+            operator: *operator_info.key,
+            channel_id,
+            bump,
+            num_deposits: 0,
+            expires_at,
+        },
+        &mut channel_info.data.borrow_mut(),
+    )
+}
+
+fn process_create_vault(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let operator_info = next_account_info(account_info_iter)?;
+    let channel_info = next_account_info(account_info_iter)?;
+    let vault_info = next_account_info(account_info_iter)?;
+    let mint_info = next_account_info(account_info_iter)?;
+    let _system_program_info = next_account_info(account_info_iter)?;
+    let token_program_info = next_account_info(account_info_iter)?;
+
+    if !operator_info.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let channel = load_channel(program_id, channel_info)?;
+    if channel.operator != *operator_info.key {
+        return Err(PayTubeEscrowError::OperatorMismatch.into());
+    }
+    check_token_program(token_program_info)?;
+
+    let (vault_address, bump) = find_vault_address(channel_info.key, mint_info.key);
+    if *vault_info.key != vault_address {
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    create_pda_account(
+        operator_info,
+        vault_info,
+        spl_token::state::Account::LEN,
+        &spl_token::id(),
+        &vault_seeds(channel_info.key, mint_info.key, &[bump]),
+    )?;
+
+    // The channel account is the vault's owner, so only this program can move
+    // the escrowed tokens.
+    invoke(
+        &spl_token::instruction::initialize_account3(
+            &spl_token::id(),
+            vault_info.key,
+            mint_info.key,
+            channel_info.key,
+        )?,
+        &[vault_info.clone(), mint_info.clone()],
+    )
+}
+
+fn process_deposit_native(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    amount: u64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let depositor_info = next_account_info(account_info_iter)?;
+    let operator_info = next_account_info(account_info_iter)?;
+    let channel_info = next_account_info(account_info_iter)?;
+    let deposit_info = next_account_info(account_info_iter)?;
+    let _system_program_info = next_account_info(account_info_iter)?;
+
+    if !depositor_info.is_signer || !operator_info.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if amount == 0 {
+        return Err(PayTubeEscrowError::ZeroDeposit.into());
+    }
+    let mut channel = load_channel(program_id, channel_info)?;
+    if channel.operator != *operator_info.key {
+        return Err(PayTubeEscrowError::OperatorMismatch.into());
+    }
+    let mut deposit = open_or_load_deposit(
+        program_id,
+        depositor_info,
+        channel_info,
+        &mut channel,
+        deposit_info,
+        None,
+    )?;
+
+    // Escrowed SOL is held by the channel account itself.
+    invoke(
+        &system_instruction::transfer(depositor_info.key, channel_info.key, amount),
+        &[depositor_info.clone(), channel_info.clone()],
+    )?;
+
+    deposit.amount = deposit
+        .amount
+        .checked_add(amount)
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    Deposit::pack(deposit, &mut deposit_info.data.borrow_mut())?;
+    Channel::pack(channel, &mut channel_info.data.borrow_mut())
+}
+
+fn process_deposit_token(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    amount: u64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let depositor_info = next_account_info(account_info_iter)?;
+    let operator_info = next_account_info(account_info_iter)?;
+    let channel_info = next_account_info(account_info_iter)?;
+    let deposit_info = next_account_info(account_info_iter)?;
+    let source_info = next_account_info(account_info_iter)?;
+    let vault_info = next_account_info(account_info_iter)?;
+    let mint_info = next_account_info(account_info_iter)?;
+    let _system_program_info = next_account_info(account_info_iter)?;
+    let token_program_info = next_account_info(account_info_iter)?;
+
+    if !depositor_info.is_signer || !operator_info.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if amount == 0 {
+        return Err(PayTubeEscrowError::ZeroDeposit.into());
+    }
+    check_token_program(token_program_info)?;
+    let mut channel = load_channel(program_id, channel_info)?;
+    if channel.operator != *operator_info.key {
+        return Err(PayTubeEscrowError::OperatorMismatch.into());
+    }
+
+    let (vault_address, _) = find_vault_address(channel_info.key, mint_info.key);
+    if *vault_info.key != vault_address {
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    let mut deposit = open_or_load_deposit(
+        program_id,
+        depositor_info,
+        channel_info,
+        &mut channel,
+        deposit_info,
+        Some(mint_info.key),
+    )?;
+
+    // The token program checks that the source and vault share a mint.
+    invoke(
+        &spl_token::instruction::transfer(
+            &spl_token::id(),
+            source_info.key,
+            vault_info.key,
+            depositor_info.key,
+            &[],
+            amount,
+        )?,
+        &[
+            source_info.clone(),
+            vault_info.clone(),
+            depositor_info.clone(),
+        ],
+    )?;
+
+    deposit.amount = deposit
+        .amount
+        .checked_add(amount)
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    Deposit::pack(deposit, &mut deposit_info.data.borrow_mut())?;
+    Channel::pack(channel, &mut channel_info.data.borrow_mut())
+}
+
+fn process_close_channel(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    final_amounts: &[u64],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let operator_info = next_account_info(account_info_iter)?;
+    let channel_info = next_account_info(account_info_iter)?;
+    let token_program_info = next_account_info(account_info_iter)?;
+
+    if !operator_info.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let channel = load_channel(program_id, channel_info)?;
+    if channel.operator != *operator_info.key {
+        return Err(PayTubeEscrowError::OperatorMismatch.into());
+    }
+    check_token_program(token_program_info)?;
+
+    // Once the channel expires, depositors may withdraw in full, so the
+    // operator's final ledger no longer applies.
+    if Clock::get()?.unix_timestamp >= channel.expires_at {
+        return Err(PayTubeEscrowError::ChannelExpired.into());
+    }
+
+    // Deposit accounts are unique per owner and mint, so every deposit is
+    // covered as long as there are as many distinct deposits as the channel
+    // has opened.
+    if final_amounts.len() != channel.num_deposits as usize {
+        return Err(PayTubeEscrowError::DepositCountMismatch.into());
+    }
+
+    let mut payouts: Vec<(&AccountInfo, &AccountInfo, &AccountInfo, Deposit, u64)> =
+        Vec::with_capacity(final_amounts.len());
+    let mut totals: Vec<(Option<Pubkey>, u64, u64)> = vec![];
+    for &final_amount in final_amounts {
+        let deposit_info = next_account_info(account_info_iter)?;
+        let owner_info = next_account_info(account_info_iter)?;
+        let destination_info = next_account_info(account_info_iter)?;
+
+        if deposit_info.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let deposit = Deposit::unpack(&deposit_info.data.borrow())?;
+        if deposit.channel != *channel_info.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if deposit.owner != *owner_info.key {
+            return Err(PayTubeEscrowError::OwnerMismatch.into());
+        }
+        if payouts
+            .iter()
+            .any(|(info, ..)| info.key == deposit_info.key)
+        {
+            return Err(PayTubeEscrowError::DuplicateDeposit.into());
+        }
+
+        let index = match totals.iter().position(|(mint, ..)| *mint == deposit.mint) {
+            Some(index) => index,
+            None => {
+                totals.push((deposit.mint, 0, 0));
+                totals.len() - 1
+            }
+        };
+        let (_, deposited, paid) = &mut totals[index];
+        *deposited = deposited
+            .checked_add(deposit.amount)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        *paid = paid
+            .checked_add(final_amount)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+
+        payouts.push((
+            deposit_info,
+            owner_info,
+            destination_info,
+            deposit,
+            final_amount,
+        ));
+    }
+
+    // The final ledger may never pay out more than was deposited. Whatever
+    // isn't paid back to depositors goes to the operator, along with the
+    // channel account and vaults, for settling what depositors owe to
+    // participants outside the escrow.
+    if totals.iter().any(|(_, deposited, paid)| paid > deposited) {
+        return Err(PayTubeEscrowError::LedgerMismatch.into());
+    }
+
+    let channel_id = channel.channel_id.to_le_bytes();
+    let bump = [channel.bump];
+    let signer_seeds = channel_seeds(&channel.operator, &channel_id, &bump);
+    let vault_infos = account_info_iter.as_slice();
+    if vault_infos.len() % 2 != 0 {
+        return Err(ProgramError::NotEnoughAccountKeys);
+    }
+
+    // Tokens are all moved, by the token program, before any lamports are
+    // moved directly, so the instruction's accounts balance at every CPI.
+    for &(_, _, destination_info, ref deposit, final_amount) in &payouts {
+        let Some(mint) = deposit.mint else {
+            continue;
+        };
+        let (vault_address, _) = find_vault_address(channel_info.key, &mint);
+        let vault_info = vault_infos
+            .iter()
+            .step_by(2)
+            .find(|info| *info.key == vault_address)
+            .ok_or(ProgramError::NotEnoughAccountKeys)?;
+
+        // Tokens may only be paid out to the deposit's owner.
+        let destination = spl_token::state::Account::unpack(&destination_info.data.borrow())?;
+        if destination.owner != deposit.owner {
+            return Err(PayTubeEscrowError::OwnerMismatch.into());
+        }
+
+        invoke_signed(
+            &spl_token::instruction::transfer(
+                &spl_token::id(),
+                vault_info.key,
+                destination_info.key,
+                channel_info.key,
+                &[],
+                final_amount,
+            )?,
+            &[
+                vault_info.clone(),
+                destination_info.clone(),
+                channel_info.clone(),
+            ],
+            &[&signer_seeds],
+        )?;
+    }
+
+    // Every depositor has been paid back, so what's left in a vault is mostly
+    // what they owe, their deposits less what they were paid back, which is
+    // released to the operator by design, for paying their creditors. Sweep
+    // it to the operator, so the vault can be closed and its rent returned to
+    // the operator too.
+    for accounts in vault_infos.chunks_exact(2) {
+        let (vault_info, leftover_info) = (&accounts[0], &accounts[1]);
+        let vault = spl_token::state::Account::unpack(&vault_info.data.borrow())?;
+        if vault.owner != *channel_info.key {
+            return Err(PayTubeEscrowError::OwnerMismatch.into());
+        }
+        if vault.amount > 0 {
+            let leftover = spl_token::state::Account::unpack(&leftover_info.data.borrow())?;
+            if leftover.owner != channel.operator {
+                return Err(PayTubeEscrowError::OwnerMismatch.into());
+            }
+            invoke_signed(
+                &spl_token::instruction::transfer(
+                    &spl_token::id(),
+                    vault_info.key,
+                    leftover_info.key,
+                    channel_info.key,
+                    &[],
+                    vault.amount,
+                )?,
+                &[
+                    vault_info.clone(),
+                    leftover_info.clone(),
+                    channel_info.clone(),
+                ],
+                &[&signer_seeds],
+            )?;
+        }
+        invoke_signed(
+            &spl_token::instruction::close_account(
+                &spl_token::id(),
+                vault_info.key,
+                operator_info.key,
+                channel_info.key,
+                &[],
+            )?,
+            &[
+                vault_info.clone(),
+                operator_info.clone(),
+                channel_info.clone(),
+            ],
+            &[&signer_seeds],
+        )?;
+    }
+
+    for (deposit_info, owner_info, _, deposit, final_amount) in payouts {
+        if deposit.mint.is_none() {
+            transfer_lamports(channel_info, owner_info, final_amount)?;
+        }
+        close_program_account(deposit_info, owner_info)?;
+    }
+
+    close_program_account(channel_info, operator_info)
+}
+
+fn process_withdraw(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let depositor_info = next_account_info(account_info_iter)?;
+    let channel_info = next_account_info(account_info_iter)?;
+    let deposit_info = next_account_info(account_info_iter)?;
+
+    if !depositor_info.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let mut channel = load_channel(program_id, channel_info)?;
+
+    // Until the channel expires, only the operator's final ledger can release
+    // deposits.
+    if Clock::get()?.unix_timestamp < channel.expires_at {
+        return Err(PayTubeEscrowError::ChannelNotExpired.into());
+    }
+
+    if deposit_info.owner != program_id {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    let deposit = Deposit::unpack(&deposit_info.data.borrow())?;
+    if deposit.channel != *channel_info.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    if deposit.owner != *depositor_info.key {
+        return Err(PayTubeEscrowError::OwnerMismatch.into());
+    }
+
+    match deposit.mint {
+        None => transfer_lamports(channel_info, depositor_info, deposit.amount)?,
+        Some(mint) => {
+            let destination_info = next_account_info(account_info_iter)?;
+            let vault_info = next_account_info(account_info_iter)?;
+            let token_program_info = next_account_info(account_info_iter)?;
+
+            check_token_program(token_program_info)?;
+            let (vault_address, _) = find_vault_address(channel_info.key, &mint);
+            if *vault_info.key != vault_address {
+                return Err(ProgramError::InvalidSeeds);
+            }
+
+            let channel_id = channel.channel_id.to_le_bytes();
+            let bump = [channel.bump];
+            invoke_signed(
+                &spl_token::instruction::transfer(
+                    &spl_token::id(),
+                    vault_info.key,
+                    destination_info.key,
+                    channel_info.key,
+                    &[],
+                    deposit.amount,
+                )?,
+                &[
+                    vault_info.clone(),
+                    destination_info.clone(),
+                    channel_info.clone(),
+                ],
+                &[&channel_seeds(&channel.operator, &channel_id, &bump)],
+            )?;
+        }
+    }
+    close_program_account(deposit_info, depositor_info)?;
+
+    // The withdrawn deposit no longer needs to appear in the final ledger.
+    channel.num_deposits = channel
+        .num_deposits
+        .checked_sub(1)
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    Channel::pack(channel, &mut channel_info.data.borrow_mut())
+}
+
+fn load_channel(program_id: &Pubkey, channel_info: &AccountInfo) -> Result<Channel, ProgramError> {
+    if channel_info.owner != program_id {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    Channel::unpack(&channel_info.data.borrow())
+}
+
+/// Load a depositor's deposit account for a mint, creating it if this is
+/// their first deposit of the mint into the channel.
+fn open_or_load_deposit<'a>(
+    program_id: &Pubkey,
+    depositor_info: &AccountInfo<'a>,
+    channel_info: &AccountInfo<'a>,
+    channel: &mut Channel,
+    deposit_info: &AccountInfo<'a>,
+    mint: Option<&Pubkey>,
+) -> Result<Deposit, ProgramError> {
+    let (deposit_address, bump) = find_deposit_address(channel_info.key, depositor_info.key, mint);
+    if *deposit_info.key != deposit_address {
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    if !deposit_info.data_is_empty() {
+        if deposit_info.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        return Deposit::unpack(&deposit_info.data.borrow());
+    }
+
+    let mint_seed = mint.copied().unwrap_or_default();
+    create_pda_account(
+        depositor_info,
+        deposit_info,
+        Deposit::LEN,
+        program_id,
+        &deposit_seeds(channel_info.key, depositor_info.key, &mint_seed, &[bump]),
+    )?;
+    channel.num_deposits = channel
+        .num_deposits
+        .checked_add(1)
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+
+    Ok(Deposit {
+        is_initialized: true,
+        channel: *channel_info.key,
+        owner: *depositor_info.key,
+        mint: mint.copied(),
+        amount: 0,
+    })
+}
+
+/// Escrowed tokens are only ever held by the SPL Token program. Token-2022
+/// mints can't be escrowed, since their extensions, such as transfer fees and
+/// hooks, could make a vault's balance diverge from its deposits.
+fn check_token_program(token_program_info: &AccountInfo) -> ProgramResult {
+    if *token_program_info.key != spl_token::id() {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    Ok(())
+}
+
+fn create_pda_account<'a>(
+    payer_info: &AccountInfo<'a>,
+    new_account_info: &AccountInfo<'a>,
+    space: usize,
+    owner: &Pubkey,
+    signer_seeds: &[&[u8]],
+) -> ProgramResult {
+    let lamports = Rent::get()?.minimum_balance(space);
+    invoke_signed(
+        &system_instruction::create_account(
+            payer_info.key,
+            new_account_info.key,
+            lamports,
+            space as u64,
+            owner,
+        ),
+        &[payer_info.clone(), new_account_info.clone()],
+        &[signer_seeds],
+    )
+}
+
+/// Move lamports out of an account owned by this program.
+fn transfer_lamports(
+    source_info: &AccountInfo,
+    destination_info: &AccountInfo,
+    amount: u64,
+) -> ProgramResult {
+    let source_lamports = source_info
+        .lamports()
+        .checked_sub(amount)
+        .ok_or(ProgramError::InsufficientFunds)?;
+    let destination_lamports = destination_info
+        .lamports()
+        .checked_add(amount)
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    **source_info.try_borrow_mut_lamports()? = source_lamports;
+    **destination_info.try_borrow_mut_lamports()? = destination_lamports;
+    Ok(())
+}
+
+/// Close an account owned by this program, sending its lamports to the
+/// destination.
+fn close_program_account(
+    account_info: &AccountInfo,
+    destination_info: &AccountInfo,
+) -> ProgramResult {
+    transfer_lamports(account_info, destination_info, account_info.lamports())?;
+    account_info.realloc(0, false)?;
+    account_info.assign(&system_program::id());
+    Ok(())
+}
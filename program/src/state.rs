@@ -0,0 +1,174 @@
+//! Program state types, and the addresses they live at.
+
+use solana_program::{
+    clock::UnixTimestamp,
+    program_error::ProgramError,
+    program_pack::{IsInitialized, Pack, Sealed},
+    pubkey::{Pubkey, PUBKEY_BYTES},
+};
+
+const CHANNEL_SEED: &[u8] = b"channel";
+const DEPOSIT_SEED: &[u8] = b"deposit";
+const VAULT_SEED: &[u8] = b"vault";
+
+/// The address of the channel account for an operator's channel.
+///
+/// The channel account also holds all escrowed SOL.
+pub fn find_channel_address(operator: &Pubkey, channel_id: u64) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[CHANNEL_SEED, operator.as_ref(), &channel_id.to_le_bytes()],
+        &crate::id(),
+    )
+}
+
+/// The address of the account recording an owner's deposit of a mint, or of
+/// SOL if `mint` is `None`, into a channel.
+pub fn find_deposit_address(
+    channel: &Pubkey,
+    owner: &Pubkey,
+    mint: Option<&Pubkey>,
+) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[
+            DEPOSIT_SEED,
+            channel.as_ref(),
+            owner.as_ref(),
+            mint.unwrap_or(&Pubkey::default()).as_ref(),
+        ],
+        &crate::id(),
+    )
+}
+
+/// The address of the token account holding a channel's escrowed tokens of a
+/// mint.
+pub fn find_vault_address(channel: &Pubkey, mint: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[VAULT_SEED, channel.as_ref(), mint.as_ref()], &crate::id())
+}
+
+pub(crate) fn channel_seeds<'a>(
+    operator: &'a Pubkey,
+    channel_id: &'a [u8; 8],
+    bump: &'a [u8; 1],
+) -> [&'a [u8]; 4] {
+    [CHANNEL_SEED, operator.as_ref(), channel_id, bump]
+}
+
+pub(crate) fn deposit_seeds<'a>(
+    channel: &'a Pubkey,
+    owner: &'a Pubkey,
+    mint: &'a Pubkey,
+    bump: &'a [u8; 1],
+) -> [&'a [u8]; 5] {
+    [
+        DEPOSIT_SEED,
+        channel.as_ref(),
+        owner.as_ref(),
+        mint.as_ref(),
+        bump,
+    ]
+}
+
+pub(crate) fn vault_seeds<'a>(
+    channel: &'a Pubkey,
+    mint: &'a Pubkey,
+    bump: &'a [u8; 1],
+) -> [&'a [u8]; 4] {
+    [VAULT_SEED, channel.as_ref(), mint.as_ref(), bump]
+}
+
+/// An open PayTube channel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Channel {
+    pub is_initialized: bool,
+    /// The operator, who signs the channel's final ledger.
+    pub operator: Pubkey,
+    pub channel_id: u64,
+    pub bump: u8,
+    /// The number of deposit accounts opened in the channel.
+    pub num_deposits: u32,
+    /// The unix timestamp after which depositors may withdraw their deposits
+    /// in full, if the operator still hasn't closed the channel.
+    pub expires_at: UnixTimestamp,
+}
+
+impl Sealed for Channel {}
+
+impl IsInitialized for Channel {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for Channel {
+    const LEN: usize = 1 + PUBKEY_BYTES + 8 + 1 + 4 + 8;
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        dst[0] = self.is_initialized as u8;
+        dst[1..33].copy_from_slice(self.operator.as_ref());
+        dst[33..41].copy_from_slice(&self.channel_id.to_le_bytes());
+        dst[41] = self.bump;
+        dst[42..46].copy_from_slice(&self.num_deposits.to_le_bytes());
+        dst[46..54].copy_from_slice(&self.expires_at.to_le_bytes());
+    }
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        Ok(Self {
+            is_initialized: unpack_bool(src[0])?,
+            operator: Pubkey::try_from(&src[1..33]).unwrap(),
+            channel_id: u64::from_le_bytes(src[33..41].try_into().unwrap()),
+            bump: src[41],
+            num_deposits: u32::from_le_bytes(src[42..46].try_into().unwrap()),
+            expires_at: i64::from_le_bytes(src[46..54].try_into().unwrap()),
+        })
+    }
+}
+
+/// A participant's deposit of SOL or a particular token into a channel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Deposit {
+    pub is_initialized: bool,
+    pub channel: Pubkey,
+    pub owner: Pubkey,
+    /// The deposited mint, or `None` for SOL.
+    pub mint: Option<Pubkey>,
+    pub amount: u64,
+}
+
+impl Sealed for Deposit {}
+
+impl IsInitialized for Deposit {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for Deposit {
+    const LEN: usize = 1 + PUBKEY_BYTES + PUBKEY_BYTES + 1 + PUBKEY_BYTES + 8;
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        dst[0] = self.is_initialized as u8;
+        dst[1..33].copy_from_slice(self.channel.as_ref());
+        dst[33..65].copy_from_slice(self.owner.as_ref());
+        dst[65] = self.mint.is_some() as u8;
+        dst[66..98].copy_from_slice(self.mint.unwrap_or_default().as_ref());
+        dst[98..106].copy_from_slice(&self.amount.to_le_bytes());
+    }
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        Ok(Self {
+            is_initialized: unpack_bool(src[0])?,
+            channel: Pubkey::try_from(&src[1..33]).unwrap(),
+            owner: Pubkey::try_from(&src[33..65]).unwrap(),
+            mint: unpack_bool(src[65])?.then(|| Pubkey::try_from(&src[66..98]).unwrap()),
+            amount: u64::from_le_bytes(src[98..106].try_into().unwrap()),
+        })
+    }
+}
+
+fn unpack_bool(byte: u8) -> Result<bool, ProgramError> {
+    match byte {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(ProgramError::InvalidAccountData),
+    }
+}
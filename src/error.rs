@@ -0,0 +1,69 @@
+//! PayTube's error type, shared across every component of the channel.
+
+use {
+    crate::report::SimulationReport,
+    solana_client::client_error::ClientError,
+    solana_sdk::{program_error::ProgramError, pubkey::Pubkey, transaction::TransactionError},
+};
+
+/// Errors that can occur while operating a PayTube channel.
+#[derive(Debug, thiserror::Error)]
+pub enum PayTubeError {
+    /// A PayTube transaction could not be converted into an SVM transaction.
+    #[error("failed to convert PayTube transaction: {0}")]
+    TransactionConversion(TransactionError),
+    /// A Solana instruction could not be built.
+    #[error("failed to build instruction: {0}")]
+    Instruction(#[from] ProgramError),
+    /// The SVM program cache could not be initialized.
+    #[error("failed to set up program cache: {0}")]
+    ProgramCache(String),
+    /// A request to the base chain's RPC failed.
+    #[error("RPC request failed: {0}")]
+    Rpc(Box<ClientError>),
+    /// A participant's token account could not be registered with the
+    /// channel.
+    #[error("registration failed: {0}")]
+    Registration(String),
+    /// A participant's deposit could not be locked in the channel.
+    #[error("deposit failed: {0}")]
+    Deposit(String),
+    /// The channel has started closing, so it no longer accepts transfers,
+    /// deposits or registrations.
+    #[error("channel is closed")]
+    ChannelClosed,
+    /// The final ledger could not be settled to the base chain.
+    #[error("settlement failed: {0}")]
+    Settlement(String),
+    /// A settlement transaction requires a signature from a key the channel
+    /// doesn't have.
+    #[error("missing signer for settlement transaction: {0}")]
+    MissingSigner(Pubkey),
+    /// One or more settlement transactions failed simulation, so nothing was
+    /// broadcast.
+    #[error(
+        "settlement simulation failed for {} transaction(s)",
+        .0.failures.len()
+    )]
+    SimulationFailed(SimulationReport),
+    /// Settlement transactions failed simulation after the ones they depend
+    /// on had already landed, so the settlement was only partly broadcast.
+    /// It can be resumed from its journal once the failures are resolved.
+    #[error(
+        "settlement incomplete: {} transaction(s) failed simulation after earlier ones landed",
+        .0.failures.len()
+    )]
+    SettlementIncomplete(SimulationReport),
+    /// Reading or writing a file failed.
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    /// Data written to or read from disk could not be (de)serialized.
+    #[error("serialization error: {0}")]
+    Serialization(#[from] bincode::Error),
+}
+
+impl From<ClientError> for PayTubeError {
+    fn from(error: ClientError) -> Self {
+        Self::Rpc(Box::new(error))
+    }
+}
@@ -0,0 +1,215 @@
+//! Instruction builders and account decoders for the PayTube escrow program.
+//!
+//! The escrow program holds each participant's deposit for the lifetime of a
+//! channel. Deposits are made when the channel opens, and when it closes the
+//! operator submits a single final ledger, from which the program pays out
+//! every deposit at once. If the operator never closes the channel, each
+//! depositor can withdraw their deposit once the channel expires.
+//!
+//! Deposits are co-signed by the operator, and only SOL and SPL Token mints
+//! can be escrowed, not Token-2022 mints.
+
+pub use paytube_escrow::{
+    id,
+    state::{find_channel_address, find_deposit_address, find_vault_address, Channel, Deposit},
+};
+use {
+    crate::error::PayTubeError,
+    paytube_escrow::instruction::PayTubeEscrowInstruction,
+    solana_sdk::{
+        account::ReadableAccount,
+        instruction::{AccountMeta, Instruction},
+        program_error::ProgramError,
+        program_pack::Pack,
+        pubkey::Pubkey,
+        system_program,
+    },
+    spl_associated_token_account::get_associated_token_address,
+    std::collections::BTreeSet,
+};
+
+/// An entry in a channel's final ledger: the amount of SOL, or of a mint's
+/// tokens, owed to a depositor when the channel closes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FinalBalance {
+    pub owner: Pubkey,
+    /// The mint, or `None` for SOL.
+    pub mint: Option<Pubkey>,
+    pub amount: u64,
+}
+
+/// Open a new channel, operated by `operator`, which expires at the unix
+/// timestamp `expires_at`.
+pub fn open_channel(operator: &Pubkey, channel_id: u64, expires_at: i64) -> Instruction {
+    let (channel, _) = find_channel_address(operator, channel_id);
+    Instruction::new_with_bytes(
+        id(),
+        &PayTubeEscrowInstruction::OpenChannel {
+            channel_id,
+            expires_at,
+        }
+        .pack(),
+        vec![
+            AccountMeta::new(*operator, true),
+            AccountMeta::new(channel, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+/// Create the vault holding a channel's escrowed tokens of a mint.
+pub fn create_vault(operator: &Pubkey, channel: &Pubkey, mint: &Pubkey) -> Instruction {
+    let (vault, _) = find_vault_address(channel, mint);
+    Instruction::new_with_bytes(
+        id(),
+        &PayTubeEscrowInstruction::CreateVault.pack(),
+        vec![
+            AccountMeta::new(*operator, true),
+            AccountMeta::new_readonly(*channel, false),
+            AccountMeta::new(vault, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+        ],
+    )
+}
+
+/// Deposit SOL into a channel, co-signed by its operator.
+pub fn deposit_native(
+    depositor: &Pubkey,
+    operator: &Pubkey,
+    channel: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    let (deposit, _) = find_deposit_address(channel, depositor, None);
+    Instruction::new_with_bytes(
+        id(),
+        &PayTubeEscrowInstruction::DepositNative { amount }.pack(),
+        vec![
+            AccountMeta::new(*depositor, true),
+            AccountMeta::new_readonly(*operator, true),
+            AccountMeta::new(*channel, false),
+            AccountMeta::new(deposit, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+/// Deposit tokens into a channel, co-signed by its operator, from the
+/// depositor's associated token account for the mint.
+pub fn deposit_token(
+    depositor: &Pubkey,
+    operator: &Pubkey,
+    channel: &Pubkey,
+    mint: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    let (deposit, _) = find_deposit_address(channel, depositor, Some(mint));
+    let (vault, _) = find_vault_address(channel, mint);
+    Instruction::new_with_bytes(
+        id(),
+        &PayTubeEscrowInstruction::DepositToken { amount }.pack(),
+        vec![
+            AccountMeta::new(*depositor, true),
+            AccountMeta::new_readonly(*operator, true),
+            AccountMeta::new(*channel, false),
+            AccountMeta::new(deposit, false),
+            AccountMeta::new(get_associated_token_address(depositor, mint), false),
+            AccountMeta::new(vault, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+        ],
+    )
+}
+
+/// Close a channel, paying out every deposit according to the final ledger.
+///
+/// The ledger must have exactly one entry per deposit in the channel, and
+/// can't pay out more of a mint than was deposited. Tokens are paid out to
+/// each owner's associated token account for the mint, and any left in a
+/// vault afterwards go to the operator's, as does any SOL left in escrow.
+pub fn close_channel(
+    operator: &Pubkey,
+    channel: &Pubkey,
+    final_ledger: &[FinalBalance],
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(*operator, true),
+        AccountMeta::new(*channel, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+    ];
+    let mut mints = BTreeSet::new();
+    for entry in final_ledger {
+        let (deposit, _) = find_deposit_address(channel, &entry.owner, entry.mint.as_ref());
+        let destination = match &entry.mint {
+            Some(mint) => {
+                mints.insert(*mint);
+                get_associated_token_address(&entry.owner, mint)
+            }
+            None => entry.owner,
+        };
+        accounts.push(AccountMeta::new(deposit, false));
+        accounts.push(AccountMeta::new(entry.owner, false));
+        accounts.push(AccountMeta::new(destination, false));
+    }
+    for mint in mints {
+        accounts.push(AccountMeta::new(
+            find_vault_address(channel, &mint).0,
+            false,
+        ));
+        accounts.push(AccountMeta::new(
+            get_associated_token_address(operator, &mint),
+            false,
+        ));
+    }
+
+    let final_amounts = final_ledger.iter().map(|entry| entry.amount).collect();
+    Instruction::new_with_bytes(
+        id(),
+        &PayTubeEscrowInstruction::CloseChannel { final_amounts }.pack(),
+        accounts,
+    )
+}
+
+/// Withdraw a deposit of SOL, or of a mint's tokens if `mint` is provided,
+/// from a channel which has expired without being closed.
+///
+/// Tokens are returned to the depositor's associated token account for the
+/// mint.
+pub fn withdraw(depositor: &Pubkey, channel: &Pubkey, mint: Option<&Pubkey>) -> Instruction {
+    let (deposit, _) = find_deposit_address(channel, depositor, mint);
+    let mut accounts = vec![
+        AccountMeta::new(*depositor, true),
+        AccountMeta::new(*channel, false),
+        AccountMeta::new(deposit, false),
+    ];
+    if let Some(mint) = mint {
+        accounts.push(AccountMeta::new(
+            get_associated_token_address(depositor, mint),
+            false,
+        ));
+        accounts.push(AccountMeta::new(find_vault_address(channel, mint).0, false));
+        accounts.push(AccountMeta::new_readonly(spl_token::id(), false));
+    }
+    Instruction::new_with_bytes(id(), &PayTubeEscrowInstruction::Withdraw.pack(), accounts)
+}
+
+/// Decode a channel account.
+pub fn decode_channel(account: &impl ReadableAccount) -> Result<Channel, PayTubeError> {
+    check_owner(account)?;
+    Ok(Channel::unpack(account.data())?)
+}
+
+/// Decode a deposit account.
+pub fn decode_deposit(account: &impl ReadableAccount) -> Result<Deposit, PayTubeError> {
+    check_owner(account)?;
+    Ok(Deposit::unpack(account.data())?)
+}
+
+fn check_owner(account: &impl ReadableAccount) -> Result<(), PayTubeError> {
+    if *account.owner() != id() {
+        return Err(ProgramError::IncorrectProgramId.into());
+    }
+    Ok(())
+}
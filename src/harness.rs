@@ -0,0 +1,290 @@
+//! An in-process stand-in for the base chain, for exercising PayTube channels
+//! end-to-end without running a validator.
+//!
+//! `InMemoryBaseChain` holds the base chain's accounts in a shared map. It
+//! serves them to a channel as an `AccountSource`, and accepts the channel's
+//! settlement transactions as a `SettlementSink`, simulating or executing them
+//! with the SVM against the same map. Cloning it produces another handle to
+//! the same state, so a test can hand one handle to the channel and keep
+//! another to assert on the settled balances.
+//!
+//! The SVM's transaction processor, and the programs in its cache, are built
+//! once when the base chain is created, and shared by every handle.
+//!
+//! Only available with the `test-harness` feature.
+
+use {
+    crate::{
+        error::PayTubeError,
+        loader::{committed_accounts, PayTubeAccountLoader},
+        processor::{
+            create_transaction_batch_processor, get_transaction_check_results, PayTubeForkGraph,
+        },
+        sink::{SettlementSink, SimulationOutcome},
+        source::{AccountSource, InMemoryAccountSource},
+    },
+    solana_compute_budget::compute_budget::ComputeBudget,
+    solana_sdk::{
+        account::{AccountSharedData, ReadableAccount},
+        bpf_loader, bpf_loader_upgradeable,
+        feature_set::FeatureSet,
+        fee::FeeStructure,
+        hash::Hash,
+        native_loader,
+        pubkey::Pubkey,
+        rent_collector::RentCollector,
+        signature::Signature,
+        system_program,
+        transaction::{SanitizedTransaction, Transaction as SolanaTransaction, TransactionError},
+    },
+    solana_svm::transaction_processor::{
+        ExecutionRecordingConfig, TransactionBatchProcessor, TransactionProcessingConfig,
+        TransactionProcessingEnvironment,
+    },
+    std::{
+        collections::{HashMap, HashSet},
+        sync::{Arc, RwLock},
+    },
+};
+
+/// An in-memory base chain.
+#[derive(Clone)]
+pub struct InMemoryBaseChain {
+    accounts: Arc<RwLock<HashMap<Pubkey, AccountSharedData>>>,
+    landed: Arc<RwLock<HashSet<Signature>>>,
+    processor: Arc<TransactionBatchProcessor<PayTubeForkGraph>>,
+    feature_set: Arc<FeatureSet>,
+}
+
+impl InMemoryBaseChain {
+    /// Create a base chain holding the provided accounts.
+    ///
+    /// Programs are only loaded into the SVM's program cache here, so every
+    /// program the base chain executes must be among these accounts. The
+    /// builtin programs' accounts are added, as they are at genesis on a real
+    /// cluster, unless provided.
+    pub fn new(
+        accounts: impl IntoIterator<Item = (Pubkey, AccountSharedData)>,
+    ) -> Result<Self, PayTubeError> {
+        let mut accounts = accounts.into_iter().collect::<HashMap<_, _>>();
+        for (program_id, name) in [
+            (system_program::id(), "system_program"),
+            (bpf_loader::id(), "solana_bpf_loader_program"),
+            (
+                bpf_loader_upgradeable::id(),
+                "solana_bpf_loader_upgradeable_program",
+            ),
+        ] {
+            accounts
+                .entry(program_id)
+                .or_insert_with(|| native_loader::create_loadable_account_for_test(name));
+        }
+        let feature_set = FeatureSet::all_enabled();
+
+        let account_loader =
+            PayTubeAccountLoader::new(Box::new(InMemoryAccountSource::new(accounts.clone())));
+        let processor = create_transaction_batch_processor(
+            &account_loader,
+            &feature_set,
+            &ComputeBudget::default(),
+        )?;
+
+        Ok(Self {
+            accounts: Arc::new(RwLock::new(accounts)),
+            landed: Arc::default(),
+            processor: Arc::new(processor),
+            feature_set: Arc::new(feature_set),
+        })
+    }
+
+    pub fn get_account(&self, pubkey: &Pubkey) -> Option<AccountSharedData> {
+        self.accounts.read().unwrap().get(pubkey).cloned()
+    }
+
+    pub fn set_account(&self, pubkey: Pubkey, account: AccountSharedData) {
+        self.accounts.write().unwrap().insert(pubkey, account);
+    }
+
+    /// The lamport balance of an account, or zero if it doesn't exist.
+    pub fn get_balance(&self, pubkey: &Pubkey) -> u64 {
+        self.get_account(pubkey)
+            .map(|account| account.lamports())
+            .unwrap_or_default()
+    }
+
+    /// Execute a single transaction against the base chain's accounts,
+    /// returning its outcome along with the accounts it would modify, without
+    /// committing anything.
+    fn run(
+        &self,
+        transaction: &SolanaTransaction,
+    ) -> Result<(SimulationOutcome, Vec<(Pubkey, AccountSharedData)>), PayTubeError> {
+        let fee_structure = FeeStructure::default();
+        let lamports_per_signature = fee_structure.lamports_per_signature;
+        let rent_collector = RentCollector::default();
+
+        // Sysvars, such as the clock, may have been set since the last run.
+        let account_loader = PayTubeAccountLoader::new(Box::new(self.clone()));
+        self.processor.reset_sysvar_cache();
+        self.processor
+            .fill_missing_sysvar_cache_entries(&account_loader);
+        account_loader.take_source_error()?;
+
+        let processing_environment = TransactionProcessingEnvironment {
+            blockhash: Hash::default(),
+            epoch_total_stake: None,
+            epoch_vote_accounts: None,
+            feature_set: self.feature_set.clone(),
+            fee_structure: Some(&fee_structure),
+            lamports_per_signature,
+            rent_collector: Some(&rent_collector),
+        };
+
+        let processing_config = TransactionProcessingConfig {
+            compute_budget: Some(ComputeBudget::default()),
+            recording_config: ExecutionRecordingConfig {
+                enable_cpi_recording: false,
+                enable_log_recording: true,
+                enable_return_data_recording: false,
+            },
+            ..Default::default()
+        };
+
+        let svm_transactions = vec![SanitizedTransaction::try_from_legacy_transaction(
+            transaction.clone(),
+            &HashSet::new(),
+        )
+        .map_err(PayTubeError::TransactionConversion)?];
+
+        let results = self.processor.load_and_execute_sanitized_transactions(
+            &account_loader,
+            &svm_transactions,
+            get_transaction_check_results(svm_transactions.len(), lamports_per_signature),
+            &processing_environment,
+            &processing_config,
+        );
+        account_loader.take_source_error()?;
+
+        let outcome = results
+            .execution_results
+            .first()
+            .map(|result| SimulationOutcome {
+                error: result.flattened_result().err(),
+                logs: result
+                    .details()
+                    .and_then(|details| details.log_messages.clone())
+                    .unwrap_or_default(),
+            })
+            .unwrap_or_default();
+
+        Ok((outcome, committed_accounts(&svm_transactions, &results)))
+    }
+}
+
+impl AccountSource for InMemoryBaseChain {
+    fn get_account(&self, pubkey: &Pubkey) -> Result<Option<AccountSharedData>, PayTubeError> {
+        Ok(InMemoryBaseChain::get_account(self, pubkey))
+    }
+}
+
+impl SettlementSink for InMemoryBaseChain {
+    fn get_latest_blockhash(&self) -> Result<Hash, PayTubeError> {
+        Ok(Hash::default())
+    }
+
+    fn simulate_transaction(
+        &self,
+        transaction: &SolanaTransaction,
+    ) -> Result<SimulationOutcome, PayTubeError> {
+        let (outcome, _) = self.run(transaction)?;
+        Ok(outcome)
+    }
+
+    /// Transactions are simulated against a copy of the base chain's
+    /// accounts, which each successful one's accounts are written to in turn.
+    fn simulate_transactions_in_sequence(
+        &self,
+        transactions: &[SolanaTransaction],
+    ) -> Result<Option<Vec<SimulationOutcome>>, PayTubeError> {
+        let scratch = Self {
+            accounts: Arc::new(RwLock::new(self.accounts.read().unwrap().clone())),
+            landed: Arc::default(),
+            processor: self.processor.clone(),
+            feature_set: self.feature_set.clone(),
+        };
+        transactions
+            .iter()
+            .map(|transaction| {
+                let (outcome, accounts) = scratch.run(transaction)?;
+                if outcome.error.is_none() {
+                    scratch.accounts.write().unwrap().extend(accounts);
+                }
+                Ok(outcome)
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Some)
+    }
+
+    /// Transactions are executed as soon as they are submitted. Like the
+    /// real base chain, a transaction which has already landed is never
+    /// executed again.
+    fn submit_transaction(
+        &self,
+        transaction: &SolanaTransaction,
+    ) -> Result<Signature, PayTubeError> {
+        transaction
+            .verify()
+            .map_err(|err| PayTubeError::Settlement(format!("transaction rejected: {err}")))?;
+        if self
+            .landed
+            .read()
+            .unwrap()
+            .contains(&transaction.signatures[0])
+        {
+            return Ok(transaction.signatures[0]);
+        }
+        let (outcome, accounts) = self.run(transaction)?;
+        if let Some(err) = outcome.error {
+            return Err(PayTubeError::Settlement(format!(
+                "transaction failed: {err}"
+            )));
+        }
+        self.accounts.write().unwrap().extend(accounts);
+        let signature = transaction.signatures[0];
+        self.landed.write().unwrap().insert(signature);
+        Ok(signature)
+    }
+
+    /// Failed transactions are never committed, so only successful ones are
+    /// ever found.
+    fn get_transaction_status(
+        &self,
+        signature: &Signature,
+    ) -> Result<Option<Result<(), TransactionError>>, PayTubeError> {
+        Ok(self
+            .landed
+            .read()
+            .unwrap()
+            .contains(signature)
+            .then_some(Ok(())))
+    }
+
+    /// Blockhashes never expire on the in-memory base chain.
+    fn is_blockhash_valid(&self, _blockhash: &Hash) -> Result<bool, PayTubeError> {
+        Ok(true)
+    }
+
+    fn confirm_transaction(
+        &self,
+        signature: &Signature,
+        _recent_blockhash: &Hash,
+    ) -> Result<(), PayTubeError> {
+        if self.landed.read().unwrap().contains(signature) {
+            Ok(())
+        } else {
+            Err(PayTubeError::Settlement(format!(
+                "transaction {signature} has not landed"
+            )))
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! PayTube's settlement journal, a write-ahead record of every settlement
+//! transaction sent to the base chain.
+//!
+//! Settlement is spread across several base chain transactions, so a crash
+//! partway through would otherwise leave no record of which ones landed. The
+//! journal is written before anything is broadcast, and updated as each
+//! transaction is submitted and confirmed, so an interrupted settlement can
+//! be resumed without sending anything twice.
+//!
+//! Revocations of participants' delegations to the settlement authority are
+//! journaled after the settlement transfers, along with any delegations the
+//! channel couldn't sign a revocation for, so a resumed settlement revokes
+//! and reports exactly what the original would have.
+//!
+//! Each journal records a fingerprint of the ledger it settles, so a channel
+//! never resumes a settlement journaled for a different one.
+//!
+//! Every write replaces the journal file atomically, by writing to a temporary
+//! file and renaming it over the original.
+
+use {
+    crate::{error::PayTubeError, report::UnrevokedDelegation},
+    serde::{Deserialize, Serialize},
+    solana_sdk::{hash::Hash, signature::Signature, transaction::Transaction as SolanaTransaction},
+    std::{
+        fs::{self, File},
+        io::BufWriter,
+        path::{Path, PathBuf},
+    },
+};
+
+/// The settlement status of a journaled transaction.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum JournalStatus {
+    /// Signed, but not yet submitted to the base chain.
+    Pending,
+    /// Submitted to the base chain, but not yet confirmed.
+    Submitted,
+    /// Confirmed on the base chain.
+    Confirmed,
+}
+
+/// A single journaled settlement transaction.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct JournalEntry {
+    /// The signed settlement transaction.
+    pub transaction: SolanaTransaction,
+    pub signature: Signature,
+    pub status: JournalStatus,
+}
+
+/// A write-ahead journal of settlement transactions.
+///
+/// A journal without a path is kept in memory only, which is what the settler
+/// uses when a channel isn't configured with a journal file.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SettlementJournal {
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    fingerprint: Hash,
+    /// The number of leading transactions which must land before the rest
+    /// can, because the rest spend funds they release.
+    prerequisites: usize,
+    entries: Vec<JournalEntry>,
+    /// Delegations the channel couldn't sign a revocation for.
+    unrevoked_delegations: Vec<UnrevokedDelegation>,
+}
+
+impl SettlementJournal {
+    /// Create a new journal of pending settlement transactions for the ledger
+    /// with the provided fingerprint, writing it to the provided path, if
+    /// any, before returning.
+    ///
+    /// The first `prerequisites` transactions must land before the rest can.
+    /// The provided unrevoked delegations are recorded for the settlement's
+    /// report.
+    pub(crate) fn create(
+        path: Option<&Path>,
+        fingerprint: Hash,
+        prerequisites: usize,
+        transactions: Vec<SolanaTransaction>,
+        unrevoked_delegations: Vec<UnrevokedDelegation>,
+    ) -> Result<Self, PayTubeError> {
+        let journal = Self {
+            path: path.map(Path::to_path_buf),
+            fingerprint,
+            prerequisites,
+            entries: transactions
+                .into_iter()
+                .map(|transaction| JournalEntry {
+                    signature: transaction.signatures[0],
+                    transaction,
+                    status: JournalStatus::Pending,
+                })
+                .collect(),
+            unrevoked_delegations,
+        };
+        journal.persist()?;
+        Ok(journal)
+    }
+
+    /// Open an existing journal file.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, PayTubeError> {
+        let path = path.as_ref();
+        let mut journal: Self = bincode::deserialize_from(File::open(path)?)?;
+        journal.path = Some(path.to_path_buf());
+        Ok(journal)
+    }
+
+    /// The fingerprint of the ledger this journal settles.
+    pub fn fingerprint(&self) -> Hash {
+        self.fingerprint
+    }
+
+    /// The number of leading transactions which must land before the rest
+    /// can.
+    pub fn prerequisites(&self) -> usize {
+        self.prerequisites
+    }
+
+    /// Every journaled settlement transaction, in the order they're sent.
+    pub fn entries(&self) -> &[JournalEntry] {
+        &self.entries
+    }
+
+    /// Every delegation the channel couldn't sign a revocation for.
+    pub fn unrevoked_delegations(&self) -> &[UnrevokedDelegation] {
+        &self.unrevoked_delegations
+    }
+
+    /// Whether every journaled transaction has been confirmed.
+    pub fn is_complete(&self) -> bool {
+        self.entries
+            .iter()
+            .all(|entry| entry.status == JournalStatus::Confirmed)
+    }
+
+    /// Replace the transaction at the provided index, which must not have
+    /// been submitted with its current signature yet.
+    pub(crate) fn replace(
+        &mut self,
+        index: usize,
+        transaction: SolanaTransaction,
+    ) -> Result<(), PayTubeError> {
+        self.entries[index] = JournalEntry {
+            signature: transaction.signatures[0],
+            transaction,
+            status: JournalStatus::Pending,
+        };
+        self.persist()
+    }
+
+    /// Update the status of the transaction at the provided index.
+    pub(crate) fn set_status(
+        &mut self,
+        index: usize,
+        status: JournalStatus,
+    ) -> Result<(), PayTubeError> {
+        self.entries[index].status = status;
+        self.persist()
+    }
+
+    fn persist(&self) -> Result<(), PayTubeError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            bincode::serialize_into(&mut writer, self)?;
+            writer
+                .into_inner()
+                .map_err(|err| err.into_error())?
+                .sync_all()?;
+        }
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
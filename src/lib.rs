@@ -12,13 +12,13 @@
 //!
 //! Users opt-in to using a PayTube channel by "registering" their token
 //! accounts to the channel. This is done by delegating a token account to the
-//! PayTube on-chain program on Solana. This delegation is temporary, and
+//! channel's settlement authority on Solana. This delegation is temporary, and
 //! released immediately after channel settlement.
 //!
 //! Note: This opt-in solution is for demonstration purposes only.
 //!
 //! ```text
-//! 
+//!
 //! PayTube "VM"
 //!
 //!    Bob          Alice        Bob          Alice          Will
@@ -53,26 +53,75 @@
 //! `TransactionProcessingCallback` interface, and provides it to the
 //! `TransactionBatchProcessor` to process PayTube transactions.
 
+pub mod error;
+pub mod escrow;
+#[cfg(feature = "test-harness")]
+pub mod harness;
+pub mod journal;
 mod loader;
 mod processor;
+pub mod report;
 mod settler;
+pub mod signer;
+pub mod sink;
+pub mod source;
+mod token;
 pub mod transaction;
 
+pub use {
+    processor::ProcessingMode,
+    settler::{NetDelta, SettlementPreview},
+};
+
 use {
     crate::{
-        loader::PayTubeAccountLoader, settler::PayTubeSettler, transaction::PayTubeTransaction,
+        error::PayTubeError,
+        journal::SettlementJournal,
+        loader::PayTubeAccountLoader,
+        report::{ProcessingReport, SettlementReport, TransactionReceipt},
+        settler::{Delegations, Escrow, Ledger, PayTubeSettler},
+        signer::SignerProvider,
+        sink::{RpcSettlementSink, SettlementSink},
+        source::{AccountSource, RpcAccountSource},
+        token::MintInfo,
+        transaction::{
+            PayTubeTransaction, PayTubeTransactionError, ReplayGuard, SignedPayTubeTransaction,
+        },
+    },
+    processor::{
+        create_transaction_batch_processor, get_transaction_check_results,
+        partition_conflict_free_batches, partition_write_conflict_free_batches, PayTubeForkGraph,
     },
-    processor::{create_transaction_batch_processor, get_transaction_check_results},
     solana_client::rpc_client::RpcClient,
     solana_compute_budget::compute_budget::ComputeBudget,
     solana_sdk::{
-        feature_set::FeatureSet, fee::FeeStructure, hash::Hash, rent_collector::RentCollector,
+        account::{from_account, AccountSharedData, ReadableAccount, WritableAccount},
+        clock::{Clock, Epoch},
+        feature_set::FeatureSet,
+        fee::FeeStructure,
+        hash::Hash,
+        program_error::ProgramError,
+        program_option::COption,
+        pubkey::Pubkey,
+        rent_collector::RentCollector,
         signature::Keypair,
+        signer::Signer,
+        sysvar,
     },
     solana_svm::transaction_processor::{
-        TransactionProcessingConfig, TransactionProcessingEnvironment,
+        ExecutionRecordingConfig, TransactionBatchProcessor, TransactionProcessingConfig,
+        TransactionProcessingEnvironment,
+    },
+    spl_token_2022::{
+        extension::{StateWithExtensions, StateWithExtensionsMut},
+        state::Account as TokenAccount,
+    },
+    std::{
+        collections::HashMap,
+        path::{Path, PathBuf},
+        sync::Arc,
+        time::{SystemTime, UNIX_EPOCH},
     },
-    std::sync::Arc,
     transaction::create_svm_transactions,
 };
 
@@ -80,15 +129,338 @@ use {
 ///
 /// Facilitates native SOL or SPL token transfers amongst various channel
 /// participants, settling the final changes in balances to the base chain.
+///
+/// A channel is long-lived. Any number of batches can be submitted while it is
+/// open, and the account loader's cache, the SVM program cache and the ledger
+/// are all retained between batches. Nothing is posted to the base chain until
+/// the channel is closed with `close`.
 pub struct PayTubeChannel {
-    /// I think you know why this is a bad idea...
-    keys: Vec<Keypair>,
-    rpc_client: RpcClient,
+    /// The channel's ID, which every transaction submitted to it is signed
+    /// for.
+    id: Pubkey,
+    /// The account which pays for settlement transactions.
+    payer: Pubkey,
+    signer_provider: Box<dyn SignerProvider>,
+    settlement_sink: Box<dyn SettlementSink>,
+    account_loader: PayTubeAccountLoader,
+    processor: TransactionBatchProcessor<PayTubeForkGraph>,
+    compute_budget: ComputeBudget,
+    feature_set: Arc<FeatureSet>,
+    fee_structure: FeeStructure,
+    rent_collector: RentCollector,
+    processing_mode: ProcessingMode,
+    replay_guard: ReplayGuard,
+    ledger: Ledger,
+    delegations: Delegations,
+    settlement_journal: Option<PathBuf>,
+    /// The settlement in progress, once the channel has started closing.
+    journal: Option<SettlementJournal>,
+    /// The channel's escrow, if participants' deposits are escrowed with the
+    /// escrow program.
+    escrow: Option<Escrow>,
+    /// Every mint the channel has seen, resolved from the base chain.
+    mints: HashMap<Pubkey, MintInfo>,
+    /// The base chain's epoch when the channel opened, which sets the fee for
+    /// every transfer of a Token-2022 mint with a transfer fee.
+    epoch: Epoch,
 }
 
 impl PayTubeChannel {
-    pub fn new(keys: Vec<Keypair>, rpc_client: RpcClient) -> Self {
-        Self { keys, rpc_client }
+    /// Open a new PayTube channel, loading accounts from and settling to the
+    /// base chain over RPC.
+    ///
+    /// Settlement transactions are paid for by `payer`, and signed by the
+    /// provided signer provider.
+    pub fn new(
+        payer: Pubkey,
+        signer_provider: Box<dyn SignerProvider>,
+        rpc_client: RpcClient,
+    ) -> Result<Self, PayTubeError> {
+        let rpc_client = Arc::new(rpc_client);
+        Self::new_with_backends(
+            payer,
+            signer_provider,
+            Box::new(RpcAccountSource::new(rpc_client.clone())),
+            Box::new(RpcSettlementSink::new(rpc_client)),
+        )
+    }
+
+    /// Open a new PayTube channel, loading accounts from the provided account
+    /// source and settling to the provided settlement sink.
+    pub fn new_with_backends(
+        payer: Pubkey,
+        signer_provider: Box<dyn SignerProvider>,
+        account_source: Box<dyn AccountSource>,
+        settlement_sink: Box<dyn SettlementSink>,
+    ) -> Result<Self, PayTubeError> {
+        // PayTube default configs.
+        let compute_budget = ComputeBudget::default();
+        let feature_set = FeatureSet::all_enabled();
+        let fee_structure = FeeStructure::default();
+        let rent_collector = RentCollector::default();
+
+        // PayTube loader/callback implementation.
+        let account_loader = PayTubeAccountLoader::new(account_source);
+        let epoch = base_chain_epoch(&account_loader)?;
+
+        // Solana SVM transaction batch processor.
+        let processor =
+            create_transaction_batch_processor(&account_loader, &feature_set, &compute_budget)?;
+        account_loader.take_source_error()?;
+
+        Ok(Self {
+            // A fresh, random ID, so a transaction signed for one channel can
+            // never be replayed into another.
+            id: Keypair::new().pubkey(),
+            payer,
+            signer_provider,
+            settlement_sink,
+            account_loader,
+            processor,
+            compute_budget,
+            feature_set: Arc::new(feature_set),
+            fee_structure,
+            rent_collector,
+            processing_mode: ProcessingMode::default(),
+            replay_guard: ReplayGuard::default(),
+            ledger: Ledger::default(),
+            delegations: Delegations::new(payer),
+            settlement_journal: None,
+            journal: None,
+            escrow: None,
+            mints: HashMap::new(),
+            epoch,
+        })
+    }
+
+    /// The channel's ID. Participants must sign their transactions for it.
+    pub fn id(&self) -> Pubkey {
+        self.id
+    }
+
+    /// Set the mode used to hand batches to the SVM.
+    ///
+    /// Defaults to `ProcessingMode::Sequential`.
+    pub fn with_processing_mode(mut self, processing_mode: ProcessingMode) -> Self {
+        self.processing_mode = processing_mode;
+        self
+    }
+
+    /// Set the settlement authority, which participants delegate their token
+    /// accounts to.
+    ///
+    /// Defaults to the payer. Any token accounts registered before the
+    /// authority is changed must be registered again.
+    pub fn with_settlement_authority(mut self, settlement_authority: Pubkey) -> Self {
+        self.delegations = Delegations::new(settlement_authority);
+        self
+    }
+
+    /// Journal settlement transactions to the provided path, so a settlement
+    /// interrupted by a crash can be resumed with `resume_settlement`, by a
+    /// fresh channel configured with the same path.
+    ///
+    /// The path should be unique to the channel. If a journal already exists
+    /// there when the channel is closed, the settlement it records is resumed
+    /// instead of starting a new one, as long as it was journaled for the
+    /// same ledger and payer.
+    pub fn with_settlement_journal(mut self, path: impl AsRef<Path>) -> Self {
+        self.settlement_journal = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Accept deposits escrowed with the escrow program, in the provided escrow
+    /// channel account, for the funds participants lock with `lock_deposit`.
+    ///
+    /// The payer must be the escrow channel's operator. Escrowed deposits are
+    /// settled by closing the escrow channel, which pays each participant back
+    /// their deposit less whatever they owe, and releases what they owe to the
+    /// payer, who pays it on to their creditors. Every deposit in the escrow
+    /// channel must be locked in the channel before it closes.
+    ///
+    /// The rest of the settlement spends what closing the escrow releases, so
+    /// it's simulated against the state closing the escrow leaves behind. A
+    /// settlement sink which can't simulate transactions in sequence has to
+    /// close the escrow before simulating the rest. If any of the rest then
+    /// fails, closing the channel returns `SettlementIncomplete`, and the
+    /// settlement can be resumed once the failures are resolved.
+    pub fn with_escrow_channel(mut self, escrow_channel: Pubkey) -> Self {
+        self.escrow = Some(Escrow::new(escrow_channel));
+        self
+    }
+
+    /// Register a participant's token account for a mint with the channel.
+    ///
+    /// The participant must have delegated their associated token account to
+    /// the channel's settlement authority, with an SPL Token `Approve`, on the
+    /// base chain. The approved amount must cover the account's whole balance,
+    /// since that's the most the channel can move out of it. Transfers out of
+    /// a registered account are then settled by the settlement authority
+    /// alone, without the owner's key.
+    ///
+    /// The delegation is revoked once the channel has settled, which needs the
+    /// owner's signature. The revocation is signed before anything is
+    /// broadcast, and if the signer provider can't sign for the owner, and
+    /// the settlement sink doesn't accept partially signed transactions, the
+    /// delegation is left in place and reported by `close`, for the owner to
+    /// revoke themselves.
+    pub fn register_token_account(
+        &mut self,
+        owner: &Pubkey,
+        mint: &Pubkey,
+    ) -> Result<(), PayTubeError> {
+        self.register_delegated_token_account(None, owner, mint)
+    }
+
+    /// Register a participant's token account for a mint with the channel, at
+    /// an explicit address rather than their associated token account, as by
+    /// `register_token_account`.
+    ///
+    /// This allows auxiliary token accounts, or those owned by a PDA, to be
+    /// settled by the settlement authority. The account must belong to
+    /// `owner`, hold tokens of `mint`, and be delegated to the settlement
+    /// authority. Transfers out of it name it with `with_source_token_account`.
+    ///
+    /// A PDA can never sign the revocation of its delegation, so the
+    /// delegation of a PDA-owned account is always left in place and reported
+    /// by `close`, for the PDA's program to revoke.
+    pub fn register_explicit_token_account(
+        &mut self,
+        token_account: &Pubkey,
+        owner: &Pubkey,
+        mint: &Pubkey,
+    ) -> Result<(), PayTubeError> {
+        self.register_delegated_token_account(Some(token_account), owner, mint)
+    }
+
+    fn register_delegated_token_account(
+        &mut self,
+        token_account: Option<&Pubkey>,
+        owner: &Pubkey,
+        mint: &Pubkey,
+    ) -> Result<(), PayTubeError> {
+        self.check_open()?;
+        let (token_account_pubkey, _, token_account, mint_info) = self
+            .load_base_chain_token_account(
+                token_account,
+                owner,
+                mint,
+                PayTubeError::Registration,
+            )?;
+        if token_account.delegate != COption::Some(*self.delegations.authority()) {
+            return Err(PayTubeError::Registration(format!(
+                "token account {token_account_pubkey} is not delegated to the settlement \
+                 authority {}",
+                self.delegations.authority()
+            )));
+        }
+        if token_account.delegated_amount < token_account.amount {
+            return Err(PayTubeError::Registration(format!(
+                "delegation of {} from token account {token_account_pubkey} doesn't cover its \
+                 balance ({})",
+                token_account.delegated_amount, token_account.amount
+            )));
+        }
+
+        self.delegations
+            .insert(token_account_pubkey, *owner, mint_info.token_program_id);
+        Ok(())
+    }
+
+    /// Lock a participant's deposit of SOL, or of a mint's tokens if `mint` is
+    /// provided, in the channel.
+    ///
+    /// The participant's spend within the channel is then capped at the
+    /// deposit, rather than their full balance on the base chain. Fees for
+    /// the participant's SOL transfers are also drawn from their SOL deposit.
+    ///
+    /// The funds must be committed on the base chain first. Either the
+    /// participant escrowed at least the deposit in the channel's escrow (see
+    /// `with_escrow_channel`), or, for tokens, they delegated at least the
+    /// deposit to the channel's settlement authority with an SPL Token
+    /// `Approve`. An escrowed deposit is settled out of the escrow, while a
+    /// delegated token account is registered with the channel, as by
+    /// `register_token_account`.
+    ///
+    /// Only an escrowed deposit is actually locked. A delegation doesn't stop
+    /// the owner from spending the tokens, or revoking it, on the base chain
+    /// while the channel is open, in which case settlement fails simulation
+    /// and the transfers out of it aren't broadcast. Channels which can't
+    /// tolerate that should only accept escrowed deposits.
+    ///
+    /// Deposits are locked when the channel opens, before the participant's
+    /// account is used by any transfer. Participants without a locked deposit
+    /// spend against their base chain balance.
+    pub fn lock_deposit(
+        &mut self,
+        owner: &Pubkey,
+        mint: Option<&Pubkey>,
+        amount: u64,
+    ) -> Result<(), PayTubeError> {
+        self.check_open()?;
+        let is_escrowed = self
+            .escrowed_amount(owner, mint)?
+            .is_some_and(|escrowed| escrowed >= amount);
+        let (pubkey, account, delegated_token_program) = match mint {
+            Some(mint) => {
+                let (pubkey, mut account, token_account, mint_info) =
+                    self.load_base_chain_token_account(None, owner, mint, PayTubeError::Deposit)?;
+                let is_delegated = token_account.delegate
+                    == COption::Some(*self.delegations.authority())
+                    && token_account.delegated_amount >= amount;
+                if !is_escrowed && !is_delegated {
+                    return Err(PayTubeError::Deposit(format!(
+                        "deposit of {amount} from {pubkey} is neither escrowed nor delegated to \
+                         the settlement authority"
+                    )));
+                }
+                if !is_escrowed && amount > token_account.amount {
+                    return Err(PayTubeError::Deposit(format!(
+                        "deposit of {amount} exceeds the balance of {pubkey} ({})",
+                        token_account.amount
+                    )));
+                }
+
+                let mut state =
+                    StateWithExtensionsMut::<TokenAccount>::unpack(account.data_as_mut_slice())?;
+                state.base.amount = amount;
+                state.pack_base();
+                (
+                    pubkey,
+                    account,
+                    (!is_escrowed).then_some(mint_info.token_program_id),
+                )
+            }
+            None => {
+                if !is_escrowed {
+                    return Err(PayTubeError::Deposit(format!(
+                        "deposit of {amount} from {owner} is not escrowed"
+                    )));
+                }
+
+                // The deposit is paid out of escrow, so the wallet may well be
+                // empty, or not exist at all.
+                let mut account = self
+                    .account_loader
+                    .get_base_chain_account(owner)?
+                    .unwrap_or_default();
+                account.set_lamports(amount);
+                (*owner, account, None)
+            }
+        };
+
+        if !self.account_loader.lock_account(&pubkey, account) {
+            return Err(PayTubeError::Deposit(format!(
+                "{pubkey} already has a deposit locked or has been used in the channel"
+            )));
+        }
+        if let Some(token_program_id) = delegated_token_program {
+            self.delegations.insert(pubkey, *owner, token_program_id);
+        }
+        if let Some(escrow) = self.escrow.as_mut().filter(|_| is_escrowed) {
+            escrow.insert(pubkey, *owner, mint.copied());
+        }
+        Ok(())
     }
 
     /// The PayTube API. Processes a batch of PayTube transactions.
@@ -100,54 +472,509 @@ impl PayTubeChannel {
     /// * Custom Solana transaction ordering (e.g. MEV).
     ///
     /// The general scaffold of the PayTube API would remain the same.
-    pub fn process_paytube_transfers(&self, transactions: &[PayTubeTransaction]) {
-        // PayTube default configs.
-        let compute_budget = ComputeBudget::default();
-        let feature_set = FeatureSet::all_enabled();
-        let fee_structure = FeeStructure::default();
-        let lamports_per_signature = fee_structure.lamports_per_signature;
-        let rent_collector = RentCollector::default();
+    ///
+    /// Every transaction's signature, channel, nonce, expiry, mint, token
+    /// accounts and decimals are verified before processing. Any transaction
+    /// that fails verification is never executed.
+    ///
+    /// A recipient without an associated token account for the mint is paid
+    /// into an empty one, opened within the channel. Settlement creates it on
+    /// the base chain, funded by the payer.
+    ///
+    /// Returns a report with one receipt per submitted transaction. If an
+    /// account can't be loaded from the base chain partway through, whatever
+    /// was already executed stays committed, and the remaining transactions
+    /// are rejected with `AccountsUnavailable`. An error is only returned if
+    /// nothing was executed.
+    pub fn process_paytube_transfers(
+        &mut self,
+        transactions: &[SignedPayTubeTransaction],
+    ) -> Result<ProcessingReport, PayTubeError> {
+        self.check_open()?;
 
-        // PayTube loader/callback implementation.
-        let account_loader = PayTubeAccountLoader::new(&self.rpc_client);
+        // 1. Verify the signed envelopes, rejecting any forgeries, replays,
+        //    transfers signed for other channels, transfers of unknown mints,
+        //    between invalid token accounts or in the wrong decimals. Mints
+        //    are only resolved from the base chain for transactions with a
+        //    valid signature, channel, nonce and expiry.
+        //
+        //    Nonces are checked against a copy of the replay guard, so that
+        //    later transactions in the batch observe earlier ones. Nothing is
+        //    consumed from the channel's own replay guard until the
+        //    transaction has been executed.
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        let mut replay_guard = self.replay_guard.clone();
+        let mut receipts = vec![None; transactions.len()];
+        let mut accepted_indices = vec![];
+        let mut accepted = vec![];
+        for (index, signed) in transactions.iter().enumerate() {
+            let transaction = &signed.transaction;
+            let result = match signed
+                .verify()
+                .and_then(|()| check_channel(&self.id, transaction))
+                .and_then(|()| replay_guard.check(transaction, now))
+            {
+                Ok(()) => {
+                    if let Some(mint) = &transaction.mint {
+                        self.resolve_mint(mint)?;
+                    }
+                    check_transfer_accounts(&self.account_loader, &self.mints, transaction)?
+                }
+                Err(error) => Err(error),
+            };
+            match result.and_then(|()| check_decimals(&self.mints, transaction)) {
+                Ok(()) => {
+                    self.open_recipient_token_account(transaction)?;
+                    replay_guard.consume(transaction);
+                    accepted_indices.push(index);
+                    accepted.push(transaction.clone());
+                }
+                Err(error) => receipts[index] = Some(TransactionReceipt::rejected(error)),
+            }
+        }
+        let transactions = accepted;
 
-        // Solana SVM transaction batch processor.
-        let processor =
-            create_transaction_batch_processor(&account_loader, &feature_set, &compute_budget);
+        let lamports_per_signature = self.fee_structure.lamports_per_signature;
 
         // The PayTube transaction processing runtime environment.
         let processing_environment = TransactionProcessingEnvironment {
             blockhash: Hash::default(),
             epoch_total_stake: None,
             epoch_vote_accounts: None,
-            feature_set: Arc::new(feature_set),
-            fee_structure: Some(&fee_structure),
+            feature_set: self.feature_set.clone(),
+            fee_structure: Some(&self.fee_structure),
             lamports_per_signature,
-            rent_collector: Some(&rent_collector),
+            rent_collector: Some(&self.rent_collector),
         };
 
         // The PayTube transaction processing config for Solana SVM.
         let processing_config = TransactionProcessingConfig {
-            compute_budget: Some(compute_budget),
+            compute_budget: Some(self.compute_budget),
+            recording_config: ExecutionRecordingConfig {
+                enable_cpi_recording: false,
+                enable_log_recording: true,
+                enable_return_data_recording: false,
+            },
             ..Default::default()
         };
 
-        // 1. Convert to an SVM transaction batch.
-        let svm_transactions = create_svm_transactions(transactions);
+        // 2. Convert to an SVM transaction batch.
+        let svm_transactions = create_svm_transactions(&transactions, &self.mints, self.epoch)?;
+
+        // 3. Split the batch according to the channel's processing mode.
+        let batches = match self.processing_mode {
+            ProcessingMode::Batched => partition_write_conflict_free_batches(&svm_transactions),
+            ProcessingMode::Sequential => partition_conflict_free_batches(&svm_transactions),
+        };
+
+        for batch in batches {
+            let svm_transactions = &svm_transactions[batch.clone()];
+
+            // 4. Process transactions with the SVM API.
+            let results = self.processor.load_and_execute_sanitized_transactions(
+                &self.account_loader,
+                svm_transactions,
+                get_transaction_check_results(svm_transactions.len(), lamports_per_signature),
+                &processing_environment,
+                &processing_config,
+            );
+
+            // If an account couldn't be loaded from the base chain, the
+            // results are discarded, and neither this sub-batch nor any after
+            // it is processed. Everything committed so far stands, and the
+            // rest are rejected without consuming their nonces, so they can
+            // be submitted again.
+            if let Err(error) = self.account_loader.take_source_error() {
+                let error = PayTubeTransactionError::AccountsUnavailable {
+                    error: error.to_string(),
+                };
+                for index in &accepted_indices[batch.start..] {
+                    receipts[*index] = Some(TransactionReceipt::rejected(error.clone()));
+                }
+                break;
+            }
+
+            // 5. Commit the resulting account states to the channel.
+            self.account_loader.commit(svm_transactions, &results);
+
+            // 6. Record the results in the channel's ledger, and consume the
+            //    executed transactions' nonces.
+            self.ledger.record(
+                &transactions[batch.clone()],
+                &self.mints,
+                self.epoch,
+                &results,
+            );
+            for transaction in &transactions[batch.clone()] {
+                self.replay_guard.consume(transaction);
+            }
+
+            // 7. Write a receipt for each executed transaction.
+            accepted_indices[batch]
+                .iter()
+                .zip(results.loaded_transactions.iter())
+                .zip(results.execution_results.iter())
+                .for_each(|((index, load_result), execution_result)| {
+                    receipts[*index] = Some(TransactionReceipt::from_svm_result(
+                        load_result,
+                        execution_result,
+                    ));
+                });
+        }
+
+        Ok(ProcessingReport {
+            receipts: receipts.into_iter().flatten().collect(),
+        })
+    }
+
+    /// Preview the settlement of the channel, without sending anything to the
+    /// base chain.
+    ///
+    /// Returns the exact transactions `close` would send to settle the ledger
+    /// (unsigned), their estimated fees, every participant's net change in
+    /// balance, and the transfer fees withheld within the channel. The
+    /// transactions revoking delegations, which `close` sends after
+    /// settling, aren't included.
+    pub fn preview_settlement(&self) -> Result<SettlementPreview, PayTubeError> {
+        let settler = PayTubeSettler::new(
+            self.settlement_sink.as_ref(),
+            &self.account_loader,
+            &self.payer,
+        );
+        settler.preview_settle(
+            &self.ledger,
+            &self.delegations,
+            self.escrow.as_ref(),
+            base_chain_epoch(&self.account_loader)?,
+            self.fee_structure.lamports_per_signature,
+        )
+    }
+
+    /// Close the channel, settling the final ledger to the Solana base chain,
+    /// then revoking every delegation to the settlement authority.
+    ///
+    /// Returns a report of any delegations the channel couldn't revoke.
+    ///
+    /// Once the settlement has been signed and journaled, the channel no
+    /// longer accepts transfers, and a failed close can be resumed with
+    /// `resume_settlement`, or by closing again. Closing is also idempotent
+    /// across processes when the channel has a settlement journal: if one
+    /// already exists, the settlement it records is resumed. A journal
+    /// recording the settlement of a different ledger or payer is an error,
+    /// rather than being resumed.
+    pub fn close(&mut self) -> Result<SettlementReport, PayTubeError> {
+        if self.journal.is_some() {
+            return self.resume_settlement();
+        }
+        if let Some(path) = self
+            .settlement_journal
+            .as_ref()
+            .filter(|path| path.exists())
+        {
+            let journal = SettlementJournal::open(path)?;
+            if journal.fingerprint() != self.ledger.fingerprint(&self.payer) {
+                return Err(PayTubeError::Settlement(format!(
+                    "settlement journal {} records a different ledger",
+                    path.display()
+                )));
+            }
+            self.journal = Some(journal);
+            return self.resume_settlement();
+        }
+
+        // 1. Convert the ledger into base chain transactions using a
+        //    `PayTubeSettler`, and journal them along with the revocations
+        //    of every participant's delegation.
+        let settler = PayTubeSettler::new(
+            self.settlement_sink.as_ref(),
+            &self.account_loader,
+            &self.payer,
+        );
+        let journal = self.journal.insert(settler.prepare_settle(
+            &self.ledger,
+            &self.delegations,
+            self.escrow.as_ref(),
+            base_chain_epoch(&self.account_loader)?,
+            self.signer_provider.as_ref(),
+            self.settlement_journal.as_deref(),
+        )?);
+
+        // 2. Submit to the Solana base chain.
+        settler.settle(journal)
+    }
+
+    /// Resume an interrupted settlement, from the channel's own settlement in
+    /// progress, or else from its settlement journal.
+    ///
+    /// A fresh channel configured with the journal of a channel which was
+    /// interrupted can resume its settlement, without any transfers being
+    /// submitted to it again. Every journaled transaction is checked against
+    /// the base chain, and only those which haven't landed are sent again. If
+    /// one landed but failed, resuming stops with an error, since resending it
+    /// can't succeed. The journaled revocations are sent once the settlement
+    /// completes.
+    pub fn resume_settlement(&mut self) -> Result<SettlementReport, PayTubeError> {
+        let journal = match self.journal.take() {
+            Some(journal) => journal,
+            None => {
+                let path = self.settlement_journal.as_ref().ok_or_else(|| {
+                    PayTubeError::Settlement("no settlement journal configured".to_string())
+                })?;
+                SettlementJournal::open(path)?
+            }
+        };
+        let journal = self.journal.insert(journal);
+
+        let settler = PayTubeSettler::new(
+            self.settlement_sink.as_ref(),
+            &self.account_loader,
+            &self.payer,
+        );
+        settler.resume_settle(journal, self.signer_provider.as_ref())
+    }
+
+    /// Check that the channel hasn't started closing.
+    fn check_open(&self) -> Result<(), PayTubeError> {
+        if self.journal.is_some() {
+            return Err(PayTubeError::ChannelClosed);
+        }
+        Ok(())
+    }
+
+    /// Resolve a mint's token program and decimals from the base chain,
+    /// caching the result for the lifetime of the channel.
+    ///
+    /// Returns `None` if the mint doesn't exist or isn't an SPL Token or
+    /// Token-2022 mint.
+    fn resolve_mint(&mut self, mint: &Pubkey) -> Result<Option<MintInfo>, PayTubeError> {
+        if let Some(mint_info) = self.mints.get(mint) {
+            return Ok(Some(*mint_info));
+        }
+        let Some(mint_info) = self
+            .account_loader
+            .get_base_chain_account(mint)?
+            .and_then(|account| MintInfo::from_account(&account))
+        else {
+            return Ok(None);
+        };
+        self.mints.insert(*mint, mint_info);
+        Ok(Some(mint_info))
+    }
+
+    /// Open an empty associated token account within the channel for the
+    /// recipient of a token transfer, if they don't have one on the base
+    /// chain.
+    fn open_recipient_token_account(
+        &self,
+        transaction: &PayTubeTransaction,
+    ) -> Result<(), PayTubeError> {
+        let Some((mint, mint_info)) = transaction
+            .mint
+            .as_ref()
+            .filter(|_| transaction.destination_token_account.is_none())
+            .and_then(|mint| Some((mint, self.mints.get(mint)?)))
+        else {
+            return Ok(());
+        };
+        let (_, destination) = transaction.token_accounts(mint, mint_info);
+        self.account_loader
+            .load_account_or_insert_with(&destination, || {
+                let mint_account = self
+                    .account_loader
+                    .load_account(mint)?
+                    .ok_or(ProgramError::InvalidAccountData)?;
+                Ok(token::empty_token_account(
+                    &mint_account,
+                    &transaction.to,
+                    mint,
+                    &self.rent_collector.rent,
+                )?)
+            })?;
+        Ok(())
+    }
+
+    /// The amount of SOL, or of a mint's tokens, an owner has escrowed in the
+    /// channel's escrow, or `None` if they have no deposit there.
+    fn escrowed_amount(
+        &self,
+        owner: &Pubkey,
+        mint: Option<&Pubkey>,
+    ) -> Result<Option<u64>, PayTubeError> {
+        let Some(escrow_channel) = self.escrow.as_ref().map(Escrow::channel) else {
+            return Ok(None);
+        };
+        let (deposit_address, _) = escrow::find_deposit_address(escrow_channel, owner, mint);
+        let Some(account) = self
+            .account_loader
+            .get_base_chain_account(&deposit_address)?
+        else {
+            return Ok(None);
+        };
+        let deposit = escrow::decode_deposit(&account)?;
+        if deposit.channel != *escrow_channel
+            || deposit.owner != *owner
+            || deposit.mint.as_ref() != mint
+        {
+            return Ok(None);
+        }
+        Ok(Some(deposit.amount))
+    }
 
-        // 2. Process transactions with the SVM API.
-        let results = processor.load_and_execute_sanitized_transactions(
-            &account_loader,
-            &svm_transactions,
-            get_transaction_check_results(svm_transactions.len(), lamports_per_signature),
-            &processing_environment,
-            &processing_config,
+    /// Load an owner's token account for a mint from the base chain, checking
+    /// that it's a token account of the mint belonging to the owner.
+    ///
+    /// Loads the provided token account, or the owner's associated token
+    /// account if there isn't one. Any problem with the account is reported
+    /// with the provided error variant.
+    fn load_base_chain_token_account(
+        &mut self,
+        token_account: Option<&Pubkey>,
+        owner: &Pubkey,
+        mint: &Pubkey,
+        error: fn(String) -> PayTubeError,
+    ) -> Result<(Pubkey, AccountSharedData, TokenAccount, MintInfo), PayTubeError> {
+        let mint_info = self
+            .resolve_mint(mint)?
+            .ok_or_else(|| error(format!("{mint} is not an SPL Token or Token-2022 mint")))?;
+        let token_account_pubkey = token_account
+            .copied()
+            .unwrap_or_else(|| mint_info.token_account_address(owner, mint));
+        let account = self
+            .account_loader
+            .get_base_chain_account(&token_account_pubkey)?
+            .ok_or_else(|| error(format!("token account {token_account_pubkey} not found")))?;
+        if account.owner() != &mint_info.token_program_id {
+            return Err(error(format!(
+                "{token_account_pubkey} is not a token account of {}",
+                mint_info.token_program_id
+            )));
+        }
+
+        let token_account = StateWithExtensions::<TokenAccount>::unpack(account.data())
+            .map_err(|err| {
+                error(format!(
+                    "failed to decode token account {token_account_pubkey}: {err}"
+                ))
+            })?
+            .base;
+        if token_account.owner != *owner || token_account.mint != *mint {
+            return Err(error(format!(
+                "token account {token_account_pubkey} does not belong to {owner} for mint {mint}"
+            )));
+        }
+        Ok((token_account_pubkey, account, token_account, mint_info))
+    }
+}
+
+/// The base chain's current epoch, according to its clock sysvar.
+fn base_chain_epoch(account_loader: &PayTubeAccountLoader) -> Result<Epoch, PayTubeError> {
+    Ok(account_loader
+        .get_base_chain_account(&sysvar::clock::id())?
+        .and_then(|account| from_account::<Clock, _>(&account))
+        .map(|clock| clock.epoch)
+        .unwrap_or_default())
+}
+
+/// Check that a transfer's mint is known to the channel, and that any token
+/// accounts it names explicitly are token accounts of the mint owned by the
+/// sender or recipient.
+///
+/// The sender may instead be the source account's delegate, for at least the
+/// amount being sent, so accounts owned by a PDA can send through a delegate.
+///
+/// Only fails outright if a token account couldn't be loaded. Otherwise, the
+/// inner result is the verdict on the transfer.
+fn check_transfer_accounts(
+    account_loader: &PayTubeAccountLoader,
+    mints: &HashMap<Pubkey, MintInfo>,
+    transaction: &PayTubeTransaction,
+) -> Result<Result<(), PayTubeTransactionError>, PayTubeError> {
+    let token_accounts = [
+        (transaction.source_token_account, transaction.from, true),
+        (transaction.destination_token_account, transaction.to, false),
+    ];
+    let Some(mint) = transaction.mint else {
+        // SOL transfers don't use token accounts.
+        return Ok(
+            match token_accounts
+                .iter()
+                .find_map(|(token_account, ..)| *token_account)
+            {
+                Some(token_account) => {
+                    Err(PayTubeTransactionError::InvalidTokenAccount { token_account })
+                }
+                None => Ok(()),
+            },
         );
+    };
+    let Some(mint_info) = mints.get(&mint) else {
+        return Ok(Err(PayTubeTransactionError::InvalidMint { mint }));
+    };
 
-        // 3. Convert results into a final ledger using a `PayTubeSettler`.
-        let settler = PayTubeSettler::new(&self.rpc_client);
+    for (token_account, owner, is_source) in token_accounts {
+        let Some(token_account) = token_account else {
+            continue;
+        };
+        let is_valid = account_loader
+            .load_account(&token_account)?
+            .filter(|account| account.owner() == &mint_info.token_program_id)
+            .and_then(|account| {
+                StateWithExtensions::<TokenAccount>::unpack(account.data())
+                    .ok()
+                    .map(|state| state.base)
+            })
+            .is_some_and(|state| {
+                let is_delegate = is_source
+                    && state.delegate == COption::Some(owner)
+                    && state.delegated_amount >= transaction.amount;
+                state.mint == mint && (state.owner == owner || is_delegate)
+            });
+        if !is_valid {
+            return Ok(Err(PayTubeTransactionError::InvalidTokenAccount {
+                token_account,
+            }));
+        }
+    }
+    Ok(Ok(()))
+}
+
+/// Check that a transaction was signed for the channel it was submitted to.
+fn check_channel(
+    channel: &Pubkey,
+    transaction: &PayTubeTransaction,
+) -> Result<(), PayTubeTransactionError> {
+    if transaction.channel == *channel {
+        Ok(())
+    } else {
+        Err(PayTubeTransactionError::WrongChannel {
+            channel: transaction.channel,
+        })
+    }
+}
 
-        // 4. Submit to the Solana base chain.
-        settler.process_settle(transactions, results, &self.keys);
+/// Check that the decimals a transfer's amount is denominated in, if any,
+/// match those of its mint, or of SOL.
+fn check_decimals(
+    mints: &HashMap<Pubkey, MintInfo>,
+    transaction: &PayTubeTransaction,
+) -> Result<(), PayTubeTransactionError> {
+    let Some(decimals) = transaction.decimals else {
+        return Ok(());
+    };
+    let expected = match transaction.mint {
+        Some(mint) => {
+            mints
+                .get(&mint)
+                .ok_or(PayTubeTransactionError::InvalidMint { mint })?
+                .decimals
+        }
+        None => spl_token::native_mint::DECIMALS,
+    };
+    if decimals != expected {
+        return Err(PayTubeTransactionError::InvalidDecimals { decimals, expected });
     }
+    Ok(())
 }
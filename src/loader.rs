@@ -1,51 +1,177 @@
 //! PayTube's "account loader" component, which provides the SVM API with the
 //! ability to load accounts for PayTube channels.
 //!
-//! The account loader is a simple example of an RPC client that can first load
-//! an account from the base chain, then cache it locally within the protocol
-//! for the duration of the channel.
+//! The account loader first loads an account from the base chain, via an
+//! `AccountSource`, then caches it locally within the protocol for the
+//! duration of the channel.
+//!
+//! Once a batch has been executed, the resulting account states are committed
+//! back into the loader's cache, making it the channel's source of truth for
+//! all off-chain balances.
 
 use {
-    solana_client::rpc_client::RpcClient,
+    crate::{error::PayTubeError, source::AccountSource},
     solana_sdk::{
         account::{AccountSharedData, ReadableAccount},
         pubkey::Pubkey,
+        transaction::SanitizedTransaction,
+    },
+    solana_svm::{
+        transaction_processing_callback::TransactionProcessingCallback,
+        transaction_processor::LoadAndExecuteSanitizedTransactionsOutput,
+    },
+    std::{
+        collections::{hash_map::Entry, HashMap},
+        sync::{Mutex, RwLock},
     },
-    solana_svm::transaction_processing_callback::TransactionProcessingCallback,
-    std::{collections::HashMap, sync::RwLock},
 };
 
 /// An account loading mechanism to hoist accounts from the base chain up to
 /// an active PayTube channel.
 ///
 /// Employs a simple cache mechanism to ensure accounts are only loaded once.
-pub struct PayTubeAccountLoader<'a> {
+/// The loader is owned by the channel, so the cache lives for as long as the
+/// channel remains open.
+pub struct PayTubeAccountLoader {
     cache: RwLock<HashMap<Pubkey, AccountSharedData>>,
-    rpc_client: &'a RpcClient,
+    source: Box<dyn AccountSource>,
+    /// The first error the source returned while loading an account for the
+    /// SVM, whose callback interface has no way to report it.
+    source_error: Mutex<Option<PayTubeError>>,
 }
 
-impl<'a> PayTubeAccountLoader<'a> {
-    pub fn new(rpc_client: &'a RpcClient) -> Self {
+impl PayTubeAccountLoader {
+    pub fn new(source: Box<dyn AccountSource>) -> Self {
         Self {
             cache: RwLock::new(HashMap::new()),
-            rpc_client,
+            source,
+            source_error: Mutex::new(None),
         }
     }
-}
 
-/// SVM implementation of the `AccountLoader` plugin trait.
-///
-/// In the Agave validator, this implementation is `Bank`.
-impl TransactionProcessingCallback for PayTubeAccountLoader<'_> {
-    fn get_account_shared_data(&self, pubkey: &Pubkey) -> Option<AccountSharedData> {
+    /// Load an account directly from the base chain, bypassing the cache.
+    pub(crate) fn get_base_chain_account(
+        &self,
+        pubkey: &Pubkey,
+    ) -> Result<Option<AccountSharedData>, PayTubeError> {
+        self.source.get_account(pubkey)
+    }
+
+    /// Load an account's state within the channel, hoisting it from the base
+    /// chain into the cache the first time it's needed.
+    pub(crate) fn load_account(
+        &self,
+        pubkey: &Pubkey,
+    ) -> Result<Option<AccountSharedData>, PayTubeError> {
         if let Some(account) = self.cache.read().unwrap().get(pubkey) {
-            return Some(account.clone());
+            return Ok(Some(account.clone()));
         }
 
-        let account: AccountSharedData = self.rpc_client.get_account(pubkey).ok()?.into();
+        let Some(account) = self.source.get_account(pubkey)? else {
+            return Ok(None);
+        };
+        self.cache.write().unwrap().insert(*pubkey, account.clone());
+
+        Ok(Some(account))
+    }
+
+    /// Load an account's state within the channel, as by `load_account`,
+    /// starting it from the provided state instead if it doesn't exist on the
+    /// base chain.
+    pub(crate) fn load_account_or_insert_with(
+        &self,
+        pubkey: &Pubkey,
+        default: impl FnOnce() -> Result<AccountSharedData, PayTubeError>,
+    ) -> Result<AccountSharedData, PayTubeError> {
+        if let Some(account) = self.load_account(pubkey)? {
+            return Ok(account);
+        }
+        let account = default()?;
         self.cache.write().unwrap().insert(*pubkey, account.clone());
+        Ok(account)
+    }
 
-        Some(account)
+    /// Return the first error hit loading an account on behalf of the SVM
+    /// since the last call, if any.
+    ///
+    /// The SVM sees an account which failed to load as missing, so whatever
+    /// it produced in the meantime must be discarded when this fails.
+    pub(crate) fn take_source_error(&self) -> Result<(), PayTubeError> {
+        match self.source_error.lock().unwrap().take() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// Fix the channel's view of an account to the provided state, in place of
+    /// its base chain state.
+    ///
+    /// Returns `false`, leaving the cache untouched, if the account has
+    /// already been loaded into the channel.
+    pub(crate) fn lock_account(&self, pubkey: &Pubkey, account: AccountSharedData) -> bool {
+        match self.cache.write().unwrap().entry(*pubkey) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(account);
+                true
+            }
+        }
+    }
+
+    /// Commit the results of a processed batch to the loader's cache.
+    ///
+    /// For each transaction that executed successfully, the post-execution
+    /// state of every writable account is stored, so any transaction processed
+    /// afterwards will observe the updated balances. Failed transactions are
+    /// discarded.
+    pub fn commit(
+        &self,
+        svm_transactions: &[SanitizedTransaction],
+        svm_output: &LoadAndExecuteSanitizedTransactionsOutput,
+    ) {
+        self.cache
+            .write()
+            .unwrap()
+            .extend(committed_accounts(svm_transactions, svm_output));
+    }
+}
+
+/// Collect the post-execution state of every writable account touched by a
+/// successfully executed transaction in a processed batch.
+pub(crate) fn committed_accounts(
+    svm_transactions: &[SanitizedTransaction],
+    svm_output: &LoadAndExecuteSanitizedTransactionsOutput,
+) -> Vec<(Pubkey, AccountSharedData)> {
+    svm_transactions
+        .iter()
+        .zip(svm_output.loaded_transactions.iter())
+        .zip(svm_output.execution_results.iter())
+        .filter(|(_, result)| result.was_executed_successfully())
+        .filter_map(|((transaction, loaded_transaction), _)| {
+            let message = transaction.message();
+            let loaded_transaction = loaded_transaction.as_ref().ok()?;
+            Some(
+                loaded_transaction
+                    .accounts
+                    .iter()
+                    .enumerate()
+                    .filter(move |(index, _)| message.is_writable(*index))
+                    .map(|(_, (pubkey, account))| (*pubkey, account.clone())),
+            )
+        })
+        .flatten()
+        .collect()
+}
+
+/// SVM implementation of the `AccountLoader` plugin trait.
+///
+/// In the Agave validator, this implementation is `Bank`.
+impl TransactionProcessingCallback for PayTubeAccountLoader {
+    fn get_account_shared_data(&self, pubkey: &Pubkey) -> Option<AccountSharedData> {
+        self.load_account(pubkey).unwrap_or_else(|error| {
+            self.source_error.lock().unwrap().get_or_insert(error);
+            None
+        })
     }
 
     fn account_matches_owners(&self, account: &Pubkey, owners: &[Pubkey]) -> Option<usize> {
@@ -1,21 +1,49 @@
 //! A helper to initialize Solana SVM API's `TransactionBatchProcessor`.
 
 use {
+    crate::error::PayTubeError,
     solana_bpf_loader_program::syscalls::create_program_runtime_environment_v1,
     solana_compute_budget::compute_budget::ComputeBudget,
     solana_program_runtime::loaded_programs::{
-        BlockRelation, ForkGraph, LoadProgramMetrics, ProgramCacheEntry,
+        BlockRelation, ForkGraph, LoadProgramMetrics, ProgramCache, ProgramCacheEntry,
+    },
+    solana_sdk::{
+        account::ReadableAccount,
+        bpf_loader,
+        bpf_loader_upgradeable::{self, get_program_data_address, UpgradeableLoaderState},
+        clock::Slot,
+        feature_set::FeatureSet,
+        pubkey::Pubkey,
+        transaction::{self, SanitizedTransaction},
     },
-    solana_sdk::{account::ReadableAccount, clock::Slot, feature_set::FeatureSet, transaction},
     solana_svm::{
         account_loader::CheckedTransactionDetails,
         transaction_processing_callback::TransactionProcessingCallback,
         transaction_processor::TransactionBatchProcessor,
     },
     solana_system_program::system_processor,
-    std::sync::{Arc, RwLock},
+    std::{
+        collections::HashSet,
+        ops::Range,
+        sync::{Arc, RwLock},
+    },
 };
 
+/// How a PayTube channel hands a batch of transactions to the SVM.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProcessingMode {
+    /// The batch is executed in as few calls as possible, each against the
+    /// same initial state. Fastest, but transactions within a call can't
+    /// observe each other. Transactions which write to the same account are
+    /// still split into separate calls, so none of their writes are lost.
+    Batched,
+    /// The batch is split into conflict-free sub-batches, which are executed
+    /// in order, with state committed in between. Every transaction observes
+    /// the effects of all transactions before it.
+    #[default]
+    Sequential,
+}
+
 /// In order to use the `TransactionBatchProcessor`, another trait - Solana
 /// Program Runtime's `ForkGraph` - must be implemented, to tell the batch
 /// processor how to work across forks.
@@ -33,12 +61,14 @@ impl ForkGraph for PayTubeForkGraph {
 /// `TransactionBatchProcessor` for use within PayTube.
 ///
 /// We're simply configuring the mocked fork graph on the SVM API's program
-/// cache, then adding the System program to the processor's builtins.
+/// cache, adding the SPL Token, Token-2022 and Associated Token Account
+/// programs to it, then adding the System program and BPF loaders to the
+/// processor's builtins.
 pub(crate) fn create_transaction_batch_processor<CB: TransactionProcessingCallback>(
     callbacks: &CB,
     feature_set: &FeatureSet,
     compute_budget: &ComputeBudget,
-) -> TransactionBatchProcessor<PayTubeForkGraph> {
+) -> Result<TransactionBatchProcessor<PayTubeForkGraph>, PayTubeError> {
     let processor = TransactionBatchProcessor::<PayTubeForkGraph>::default();
 
     {
@@ -51,31 +81,46 @@ pub(crate) fn create_transaction_batch_processor<CB: TransactionProcessingCallba
         // (Use Loader v4 program to initialize runtime v2 if desired)
         cache.environments.program_runtime_v1 = Arc::new(
             create_program_runtime_environment_v1(feature_set, compute_budget, false, false)
-                .unwrap(),
+                .map_err(|err| PayTubeError::ProgramCache(err.to_string()))?,
         );
 
-        // Add the SPL Token program to the cache.
-        if let Some(program_account) = callbacks.get_account_shared_data(&spl_token::id()) {
-            let elf_bytes = program_account.data();
-            let program_runtime_environment = cache.environments.program_runtime_v1.clone();
-            cache.assign_program(
-                spl_token::id(),
-                Arc::new(
-                    ProgramCacheEntry::new(
-                        &solana_sdk::bpf_loader::id(),
-                        program_runtime_environment,
-                        0,
-                        0,
-                        elf_bytes,
-                        elf_bytes.len(),
-                        &mut LoadProgramMetrics::default(),
-                    )
-                    .unwrap(),
-                ),
-            );
+        // Add the SPL Token program, and the Associated Token Account program
+        // used to create recipients' token accounts at settlement, to the
+        // cache.
+        for program_id in [spl_token::id(), spl_associated_token_account::id()] {
+            if let Some(program_account) = callbacks.get_account_shared_data(&program_id) {
+                assign_program(
+                    &mut cache,
+                    program_id,
+                    &bpf_loader::id(),
+                    program_account.data(),
+                )?;
+            }
+        }
+
+        // Add the Token-2022 program to the cache. It's deployed with the
+        // upgradeable loader, so its ELF lives in a separate program data
+        // account.
+        let program_data_address = get_program_data_address(&spl_token_2022::id());
+        if let Some(program_data_account) = callbacks.get_account_shared_data(&program_data_address)
+        {
+            let elf_bytes = program_data_account
+                .data()
+                .get(UpgradeableLoaderState::size_of_programdata_metadata()..)
+                .unwrap_or_default();
+            assign_program(
+                &mut cache,
+                spl_token_2022::id(),
+                &bpf_loader_upgradeable::id(),
+                elf_bytes,
+            )?;
         }
     }
 
+    // Load the sysvars, such as the clock Token-2022 reads transfer fees by,
+    // from the base chain.
+    processor.fill_missing_sysvar_cache_entries(callbacks);
+
     // Add the system program builtin.
     processor.add_builtin(
         callbacks,
@@ -88,10 +133,11 @@ pub(crate) fn create_transaction_batch_processor<CB: TransactionProcessingCallba
         ),
     );
 
-    // Add the BPF Loader v2 builtin, for the SPL Token program.
+    // Add the BPF Loader v2 builtin, for the SPL Token and Associated Token
+    // Account programs.
     processor.add_builtin(
         callbacks,
-        solana_sdk::bpf_loader::id(),
+        bpf_loader::id(),
         "solana_bpf_loader_program",
         ProgramCacheEntry::new_builtin(
             0,
@@ -100,7 +146,45 @@ pub(crate) fn create_transaction_batch_processor<CB: TransactionProcessingCallba
         ),
     );
 
-    processor
+    // Add the upgradeable BPF Loader builtin, for the Token-2022 program.
+    processor.add_builtin(
+        callbacks,
+        bpf_loader_upgradeable::id(),
+        "solana_bpf_loader_upgradeable_program",
+        ProgramCacheEntry::new_builtin(
+            0,
+            b"solana_bpf_loader_upgradeable_program".len(),
+            solana_bpf_loader_program::Entrypoint::vm,
+        ),
+    );
+
+    Ok(processor)
+}
+
+/// Compile a program's ELF and add it to the program cache.
+fn assign_program(
+    cache: &mut ProgramCache<PayTubeForkGraph>,
+    program_id: Pubkey,
+    loader_id: &Pubkey,
+    elf_bytes: &[u8],
+) -> Result<(), PayTubeError> {
+    let program_runtime_environment = cache.environments.program_runtime_v1.clone();
+    cache.assign_program(
+        program_id,
+        Arc::new(
+            ProgramCacheEntry::new(
+                loader_id,
+                program_runtime_environment,
+                0,
+                0,
+                elf_bytes,
+                elf_bytes.len(),
+                &mut LoadProgramMetrics::default(),
+            )
+            .map_err(|err| PayTubeError::ProgramCache(err.to_string()))?,
+        ),
+    );
+    Ok(())
 }
 
 /// This functions is also a mock. In the Agave validator, the bank pre-checks
@@ -118,3 +202,61 @@ pub(crate) fn get_transaction_check_results(
         len
     ]
 }
+
+/// Split a batch of transactions into consecutive sub-batches, none of which
+/// contain two transactions with conflicting account locks.
+///
+/// Two transactions conflict if either one writes to an account the other
+/// one reads or writes. Since the sub-batches are consecutive, executing them
+/// in order preserves the original ordering of the batch.
+pub(crate) fn partition_conflict_free_batches(
+    transactions: &[SanitizedTransaction],
+) -> Vec<Range<usize>> {
+    partition_batches(transactions, true)
+}
+
+/// Split a batch of transactions into consecutive sub-batches, none of which
+/// contain two transactions writing to the same account.
+///
+/// Every transaction in a sub-batch executes against the same state, so
+/// without this, committing the sub-batch would keep only the last write to
+/// an account, and lose the rest.
+pub(crate) fn partition_write_conflict_free_batches(
+    transactions: &[SanitizedTransaction],
+) -> Vec<Range<usize>> {
+    partition_batches(transactions, false)
+}
+
+fn partition_batches(
+    transactions: &[SanitizedTransaction],
+    include_reads: bool,
+) -> Vec<Range<usize>> {
+    let mut batches = vec![];
+    let mut start = 0;
+    let mut writable = HashSet::new();
+    let mut readonly = HashSet::new();
+
+    for (index, transaction) in transactions.iter().enumerate() {
+        let locks = transaction.get_account_locks_unchecked();
+        let conflicts = locks.writable.iter().any(|key| writable.contains(*key))
+            || (include_reads
+                && (locks.writable.iter().any(|key| readonly.contains(*key))
+                    || locks.readonly.iter().any(|key| writable.contains(*key))));
+
+        if conflicts {
+            batches.push(start..index);
+            start = index;
+            writable.clear();
+            readonly.clear();
+        }
+
+        writable.extend(locks.writable.into_iter().copied());
+        readonly.extend(locks.readonly.into_iter().copied());
+    }
+
+    if start < transactions.len() {
+        batches.push(start..transactions.len());
+    }
+
+    batches
+}
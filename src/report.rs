@@ -0,0 +1,124 @@
+//! PayTube's reports, which tell the caller what happened to every PayTube
+//! transaction submitted to a channel, why a settlement was aborted, and what
+//! a completed settlement left behind.
+
+use {
+    crate::transaction::PayTubeTransactionError,
+    serde::{Deserialize, Serialize},
+    solana_sdk::{
+        instruction::Instruction, pubkey::Pubkey, signature::Signature,
+        transaction::TransactionError,
+    },
+    solana_svm::{
+        account_loader::TransactionLoadResult, transaction_results::TransactionExecutionResult,
+    },
+};
+
+/// The outcome of a single PayTube transaction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TransactionStatus {
+    /// The transaction was executed successfully, and recorded in the ledger.
+    Succeeded,
+    /// The transaction was handed to the SVM, but failed.
+    Failed(TransactionError),
+    /// The transaction was rejected by the channel, and never executed.
+    Rejected(PayTubeTransactionError),
+}
+
+/// A receipt for a single PayTube transaction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TransactionReceipt {
+    pub status: TransactionStatus,
+    /// The fee charged to the sender, in lamports. Only successful
+    /// transactions are committed to the channel, so a transaction which
+    /// failed was never charged one.
+    pub fee: u64,
+    pub compute_units_consumed: u64,
+    pub log_messages: Vec<String>,
+}
+
+impl TransactionReceipt {
+    pub(crate) fn rejected(error: PayTubeTransactionError) -> Self {
+        Self {
+            status: TransactionStatus::Rejected(error),
+            fee: 0,
+            compute_units_consumed: 0,
+            log_messages: vec![],
+        }
+    }
+
+    pub(crate) fn from_svm_result(
+        load_result: &TransactionLoadResult,
+        execution_result: &TransactionExecutionResult,
+    ) -> Self {
+        match execution_result {
+            TransactionExecutionResult::Executed { details, .. } => Self {
+                status: match &details.status {
+                    Ok(()) => TransactionStatus::Succeeded,
+                    Err(err) => TransactionStatus::Failed(err.clone()),
+                },
+                fee: match (&details.status, load_result) {
+                    (Ok(()), Ok(loaded)) => loaded.fee_details.total_fee(),
+                    _ => 0,
+                },
+                compute_units_consumed: details.executed_units,
+                log_messages: details.log_messages.clone().unwrap_or_default(),
+            },
+            TransactionExecutionResult::NotExecuted(err) => Self {
+                status: TransactionStatus::Failed(err.clone()),
+                fee: 0,
+                compute_units_consumed: 0,
+                log_messages: vec![],
+            },
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status == TransactionStatus::Succeeded
+    }
+}
+
+/// A report for a processed batch of PayTube transactions.
+///
+/// Contains one receipt per submitted transaction, in the order they were
+/// submitted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProcessingReport {
+    pub receipts: Vec<TransactionReceipt>,
+}
+
+/// A settlement transaction which failed simulation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SimulationFailure {
+    /// The index of the transaction in the packed settlement.
+    pub index: usize,
+    pub signature: Signature,
+    pub error: TransactionError,
+    pub logs: Vec<String>,
+}
+
+/// A report of every settlement transaction which failed simulation, causing
+/// the settlement to be aborted before any of them were broadcast.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SimulationReport {
+    pub failures: Vec<SimulationFailure>,
+}
+
+/// A delegation to the settlement authority which the channel couldn't
+/// revoke, because its signer provider can't sign for the token account's
+/// owner.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct UnrevokedDelegation {
+    pub token_account: Pubkey,
+    pub owner: Pubkey,
+    /// The instruction revoking the delegation, for the owner to sign and
+    /// submit themselves.
+    pub revoke: Instruction,
+}
+
+/// A report of a completed settlement.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SettlementReport {
+    /// Every delegation left in place once the channel settled.
+    pub unrevoked_delegations: Vec<UnrevokedDelegation>,
+}
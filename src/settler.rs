@@ -9,31 +9,87 @@
 //! transactions across a handful of users, but only the resulting difference
 //! between their balance when the channel opened and their balance when the
 //! channel is about to close are needed to create the settlement transaction.
+//! Those differences are then netted across all participants, so the number of
+//! settlement transfers grows with the number of participants, rather than
+//! the number of pairs of participants who transacted.
 
 use {
-    crate::transaction::PayTubeTransaction,
-    solana_client::rpc_client::RpcClient,
+    crate::{
+        error::PayTubeError,
+        escrow::{self, FinalBalance},
+        journal::{JournalStatus, SettlementJournal},
+        loader::PayTubeAccountLoader,
+        report::{SettlementReport, SimulationFailure, SimulationReport, UnrevokedDelegation},
+        signer::SignerProvider,
+        sink::{SettlementSink, SimulationOutcome},
+        token::MintInfo,
+        transaction::PayTubeTransaction,
+    },
+    solana_compute_budget::compute_budget_processor::{
+        DEFAULT_INSTRUCTION_COMPUTE_UNIT_LIMIT, MAX_COMPUTE_UNIT_LIMIT,
+    },
     solana_sdk::{
-        instruction::Instruction as SolanaInstruction, pubkey::Pubkey, signature::Keypair,
-        signer::Signer, system_instruction, transaction::Transaction as SolanaTransaction,
+        account::ReadableAccount, clock::Epoch, hash::Hash,
+        instruction::Instruction as SolanaInstruction, message::Message, packet::PACKET_DATA_SIZE,
+        pubkey::Pubkey, signature::Signature, system_instruction, system_program,
+        transaction::Transaction as SolanaTransaction,
     },
     solana_svm::transaction_processor::LoadAndExecuteSanitizedTransactionsOutput,
-    spl_associated_token_account::get_associated_token_address,
-    std::collections::HashMap,
+    spl_associated_token_account::{
+        get_associated_token_address, instruction::create_associated_token_account_idempotent,
+    },
+    std::{
+        collections::{BTreeMap, HashMap},
+        ops::Range,
+        path::Path,
+    },
 };
 
 /// The key used for storing ledger entries.
 ///
-/// Each entry in the ledger represents the movement of SOL or tokens between
-/// two parties. The two keys of the two parties are stored in a sorted array
-/// of length two, and the value's sign determines the direction of transfer.
+/// Each entry in the ledger represents a single participant's net position in
+/// SOL or a particular token, across every transfer they've been a part of.
+/// Transfers out of an account decrease its position, and transfers into it
+/// increase it.
 ///
-/// This design allows the ledger to combine transfers from a -> b and b -> a
-/// in the same entry, calculating the final delta between two parties.
+/// `account` is the account holding the balance: the owner itself for SOL, or
+/// one of the owner's token accounts for the mint.
 #[derive(PartialEq, Eq, Hash)]
 struct LedgerKey {
     mint: Option<Pubkey>,
-    keys: [Pubkey; 2],
+    owner: Pubkey,
+    account: Pubkey,
+}
+
+/// A single base chain transfer required to settle the ledger.
+///
+/// `source` and `destination` are the accounts transferred between, which are
+/// `from` and `to` themselves for SOL.
+///
+/// `fee` is the transfer fee withheld from `amount` by a Token-2022 mint with
+/// the `TransferFee` extension, which is zero for every other mint.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct NetTransfer {
+    pub mint: Option<Pubkey>,
+    pub from: Pubkey,
+    pub to: Pubkey,
+    pub source: Pubkey,
+    pub destination: Pubkey,
+    pub amount: u64,
+    pub fee: u64,
+}
+
+/// A participant's net change in balance of SOL or a particular token.
+///
+/// A participant transacting through more than one token account for a mint
+/// has a separate net change for each.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NetDelta {
+    pub mint: Option<Pubkey>,
+    pub owner: Pubkey,
+    /// The token account, or `None` for SOL.
+    pub token_account: Option<Pubkey>,
+    pub amount: i128,
 }
 
 /// A ledger of PayTube transactions, used to deconstruct into base chain
@@ -41,101 +97,971 @@ struct LedgerKey {
 ///
 /// The value is stored as a signed `i128`, in order to include a sign but also
 /// provide enough room to store `u64::MAX`.
-struct Ledger {
+///
+/// The ledger is kept alive for the lifetime of a channel, and each processed
+/// batch is recorded into it, so only one set of base chain transactions is
+/// needed when the channel is closed.
+///
+/// Since the ledger tracks net positions rather than pairwise balances, it can
+/// net across all participants at once. For example, a cycle of equal payments
+/// A -> B -> C -> A results in no base chain transfers at all.
+///
+/// Transfers of mints with a transfer fee credit the recipient with the
+/// amount sent less the fee, and the fee is recorded as withheld.
+#[derive(Default)]
+pub(crate) struct Ledger {
     ledger: HashMap<LedgerKey, i128>,
+    /// Every mint transferred in the ledger.
+    mints: HashMap<Pubkey, MintInfo>,
+    /// Transfer fees withheld within the channel, per mint.
+    withheld: BTreeMap<Pubkey, u64>,
 }
 
 impl Ledger {
-    fn new(
+    /// Record the results of a processed batch of PayTube transactions,
+    /// executed during `epoch`.
+    ///
+    /// The provided mints must include every mint transferred in the batch.
+    pub(crate) fn record(
+        &mut self,
         paytube_transactions: &[PayTubeTransaction],
-        svm_output: LoadAndExecuteSanitizedTransactionsOutput,
-    ) -> Self {
-        let mut ledger: HashMap<LedgerKey, i128> = HashMap::new();
+        mints: &HashMap<Pubkey, MintInfo>,
+        epoch: Epoch,
+        svm_output: &LoadAndExecuteSanitizedTransactionsOutput,
+    ) {
+        let ledger = &mut self.ledger;
+        let withheld = &mut self.withheld;
+        self.mints.extend(
+            paytube_transactions
+                .iter()
+                .filter_map(|transaction| transaction.mint)
+                .filter_map(|mint| Some((mint, *mints.get(&mint)?))),
+        );
         paytube_transactions
             .iter()
-            .zip(svm_output.execution_results)
+            .zip(svm_output.execution_results.iter())
             .for_each(|(transaction, result)| {
                 // Only append to the ledger if the PayTube transaction was
                 // successful.
                 if result.was_executed_successfully() {
                     let mint = transaction.mint;
-                    let mut keys = [transaction.from, transaction.to];
-                    keys.sort();
-                    let amount = if keys.iter().position(|k| k.eq(&transaction.from)).unwrap() == 0
-                    {
-                        transaction.amount as i128
-                    } else {
-                        -(transaction.amount as i128)
-                    };
-                    *ledger.entry(LedgerKey { mint, keys }).or_default() += amount;
+                    let mint_info = mint.and_then(|mint| mints.get(&mint));
+                    let fee = mint_info
+                        .map(|mint_info| mint_info.transfer_fee(epoch, transaction.amount))
+                        .unwrap_or_default();
+                    let (source, destination) = mint
+                        .zip(mint_info)
+                        .map(|(mint, mint_info)| transaction.token_accounts(&mint, mint_info))
+                        .unwrap_or((transaction.from, transaction.to));
+                    *ledger
+                        .entry(LedgerKey {
+                            mint,
+                            owner: transaction.from,
+                            account: source,
+                        })
+                        .or_default() -= transaction.amount as i128;
+                    *ledger
+                        .entry(LedgerKey {
+                            mint,
+                            owner: transaction.to,
+                            account: destination,
+                        })
+                        .or_default() += (transaction.amount - fee) as i128;
+                    if let (Some(mint), true) = (mint, fee > 0) {
+                        *withheld.entry(mint).or_default() += fee;
+                    }
                 }
             });
-        Self { ledger }
     }
 
-    fn generate_base_chain_instructions(&self) -> Vec<SolanaInstruction> {
+    /// Every participant's non-zero net position, sorted by mint, owner, then
+    /// token account.
+    pub(crate) fn net_deltas(&self) -> Vec<NetDelta> {
+        let mut net_deltas = self
+            .ledger
+            .iter()
+            .filter(|(_, amount)| **amount != 0)
+            .map(|(key, amount)| NetDelta {
+                mint: key.mint,
+                owner: key.owner,
+                token_account: key.mint.map(|_| key.account),
+                amount: *amount,
+            })
+            .collect::<Vec<_>>();
+        net_deltas.sort_by(|a, b| {
+            (a.mint, a.owner, a.token_account).cmp(&(b.mint, b.owner, b.token_account))
+        });
+        net_deltas
+    }
+
+    /// A fingerprint of every net position in the ledger, and the payer
+    /// settling them, identifying the settlement a journal records.
+    pub(crate) fn fingerprint(&self, payer: &Pubkey) -> Hash {
+        let mut hasher = solana_sdk::hash::Hasher::default();
+        hasher.hash(payer.as_ref());
+        for net_delta in self.net_deltas() {
+            hasher.hash(net_delta.mint.unwrap_or_default().as_ref());
+            hasher.hash(net_delta.owner.as_ref());
+            hasher.hash(net_delta.token_account.unwrap_or_default().as_ref());
+            hasher.hash(&net_delta.amount.to_le_bytes());
+        }
+        hasher.result()
+    }
+
+    /// The transfer fees withheld within the channel, per mint.
+    pub(crate) fn withheld_fees(&self) -> &BTreeMap<Pubkey, u64> {
+        &self.withheld
+    }
+
+    /// Compute the minimal set of transfers needed to settle every
+    /// participant's net position.
+    ///
+    /// For each mint, participants are split into debtors and creditors, and
+    /// the largest remaining debtor greedily pays the largest remaining
+    /// creditor. Every transfer fully settles at least one of the two, so
+    /// settling `n` participants in a mint never takes more than `n - 1`
+    /// transfers.
+    ///
+    /// For mints with a transfer fee, each creditor is sent enough to cover
+    /// the fee withheld during `epoch`, so they receive exactly what they're
+    /// owed. Fees withheld within the channel mean a mint's debts exceed its
+    /// credits, and any debt left once every creditor is paid stays with the
+    /// debtor.
+    ///
+    /// Debts of participants who escrowed their deposit are paid out of
+    /// escrow, which releases them to the payer as the escrow's operator, so
+    /// the payer pays their creditors in their place.
+    pub(crate) fn net_transfers(
+        &self,
+        escrow: Option<&Escrow>,
+        payer: &Pubkey,
+        epoch: Epoch,
+    ) -> Vec<NetTransfer> {
+        let mut positions: BTreeMap<Option<Pubkey>, BTreeMap<(Pubkey, Pubkey), i128>> =
+            BTreeMap::new();
         self.ledger
             .iter()
-            .map(|(key, amount)| {
-                let (from, to, amount) = if *amount < 0 {
-                    (key.keys[1], key.keys[0], (amount * -1) as u64)
-                } else {
-                    (key.keys[0], key.keys[1], *amount as u64)
-                };
-                if let Some(mint) = key.mint {
-                    let source_pubkey = get_associated_token_address(&from, &mint);
-                    let destination_pubkey = get_associated_token_address(&to, &mint);
-                    return spl_token::instruction::transfer(
-                        &spl_token::id(),
-                        &source_pubkey,
-                        &destination_pubkey,
-                        &from,
-                        &[],
-                        amount,
-                    )
-                    .unwrap();
+            .filter(|(_, amount)| **amount != 0)
+            .for_each(|(key, amount)| {
+                let position =
+                    if *amount < 0 && escrow.is_some_and(|escrow| escrow.contains(&key.account)) {
+                        let source = key
+                            .mint
+                            .and_then(|mint| {
+                                let mint_info = self.mints.get(&mint)?;
+                                Some(mint_info.token_account_address(payer, &mint))
+                            })
+                            .unwrap_or(*payer);
+                        (*payer, source)
+                    } else {
+                        (key.owner, key.account)
+                    };
+                *positions
+                    .entry(key.mint)
+                    .or_default()
+                    .entry(position)
+                    .or_default() += *amount;
+            });
+
+        let mut transfers = vec![];
+        for (mint, positions) in positions {
+            let mint_info = mint.and_then(|mint| self.mints.get(&mint));
+            let transfer_fee = |amount: u64| {
+                mint_info.map_or(0, |mint_info| mint_info.transfer_fee(epoch, amount))
+            };
+            let inverse_transfer_fee = |amount: u64| {
+                mint_info.map_or(0, |mint_info| mint_info.inverse_transfer_fee(epoch, amount))
+            };
+
+            let (mut debtors, mut creditors): (Vec<_>, Vec<_>) = positions
+                .into_iter()
+                .filter(|(_, amount)| *amount != 0)
+                .partition(|(_, amount)| *amount < 0);
+
+            // Largest positions first, with the pubkeys as a tie-breaker to
+            // keep the output deterministic.
+            let by_amount = |a: &((Pubkey, Pubkey), i128), b: &((Pubkey, Pubkey), i128)| {
+                b.1.abs().cmp(&a.1.abs()).then(a.0.cmp(&b.0))
+            };
+            debtors.sort_by(by_amount);
+            creditors.sort_by(by_amount);
+
+            let (mut d, mut c) = (0, 0);
+            while d < debtors.len() && c < creditors.len() {
+                let ((from, source), debt) = &mut debtors[d];
+                let ((to, destination), credit) = &mut creditors[c];
+
+                // Send the creditor everything they're owed plus the fee,
+                // or everything the debtor owes if that's not enough.
+                let owed = *credit as u64;
+                let amount = (owed + inverse_transfer_fee(owed)).min((-*debt) as u64);
+                let fee = transfer_fee(amount);
+                transfers.push(NetTransfer {
+                    mint,
+                    from: *from,
+                    to: *to,
+                    source: *source,
+                    destination: *destination,
+                    amount,
+                    fee,
+                });
+                *debt += amount as i128;
+                *credit -= ((amount - fee) as i128).min(*credit);
+                if *debt == 0 {
+                    d += 1;
                 }
-                system_instruction::transfer(&from, &to, amount)
+                if *credit == 0 {
+                    c += 1;
+                }
+            }
+        }
+
+        transfers
+    }
+
+    /// Build the base chain instructions settling the ledger, grouped by
+    /// transfer. Each group is always packed into a single transaction.
+    ///
+    /// If a recipient's associated token account doesn't exist on the base
+    /// chain, because they never had one or closed it while the channel was
+    /// open, the group paying into it first creates it, funded by the payer.
+    /// Creation is idempotent, so every group still succeeds on its own when a
+    /// recipient is paid by more than one debtor.
+    fn generate_base_chain_instructions(
+        &self,
+        delegations: &Delegations,
+        escrow: Option<&Escrow>,
+        account_loader: &PayTubeAccountLoader,
+        payer: &Pubkey,
+        epoch: Epoch,
+    ) -> Result<Vec<Vec<SolanaInstruction>>, PayTubeError> {
+        self.net_transfers(escrow, payer, epoch)
+            .into_iter()
+            .map(
+                |NetTransfer {
+                     mint,
+                     from,
+                     to,
+                     source,
+                     destination,
+                     amount,
+                     fee,
+                 }| {
+                    if let Some(mint) = mint {
+                        let mint_info = self.mints.get(&mint).ok_or_else(|| {
+                            PayTubeError::Settlement(format!("unknown mint {mint}"))
+                        })?;
+                        let mut instructions = vec![];
+                        if destination == mint_info.token_account_address(&to, &mint)
+                            && !account_loader
+                                .get_base_chain_account(&destination)?
+                                .is_some_and(|account| {
+                                    account.owner() == &mint_info.token_program_id
+                                })
+                        {
+                            instructions.push(mint_info.create_token_account(payer, &to, &mint));
+                        }
+                        instructions.push(mint_info.transfer(
+                            &mint,
+                            &source,
+                            &destination,
+                            &delegations.transfer_authority(&source, &from),
+                            amount,
+                            fee,
+                        )?);
+                        return Ok(instructions);
+                    }
+                    Ok(vec![system_instruction::transfer(&from, &to, amount)])
+                },
+            )
+            .collect()
+    }
+}
+
+/// Token accounts whose owners have delegated them to the channel's
+/// settlement authority, with an SPL Token `Approve`.
+///
+/// Transfers out of a delegated token account are signed by the settlement
+/// authority alone, rather than by the account's owner. Each delegation is
+/// revoked once the channel has settled, which the owner signs.
+pub(crate) struct Delegations {
+    authority: Pubkey,
+    /// The owner and token program of each delegated token account.
+    token_accounts: BTreeMap<Pubkey, (Pubkey, Pubkey)>,
+}
+
+impl Delegations {
+    pub(crate) fn new(authority: Pubkey) -> Self {
+        Self {
+            authority,
+            token_accounts: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn authority(&self) -> &Pubkey {
+        &self.authority
+    }
+
+    /// Record an owner's token account as delegated.
+    pub(crate) fn insert(
+        &mut self,
+        token_account: Pubkey,
+        owner: Pubkey,
+        token_program_id: Pubkey,
+    ) {
+        self.token_accounts
+            .insert(token_account, (owner, token_program_id));
+    }
+
+    /// The authority which signs transfers out of an owner's token account.
+    fn transfer_authority(&self, token_account: &Pubkey, owner: &Pubkey) -> Pubkey {
+        if self.token_accounts.contains_key(token_account) {
+            self.authority
+        } else {
+            *owner
+        }
+    }
+
+    /// Instructions revoking every delegation, by token account, grouped by
+    /// the token account's owner, who signs them.
+    fn revoke_instructions(
+        &self,
+    ) -> Result<BTreeMap<Pubkey, Vec<(Pubkey, SolanaInstruction)>>, PayTubeError> {
+        let mut revocations: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for (token_account, (owner, token_program_id)) in &self.token_accounts {
+            let instruction =
+                spl_token_2022::instruction::revoke(token_program_id, token_account, owner, &[])?;
+            revocations
+                .entry(*owner)
+                .or_default()
+                .push((*token_account, instruction));
+        }
+        Ok(revocations)
+    }
+}
+
+/// Deposits escrowed with the escrow program, which participants locked in
+/// the channel with `lock_deposit`.
+///
+/// Escrowed participants are settled by closing the escrow channel, rather
+/// than by transfers out of their own accounts. The escrow pays each of them
+/// back their deposit, less whatever they owe, and releases what they owe to
+/// its operator, the payer, who pays it on to their creditors.
+pub(crate) struct Escrow {
+    channel: Pubkey,
+    /// The owner and mint of each escrowed deposit, by the account it was
+    /// locked in.
+    deposits: BTreeMap<Pubkey, (Pubkey, Option<Pubkey>)>,
+}
+
+impl Escrow {
+    pub(crate) fn new(channel: Pubkey) -> Self {
+        Self {
+            channel,
+            deposits: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn channel(&self) -> &Pubkey {
+        &self.channel
+    }
+
+    /// Record an owner's deposit of a mint, or of SOL if `mint` is `None`, as
+    /// escrowed, and locked in the provided account.
+    pub(crate) fn insert(&mut self, account: Pubkey, owner: Pubkey, mint: Option<Pubkey>) {
+        self.deposits.insert(account, (owner, mint));
+    }
+
+    fn contains(&self, account: &Pubkey) -> bool {
+        self.deposits.contains_key(account)
+    }
+
+    /// Instructions closing the escrow channel, paying each depositor back
+    /// their deposit less whatever they owe.
+    ///
+    /// The payer must operate the escrow channel, and every deposit in it must
+    /// have been locked in the channel, since the final ledger has to cover
+    /// them all. Each token depositor is paid back to their associated token
+    /// account, and tokens left in a vault are swept to the payer's. Any of
+    /// these which doesn't exist, because its owner never had one or closed
+    /// it while the channel was open, is created first, funded by the payer.
+    fn close_instructions(
+        &self,
+        ledger: &Ledger,
+        account_loader: &PayTubeAccountLoader,
+        payer: &Pubkey,
+    ) -> Result<Vec<SolanaInstruction>, PayTubeError> {
+        let channel = account_loader
+            .get_base_chain_account(&self.channel)?
+            .ok_or_else(|| {
+                PayTubeError::Settlement(format!("escrow channel {} not found", self.channel))
             })
-            .collect::<Vec<_>>()
+            .and_then(|account| escrow::decode_channel(&account))?;
+        if channel.operator != *payer {
+            return Err(PayTubeError::Settlement(format!(
+                "escrow channel {} is operated by {}, not the payer {payer}",
+                self.channel, channel.operator
+            )));
+        }
+        if channel.num_deposits as usize != self.deposits.len() {
+            return Err(PayTubeError::Settlement(format!(
+                "escrow channel {} holds {} deposits, but {} were locked in the channel",
+                self.channel,
+                channel.num_deposits,
+                self.deposits.len()
+            )));
+        }
+
+        let mut instructions = vec![];
+        let mut final_ledger = vec![];
+        for (account, (owner, mint)) in &self.deposits {
+            let (deposit_address, _) =
+                escrow::find_deposit_address(&self.channel, owner, mint.as_ref());
+            let deposit = account_loader
+                .get_base_chain_account(&deposit_address)?
+                .ok_or_else(|| {
+                    PayTubeError::Settlement(format!("escrow deposit {deposit_address} not found"))
+                })
+                .and_then(|account| escrow::decode_deposit(&account))?;
+            let debt = ledger
+                .ledger
+                .get(&LedgerKey {
+                    mint: *mint,
+                    owner: *owner,
+                    account: *account,
+                })
+                .map_or(0, |amount| (-*amount).max(0));
+            let amount = u64::try_from(i128::from(deposit.amount) - debt).map_err(|_| {
+                PayTubeError::Settlement(format!(
+                    "{owner} owes more than their escrowed deposit {deposit_address}"
+                ))
+            })?;
+
+            if let Some(mint) = mint {
+                if final_ledger
+                    .iter()
+                    .all(|entry: &FinalBalance| entry.mint != Some(*mint))
+                    && account_loader
+                        .get_base_chain_account(&get_associated_token_address(payer, mint))?
+                        .is_none()
+                {
+                    instructions.push(create_associated_token_account_idempotent(
+                        payer,
+                        payer,
+                        mint,
+                        &spl_token::id(),
+                    ));
+                }
+
+                if account_loader
+                    .get_base_chain_account(&get_associated_token_address(owner, mint))?
+                    .is_none_or(|account| account.owner() != &spl_token::id())
+                {
+                    instructions.push(create_associated_token_account_idempotent(
+                        payer,
+                        owner,
+                        mint,
+                        &spl_token::id(),
+                    ));
+                }
+            }
+            final_ledger.push(FinalBalance {
+                owner: *owner,
+                mint: *mint,
+                amount,
+            });
+        }
+        instructions.push(escrow::close_channel(payer, &self.channel, &final_ledger));
+        Ok(instructions)
     }
 }
 
+/// A preview of everything settling a channel would post to the base chain.
+#[derive(Debug)]
+pub struct SettlementPreview {
+    /// The packed settlement transactions, unsigned.
+    pub transactions: Vec<SolanaTransaction>,
+    /// The estimated total fee for all settlement transactions, in lamports.
+    pub estimated_fee: u64,
+    /// Each participant's net change in balance, per mint.
+    pub net_deltas: Vec<NetDelta>,
+    /// Transfer fees withheld by Token-2022 mints within the channel, per
+    /// mint.
+    pub withheld_fees: BTreeMap<Pubkey, u64>,
+}
+
+/// Conservative estimates of the compute units consumed by each kind of
+/// settlement instruction, used when packing transactions.
+const SYSTEM_TRANSFER_COMPUTE_UNITS: u64 = 300;
+const TOKEN_TRANSFER_COMPUTE_UNITS: u64 = 10_000;
+const CREATE_TOKEN_ACCOUNT_COMPUTE_UNITS: u64 = 30_000;
+
 /// PayTube final transaction settler.
 pub struct PayTubeSettler<'a> {
-    rpc_client: &'a RpcClient,
+    settlement_sink: &'a dyn SettlementSink,
+    account_loader: &'a PayTubeAccountLoader,
+    payer: &'a Pubkey,
 }
 
 impl<'a> PayTubeSettler<'a> {
-    pub fn new(rpc_client: &'a RpcClient) -> Self {
-        Self { rpc_client }
+    pub fn new(
+        settlement_sink: &'a dyn SettlementSink,
+        account_loader: &'a PayTubeAccountLoader,
+        payer: &'a Pubkey,
+    ) -> Self {
+        Self {
+            settlement_sink,
+            account_loader,
+            payer,
+        }
     }
 
-    /// Settle the payment channel results to the Solana blockchain.
-    pub fn process_settle(
+    /// Pack the ledger's settlement instructions, for the base chain's current
+    /// epoch, into unsigned transactions.
+    ///
+    /// If any deposits are escrowed, the first transaction closes the escrow,
+    /// releasing what escrowed debtors owe to the payer, who pays it on in the
+    /// transfers after it. Returns the transactions along with how many of them
+    /// must land before the rest can.
+    fn pack_transactions(
         &self,
-        paytube_transactions: &[PayTubeTransaction],
-        svm_output: LoadAndExecuteSanitizedTransactionsOutput,
-        keys: &[Keypair],
-    ) {
-        // Build the ledger from the processed PayTube transactions.
-        let ledger = Ledger::new(paytube_transactions, svm_output);
+        ledger: &Ledger,
+        delegations: &Delegations,
+        escrow: Option<&Escrow>,
+        epoch: Epoch,
+    ) -> Result<(Vec<SolanaTransaction>, usize), PayTubeError> {
+        let mut transactions = match escrow.filter(|escrow| !escrow.deposits.is_empty()) {
+            Some(escrow) => pack_instructions(
+                vec![escrow.close_instructions(ledger, self.account_loader, self.payer)?],
+                self.payer,
+            )?,
+            None => vec![],
+        };
+        let prerequisites = transactions.len();
 
         // Build the Solana instructions from the ledger.
-        let instructions = ledger.generate_base_chain_instructions();
-
-        // Send the transactions to the Solana blockchain.
-        let recent_blockhash = self.rpc_client.get_latest_blockhash().unwrap();
-        instructions.chunks(10).for_each(|chunk| {
-            let transaction = SolanaTransaction::new_signed_with_payer(
-                chunk,
-                Some(&keys[0].pubkey()),
-                keys,
-                recent_blockhash,
-            );
-            self.rpc_client
-                .send_and_confirm_transaction(&transaction)
-                .unwrap();
-        });
+        let instructions = ledger.generate_base_chain_instructions(
+            delegations,
+            escrow,
+            self.account_loader,
+            self.payer,
+            epoch,
+        )?;
+
+        transactions.extend(pack_instructions(instructions, self.payer)?);
+        Ok((transactions, prerequisites))
+    }
+
+    /// Preview the settlement of the payment channel results, without sending
+    /// anything to the Solana blockchain.
+    pub(crate) fn preview_settle(
+        &self,
+        ledger: &Ledger,
+        delegations: &Delegations,
+        escrow: Option<&Escrow>,
+        epoch: Epoch,
+        lamports_per_signature: u64,
+    ) -> Result<SettlementPreview, PayTubeError> {
+        let (transactions, _) = self.pack_transactions(ledger, delegations, escrow, epoch)?;
+        let estimated_fee = transactions
+            .iter()
+            .map(|transaction| {
+                u64::from(transaction.message.header.num_required_signatures)
+                    * lamports_per_signature
+            })
+            .sum();
+        Ok(SettlementPreview {
+            transactions,
+            estimated_fee,
+            net_deltas: ledger.net_deltas(),
+            withheld_fees: ledger.withheld_fees().clone(),
+        })
+    }
+
+    /// Sign and journal every transaction settling the payment channel
+    /// results to the Solana blockchain, followed by those revoking every
+    /// delegation to the settlement authority, without broadcasting anything.
+    ///
+    /// The journal is written to the provided path, if any, and is settled
+    /// with `settle`.
+    pub(crate) fn prepare_settle(
+        &self,
+        ledger: &Ledger,
+        delegations: &Delegations,
+        escrow: Option<&Escrow>,
+        epoch: Epoch,
+        signer_provider: &dyn SignerProvider,
+        journal_path: Option<&Path>,
+    ) -> Result<SettlementJournal, PayTubeError> {
+        let (transactions, prerequisites) =
+            self.pack_transactions(ledger, delegations, escrow, epoch)?;
+
+        // Sign the transactions.
+        let recent_blockhash = self.settlement_sink.get_latest_blockhash()?;
+        let mut transactions = transactions
+            .into_iter()
+            .map(|transaction| {
+                sign_transaction(
+                    transaction,
+                    signer_provider,
+                    recent_blockhash,
+                    self.settlement_sink.accepts_partial_signatures(),
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let (revocations, unrevoked_delegations) =
+            self.sign_revocations(delegations, signer_provider, recent_blockhash)?;
+        transactions.extend(revocations);
+
+        SettlementJournal::create(
+            journal_path,
+            ledger.fingerprint(self.payer),
+            prerequisites,
+            transactions,
+            unrevoked_delegations,
+        )
     }
+
+    /// Sign transactions revoking every delegation to the settlement
+    /// authority, each signed by the token account's owner, through the
+    /// signer provider, or left for the owner to sign if the settlement sink
+    /// accepts partially signed transactions.
+    ///
+    /// Delegations whose owner the signer provider can't sign for, or which
+    /// are owned by a PDA, which can never sign, are left in place, and
+    /// returned for their owners to revoke themselves.
+    fn sign_revocations(
+        &self,
+        delegations: &Delegations,
+        signer_provider: &dyn SignerProvider,
+        recent_blockhash: Hash,
+    ) -> Result<(Vec<SolanaTransaction>, Vec<UnrevokedDelegation>), PayTubeError> {
+        let mut transactions = vec![];
+        let mut unrevoked_delegations = vec![];
+        for (owner, revocations) in delegations.revoke_instructions()? {
+            // A PDA can never sign, so there's no point asking for its
+            // signature.
+            let signed = if owner.is_on_curve() {
+                let instructions = revocations
+                    .iter()
+                    .map(|(_, instruction)| vec![instruction.clone()])
+                    .collect();
+                pack_instructions(instructions, self.payer)?
+                    .into_iter()
+                    .map(|transaction| {
+                        sign_transaction(
+                            transaction,
+                            signer_provider,
+                            recent_blockhash,
+                            self.settlement_sink.accepts_partial_signatures(),
+                        )
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            } else {
+                Err(PayTubeError::MissingSigner(owner))
+            };
+            match signed {
+                Ok(signed) => transactions.extend(signed),
+                Err(PayTubeError::MissingSigner(pubkey)) if pubkey == owner => {
+                    unrevoked_delegations.extend(revocations.into_iter().map(
+                        |(token_account, revoke)| UnrevokedDelegation {
+                            token_account,
+                            owner,
+                            revoke,
+                        },
+                    ));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok((transactions, unrevoked_delegations))
+    }
+
+    /// Resume an interrupted settlement from its journal.
+    ///
+    /// Transactions which already landed are never sent again. The rest are
+    /// resent as-is while their blockhash is still valid, or re-signed with a
+    /// fresh one once it has expired, since they can then never land.
+    pub(crate) fn resume_settle(
+        &self,
+        journal: &mut SettlementJournal,
+        signer_provider: &dyn SignerProvider,
+    ) -> Result<SettlementReport, PayTubeError> {
+        let mut fresh_blockhash = None;
+        for index in 0..journal.entries().len() {
+            let entry = &journal.entries()[index];
+            if entry.status == JournalStatus::Confirmed {
+                continue;
+            }
+
+            // Check the blockhash before the status, so a transaction which
+            // lands in between can't be mistaken for one which never will.
+            let expired = !self
+                .settlement_sink
+                .is_blockhash_valid(&entry.transaction.message.recent_blockhash)?;
+
+            // Never resend a transaction which already landed, and stop if one
+            // landed but failed, since resending it can't succeed.
+            match self
+                .settlement_sink
+                .get_transaction_status(&entry.signature)?
+            {
+                Some(Ok(())) => {
+                    journal.set_status(index, JournalStatus::Confirmed)?;
+                    continue;
+                }
+                Some(Err(err)) => {
+                    return Err(PayTubeError::Settlement(format!(
+                        "settlement transaction {} landed but failed: {err}",
+                        entry.signature
+                    )));
+                }
+                None => {}
+            }
+
+            // A transaction whose blockhash had already expired before its
+            // status was checked can never land, so it's safe to re-sign it
+            // with a fresh one. Otherwise, it's resent exactly as signed, so
+            // it can land at most once.
+            if expired {
+                let recent_blockhash = match fresh_blockhash {
+                    Some(blockhash) => blockhash,
+                    None => *fresh_blockhash.insert(self.settlement_sink.get_latest_blockhash()?),
+                };
+                let transaction = sign_transaction(
+                    entry.transaction.clone(),
+                    signer_provider,
+                    recent_blockhash,
+                    self.settlement_sink.accepts_partial_signatures(),
+                )?;
+                journal.replace(index, transaction)?;
+            }
+        }
+
+        self.settle(journal)
+    }
+
+    /// Simulate, then submit and confirm, every journaled transaction which
+    /// hasn't been confirmed yet, returning a report of the settlement.
+    ///
+    /// Every transaction is simulated before anything is broadcast, so a
+    /// failure can't leave the settlement half-landed. The rest of the
+    /// journal spends what its prerequisites release, so if the settlement
+    /// sink can't simulate them in sequence, the prerequisites have to land
+    /// before the rest can be simulated, and a failure then leaves the
+    /// settlement incomplete.
+    pub(crate) fn settle(
+        &self,
+        journal: &mut SettlementJournal,
+    ) -> Result<SettlementReport, PayTubeError> {
+        let (indices, transactions): (Vec<_>, Vec<_>) = journal
+            .entries()
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.status != JournalStatus::Confirmed)
+            .map(|(index, entry)| (index, entry.transaction.clone()))
+            .unzip();
+        match self
+            .settlement_sink
+            .simulate_transactions_in_sequence(&transactions)?
+        {
+            Some(outcomes) => {
+                check_simulation(indices.into_iter().zip(&transactions).zip(outcomes))?;
+                self.broadcast(journal, 0..journal.entries().len())?;
+            }
+            None => {
+                let prerequisites = journal.prerequisites();
+                let split = indices.partition_point(|index| *index < prerequisites);
+                self.simulate(indices[..split].iter().copied().zip(&transactions[..split]))?;
+                self.broadcast(journal, 0..prerequisites)?;
+                match self.simulate(indices[split..].iter().copied().zip(&transactions[split..])) {
+                    Err(PayTubeError::SimulationFailed(report)) if prerequisites > 0 => {
+                        return Err(PayTubeError::SettlementIncomplete(report))
+                    }
+                    result => result?,
+                }
+                self.broadcast(journal, prerequisites..journal.entries().len())?;
+            }
+        }
+        Ok(SettlementReport {
+            unrevoked_delegations: journal.unrevoked_delegations().to_vec(),
+        })
+    }
+
+    /// Submit and confirm every journaled transaction in the provided range
+    /// which hasn't been confirmed yet, updating the journal as it goes.
+    fn broadcast(
+        &self,
+        journal: &mut SettlementJournal,
+        range: Range<usize>,
+    ) -> Result<(), PayTubeError> {
+        for index in range {
+            if journal.entries()[index].status == JournalStatus::Confirmed {
+                continue;
+            }
+            journal.set_status(index, JournalStatus::Submitted)?;
+            let transaction = &journal.entries()[index].transaction;
+            let signature = self.settlement_sink.submit_transaction(transaction)?;
+            self.settlement_sink
+                .confirm_transaction(&signature, &transaction.message.recent_blockhash)?;
+            journal.set_status(index, JournalStatus::Confirmed)?;
+        }
+        Ok(())
+    }
+
+    /// Simulate every settlement transaction, returning a report of all
+    /// failures if any transaction would fail.
+    ///
+    /// Each transaction is simulated independently against the current state
+    /// of the base chain. Settlement transfers never depend on one another,
+    /// since every debtor pays out of their opening balance, or, for escrowed
+    /// debts, out of what closing the escrow released to the payer.
+    fn simulate<'b>(
+        &self,
+        transactions: impl IntoIterator<Item = (usize, &'b SolanaTransaction)>,
+    ) -> Result<(), PayTubeError> {
+        let outcomes = transactions
+            .into_iter()
+            .map(|transaction| {
+                let outcome = self.settlement_sink.simulate_transaction(transaction.1)?;
+                Ok((transaction, outcome))
+            })
+            .collect::<Result<Vec<_>, PayTubeError>>()?;
+        check_simulation(outcomes)
+    }
+}
+
+/// Return a report of every failed simulation, if any of the settlement
+/// transactions would fail.
+fn check_simulation<'a>(
+    outcomes: impl IntoIterator<Item = ((usize, &'a SolanaTransaction), SimulationOutcome)>,
+) -> Result<(), PayTubeError> {
+    let failures = outcomes
+        .into_iter()
+        .filter_map(|((index, transaction), outcome)| {
+            outcome.error.map(|error| SimulationFailure {
+                index,
+                signature: transaction.signatures[0],
+                error,
+                logs: outcome.logs,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(PayTubeError::SimulationFailed(SimulationReport {
+            failures,
+        }))
+    }
+}
+
+/// Pack groups of instructions into unsigned transactions.
+///
+/// Groups are added to each transaction greedily, until the next one would
+/// push its serialized size, including every required signature, over the
+/// packet size limit, or its estimated compute units over the transaction
+/// compute unit limit. A group is never split across transactions.
+fn pack_instructions(
+    instruction_groups: Vec<Vec<SolanaInstruction>>,
+    payer: &Pubkey,
+) -> Result<Vec<SolanaTransaction>, PayTubeError> {
+    let mut transactions = vec![];
+    let mut packed = vec![];
+    let mut compute_units = 0;
+    for group in instruction_groups {
+        let group_compute_units = group.iter().map(estimate_compute_units).sum::<u64>();
+        let packed_len = packed.len();
+        packed.extend(group);
+
+        // A single group always gets a transaction of its own, even if it
+        // doesn't fit.
+        if packed_len > 0
+            && (compute_units + group_compute_units > u64::from(MAX_COMPUTE_UNIT_LIMIT)
+                || serialized_size(&packed, payer)? > PACKET_DATA_SIZE as u64)
+        {
+            let group = packed.split_off(packed_len);
+            transactions.push(SolanaTransaction::new_unsigned(Message::new(
+                &packed,
+                Some(payer),
+            )));
+            packed = group;
+            compute_units = 0;
+        }
+        compute_units += group_compute_units;
+    }
+    if !packed.is_empty() {
+        transactions.push(SolanaTransaction::new_unsigned(Message::new(
+            &packed,
+            Some(payer),
+        )));
+    }
+
+    Ok(transactions)
+}
+
+/// Estimate the compute units consumed by a settlement instruction.
+///
+/// Unknown instructions are assumed to use the default per-instruction limit.
+fn estimate_compute_units(instruction: &SolanaInstruction) -> u64 {
+    if instruction.program_id == system_program::id() {
+        SYSTEM_TRANSFER_COMPUTE_UNITS
+    } else if instruction.program_id == spl_token::id()
+        || instruction.program_id == spl_token_2022::id()
+    {
+        TOKEN_TRANSFER_COMPUTE_UNITS
+    } else if instruction.program_id == spl_associated_token_account::id() {
+        CREATE_TOKEN_ACCOUNT_COMPUTE_UNITS
+    } else {
+        u64::from(DEFAULT_INSTRUCTION_COMPUTE_UNIT_LIMIT)
+    }
+}
+
+/// The serialized size of a transaction containing the provided instructions,
+/// once it has been signed.
+fn serialized_size(
+    instructions: &[SolanaInstruction],
+    payer: &Pubkey,
+) -> Result<u64, PayTubeError> {
+    let transaction = SolanaTransaction::new_unsigned(Message::new(instructions, Some(payer)));
+    Ok(bincode::serialized_size(&transaction)?)
+}
+
+/// Sign a settlement transaction with a recent blockhash.
+///
+/// Netting means not every participant takes part in every transaction, so
+/// only the keys it actually requires are asked for a signature. Every
+/// signature is checked before it's accepted.
+///
+/// With `allow_missing_signers`, a key the signer provider can't sign for is
+/// left with the default signature, for its owner to co-sign later.
+fn sign_transaction(
+    mut transaction: SolanaTransaction,
+    signer_provider: &dyn SignerProvider,
+    recent_blockhash: Hash,
+    allow_missing_signers: bool,
+) -> Result<SolanaTransaction, PayTubeError> {
+    transaction.message.recent_blockhash = recent_blockhash;
+    let message_data = transaction.message_data();
+    transaction.signatures = transaction
+        .message
+        .signer_keys()
+        .into_iter()
+        .map(|pubkey| {
+            let signature = match signer_provider.sign_message(pubkey, &message_data) {
+                Err(PayTubeError::MissingSigner(_)) if allow_missing_signers => {
+                    return Ok(Signature::default());
+                }
+                result => result?,
+            };
+            if !signature.verify(pubkey.as_ref(), &message_data) {
+                return Err(PayTubeError::Settlement(format!(
+                    "invalid signature from {pubkey}"
+                )));
+            }
+            Ok(signature)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(transaction)
 }
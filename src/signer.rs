@@ -0,0 +1,224 @@
+//! PayTube's signer providers, which sign settlement transactions on behalf of
+//! channel participants.
+//!
+//! The channel never needs to hold participants' private keys itself. When
+//! it's time to settle, the settler asks its signer provider for a signature
+//! from each key a settlement transaction requires. Keys can be held in
+//! memory, in a keystore on disk, by a separate signer process, or by the
+//! participants themselves, who are prompted for their signatures.
+
+use {
+    crate::error::PayTubeError,
+    base64::{engine::general_purpose::STANDARD as BASE64_STANDARD, Engine},
+    solana_sdk::{
+        pubkey::Pubkey,
+        signature::{read_keypair_file, write_keypair_file, Keypair, Signature},
+        signer::Signer,
+    },
+    std::{
+        io::{self, BufRead, ErrorKind, Read, StdinLock, Stdout, Write},
+        net::{TcpStream, ToSocketAddrs},
+        path::{Path, PathBuf},
+        str::FromStr,
+        sync::Mutex,
+    },
+};
+
+/// A source of signatures for settlement transactions.
+pub trait SignerProvider {
+    /// Sign a message with the key for the provided pubkey.
+    ///
+    /// Returns `PayTubeError::MissingSigner` if the provider has no way to
+    /// sign for the pubkey.
+    fn sign_message(&self, pubkey: &Pubkey, message: &[u8]) -> Result<Signature, PayTubeError>;
+}
+
+/// A signer provider backed by signers held in memory.
+pub struct LocalSigners {
+    signers: Vec<Box<dyn Signer>>,
+}
+
+impl LocalSigners {
+    pub fn new(signers: Vec<Box<dyn Signer>>) -> Self {
+        Self { signers }
+    }
+}
+
+impl<S: Signer + 'static> From<Vec<S>> for LocalSigners {
+    fn from(signers: Vec<S>) -> Self {
+        Self::new(
+            signers
+                .into_iter()
+                .map(|signer| Box::new(signer) as Box<dyn Signer>)
+                .collect(),
+        )
+    }
+}
+
+impl SignerProvider for LocalSigners {
+    fn sign_message(&self, pubkey: &Pubkey, message: &[u8]) -> Result<Signature, PayTubeError> {
+        let signer = self
+            .signers
+            .iter()
+            .find(|signer| signer.try_pubkey().ok().as_ref() == Some(pubkey))
+            .ok_or(PayTubeError::MissingSigner(*pubkey))?;
+        signer
+            .try_sign_message(message)
+            .map_err(|err| PayTubeError::Settlement(format!("failed to sign for {pubkey}: {err}")))
+    }
+}
+
+/// A signer provider backed by a directory of keypair files.
+///
+/// Each keypair is stored as `<pubkey>.json`, in the same format as the
+/// Solana CLI, and is only read from disk when a signature is needed.
+pub struct FileKeystore {
+    path: PathBuf,
+}
+
+impl FileKeystore {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Write a keypair to the keystore.
+    pub fn insert(&self, keypair: &Keypair) -> Result<(), PayTubeError> {
+        write_keypair_file(keypair, self.keypair_path(&keypair.pubkey())).map_err(|err| {
+            PayTubeError::Settlement(format!(
+                "failed to write keypair for {}: {err}",
+                keypair.pubkey()
+            ))
+        })?;
+        Ok(())
+    }
+
+    fn keypair_path(&self, pubkey: &Pubkey) -> PathBuf {
+        self.path.join(format!("{pubkey}.json"))
+    }
+}
+
+impl SignerProvider for FileKeystore {
+    fn sign_message(&self, pubkey: &Pubkey, message: &[u8]) -> Result<Signature, PayTubeError> {
+        let path = self.keypair_path(pubkey);
+        if !path.exists() {
+            return Err(PayTubeError::MissingSigner(*pubkey));
+        }
+        let keypair = read_keypair_file(&path).map_err(|err| {
+            PayTubeError::Settlement(format!("failed to read keypair for {pubkey}: {err}"))
+        })?;
+        if keypair.pubkey() != *pubkey {
+            return Err(PayTubeError::Settlement(format!(
+                "keypair file for {pubkey} holds the key for {}",
+                keypair.pubkey()
+            )));
+        }
+        Ok(keypair.sign_message(message))
+    }
+}
+
+/// A signer provider which forwards every request to a remote signer process
+/// over a stream.
+///
+/// Each request is a bincode-encoded `(Pubkey, Vec<u8>)` of the signer's
+/// pubkey and the message to sign. Each response is a bincode-encoded
+/// `Option<Signature>`, which is `None` if the remote signer won't sign for
+/// the pubkey. `serve_remote_signer` is a local stand-in for the remote end.
+pub struct RemoteSigner<S> {
+    stream: Mutex<S>,
+}
+
+impl<S: Read + Write> RemoteSigner<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream: Mutex::new(stream),
+        }
+    }
+}
+
+impl RemoteSigner<TcpStream> {
+    /// Connect to a remote signer process listening on a TCP socket.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self, PayTubeError> {
+        Ok(Self::new(TcpStream::connect(addr)?))
+    }
+}
+
+impl<S: Read + Write> SignerProvider for RemoteSigner<S> {
+    fn sign_message(&self, pubkey: &Pubkey, message: &[u8]) -> Result<Signature, PayTubeError> {
+        let mut stream = self.stream.lock().unwrap();
+        stream.write_all(&bincode::serialize(&(pubkey, message))?)?;
+        stream.flush()?;
+        let signature: Option<Signature> = bincode::deserialize_from(&mut *stream)?;
+        signature.ok_or(PayTubeError::MissingSigner(*pubkey))
+    }
+}
+
+/// Serve signing requests from a `RemoteSigner` over a stream, until the
+/// stream is closed, signing with the provided signer provider.
+pub fn serve_remote_signer(
+    mut stream: impl Read + Write,
+    signer_provider: &dyn SignerProvider,
+) -> Result<(), PayTubeError> {
+    loop {
+        let (pubkey, message): (Pubkey, Vec<u8>) = match bincode::deserialize_from(&mut stream) {
+            Ok(request) => request,
+            Err(err) => match *err {
+                bincode::ErrorKind::Io(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                    return Ok(())
+                }
+                err => return Err(Box::new(err).into()),
+            },
+        };
+        let signature = signer_provider.sign_message(&pubkey, &message).ok();
+        stream.write_all(&bincode::serialize(&signature)?)?;
+        stream.flush()?;
+    }
+}
+
+/// A signer provider which collects signatures from participants as they're
+/// needed, by prompting for them.
+///
+/// For each signature, the signer's pubkey and the base64-encoded message are
+/// written to the output, and a base58-encoded signature is read back from the
+/// input. An empty line means the participant declined to sign.
+pub struct InteractiveSigner<R, W> {
+    io: Mutex<(R, W)>,
+}
+
+impl<R: BufRead, W: Write> InteractiveSigner<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Self {
+            io: Mutex::new((input, output)),
+        }
+    }
+}
+
+impl InteractiveSigner<StdinLock<'static>, Stdout> {
+    /// Prompt for signatures on the process's standard input and output.
+    pub fn stdio() -> Self {
+        Self::new(io::stdin().lock(), io::stdout())
+    }
+}
+
+impl<R: BufRead, W: Write> SignerProvider for InteractiveSigner<R, W> {
+    fn sign_message(&self, pubkey: &Pubkey, message: &[u8]) -> Result<Signature, PayTubeError> {
+        let mut io = self.io.lock().unwrap();
+        let (input, output) = &mut *io;
+
+        writeln!(output, "Signature required from {pubkey} for message:")?;
+        writeln!(output, "{}", BASE64_STANDARD.encode(message))?;
+        write!(output, "Signature: ")?;
+        output.flush()?;
+
+        let mut line = String::new();
+        input.read_line(&mut line)?;
+        let line = line.trim();
+        if line.is_empty() {
+            return Err(PayTubeError::MissingSigner(*pubkey));
+        }
+        Signature::from_str(line).map_err(|err| {
+            PayTubeError::Settlement(format!("invalid signature from {pubkey}: {err}"))
+        })
+    }
+}
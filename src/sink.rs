@@ -0,0 +1,268 @@
+//! PayTube's settlement sinks, which the settler posts the final ledger to.
+//!
+//! The settler only needs a few things from the base chain: a recent
+//! blockhash to sign settlement transactions with, a way to simulate them,
+//! somewhere to submit them, and a way to confirm they landed. Abstracting
+//! these behind a trait allows settlement to target a live cluster over RPC,
+//! an in-process stand-in for the base chain, or a file on disk for operators
+//! to review and co-sign before anything is broadcast.
+
+use {
+    crate::error::PayTubeError,
+    base64::{engine::general_purpose::STANDARD as BASE64_STANDARD, Engine},
+    solana_client::rpc_client::RpcClient,
+    solana_sdk::{
+        hash::Hash,
+        signature::Signature,
+        transaction::{Transaction as SolanaTransaction, TransactionError},
+    },
+    std::{
+        fs::{File, OpenOptions},
+        io::Write,
+        path::{Path, PathBuf},
+        sync::Arc,
+        thread,
+        time::Duration,
+    },
+};
+
+/// How often `confirm_transaction` polls for the status of a transaction by
+/// default.
+const CONFIRMATION_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The outcome of simulating a settlement transaction.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SimulationOutcome {
+    /// The error the transaction would fail with, if any.
+    pub error: Option<TransactionError>,
+    pub logs: Vec<String>,
+}
+
+/// A destination for settlement transactions.
+pub trait SettlementSink {
+    /// Fetch a recent blockhash to sign settlement transactions with.
+    fn get_latest_blockhash(&self) -> Result<Hash, PayTubeError>;
+
+    /// Simulate a signed settlement transaction against the current state of
+    /// the base chain, without submitting it.
+    fn simulate_transaction(
+        &self,
+        transaction: &SolanaTransaction,
+    ) -> Result<SimulationOutcome, PayTubeError>;
+
+    /// Simulate a sequence of signed settlement transactions, each against
+    /// the state the successful ones before it would leave behind, without
+    /// submitting any of them. Returns `None` if the sink can't.
+    ///
+    /// Defaults to `None`, since a live cluster can only simulate each
+    /// transaction against its current state.
+    fn simulate_transactions_in_sequence(
+        &self,
+        _transactions: &[SolanaTransaction],
+    ) -> Result<Option<Vec<SimulationOutcome>>, PayTubeError> {
+        Ok(None)
+    }
+
+    /// Submit a signed settlement transaction, returning its signature.
+    fn submit_transaction(
+        &self,
+        transaction: &SolanaTransaction,
+    ) -> Result<Signature, PayTubeError>;
+
+    /// The result of a settlement transaction which has landed on the base
+    /// chain, or `None` if it hasn't landed.
+    ///
+    /// A transaction which landed but failed can never land again, so it
+    /// must not be mistaken for one which hasn't landed.
+    ///
+    /// Defaults to `None`, for sinks which never broadcast anything
+    /// themselves, so a resumed settlement always submits every transaction
+    /// again.
+    fn get_transaction_status(
+        &self,
+        _signature: &Signature,
+    ) -> Result<Option<Result<(), TransactionError>>, PayTubeError> {
+        Ok(None)
+    }
+
+    /// Whether a blockhash can still be used to land a transaction.
+    ///
+    /// Defaults to `true`, for sinks which never broadcast anything
+    /// themselves, so a resumed settlement keeps every transaction as it was
+    /// signed.
+    fn is_blockhash_valid(&self, _blockhash: &Hash) -> Result<bool, PayTubeError> {
+        Ok(true)
+    }
+
+    /// Wait for a submitted settlement transaction, signed with the provided
+    /// blockhash, to be confirmed, returning an error if it failed.
+    ///
+    /// Defaults to polling `get_transaction_status` until the transaction
+    /// lands, or until its blockhash is no longer valid, since it can then
+    /// never land. A transaction which landed but failed is reported as an
+    /// error rather than as confirmed. Sinks which never broadcast anything
+    /// themselves never see a transaction land, so must override this.
+    fn confirm_transaction(
+        &self,
+        signature: &Signature,
+        recent_blockhash: &Hash,
+    ) -> Result<(), PayTubeError> {
+        loop {
+            // The blockhash is checked first, so a transaction which landed
+            // just before it expired is still found.
+            let expired = !self.is_blockhash_valid(recent_blockhash)?;
+            match self.get_transaction_status(signature)? {
+                Some(Ok(())) => return Ok(()),
+                Some(Err(err)) => {
+                    return Err(PayTubeError::Settlement(format!(
+                        "transaction {signature} failed: {err}"
+                    )))
+                }
+                None if expired => {
+                    return Err(PayTubeError::Settlement(format!(
+                        "transaction {signature} expired before it landed"
+                    )))
+                }
+                None => thread::sleep(CONFIRMATION_POLL_INTERVAL),
+            }
+        }
+    }
+
+    /// Whether settlement transactions may be submitted with signatures still
+    /// missing, for their signers to add before anything is broadcast.
+    ///
+    /// Defaults to `false`, so settlement fails if the signer provider can't
+    /// sign for every required key.
+    fn accepts_partial_signatures(&self) -> bool {
+        false
+    }
+}
+
+/// Settles to a live cluster over RPC.
+pub struct RpcSettlementSink {
+    rpc_client: Arc<RpcClient>,
+}
+
+impl RpcSettlementSink {
+    pub fn new(rpc_client: Arc<RpcClient>) -> Self {
+        Self { rpc_client }
+    }
+}
+
+impl SettlementSink for RpcSettlementSink {
+    fn get_latest_blockhash(&self) -> Result<Hash, PayTubeError> {
+        Ok(self.rpc_client.get_latest_blockhash()?)
+    }
+
+    fn simulate_transaction(
+        &self,
+        transaction: &SolanaTransaction,
+    ) -> Result<SimulationOutcome, PayTubeError> {
+        let result = self.rpc_client.simulate_transaction(transaction)?.value;
+        Ok(SimulationOutcome {
+            error: result.err,
+            logs: result.logs.unwrap_or_default(),
+        })
+    }
+
+    fn submit_transaction(
+        &self,
+        transaction: &SolanaTransaction,
+    ) -> Result<Signature, PayTubeError> {
+        Ok(self.rpc_client.send_transaction(transaction)?)
+    }
+
+    /// Searches the cluster's full transaction history, so a transaction
+    /// which landed long ago is still found.
+    fn get_transaction_status(
+        &self,
+        signature: &Signature,
+    ) -> Result<Option<Result<(), TransactionError>>, PayTubeError> {
+        Ok(self
+            .rpc_client
+            .get_signature_status_with_commitment_and_history(
+                signature,
+                self.rpc_client.commitment(),
+                true,
+            )?)
+    }
+
+    fn is_blockhash_valid(&self, blockhash: &Hash) -> Result<bool, PayTubeError> {
+        Ok(self
+            .rpc_client
+            .is_blockhash_valid(blockhash, self.rpc_client.commitment())?)
+    }
+}
+
+/// A dry-run sink, which writes settlement transactions to a file instead of
+/// broadcasting them.
+///
+/// Each transaction is written on its own line, as base64-encoded wire format,
+/// so operators can review it and add any missing signatures before sending
+/// it themselves. Since nothing is broadcast, the caller provides the
+/// blockhash to sign with, and nothing written to the file is ever considered
+/// landed.
+pub struct FileSettlementSink {
+    path: PathBuf,
+    recent_blockhash: Hash,
+    partial_signing: bool,
+}
+
+impl FileSettlementSink {
+    /// Create a sink writing to the given path, truncating any existing file.
+    pub fn new(path: impl AsRef<Path>, recent_blockhash: Hash) -> Result<Self, PayTubeError> {
+        let path = path.as_ref().to_path_buf();
+        File::create(&path)?;
+        Ok(Self {
+            path,
+            recent_blockhash,
+            partial_signing: false,
+        })
+    }
+
+    /// Write transactions even when the channel can't sign for every key they
+    /// require, leaving each missing signature as the default signature for
+    /// its signer to fill in.
+    pub fn with_partial_signing(mut self) -> Self {
+        self.partial_signing = true;
+        self
+    }
+}
+
+impl SettlementSink for FileSettlementSink {
+    fn get_latest_blockhash(&self) -> Result<Hash, PayTubeError> {
+        Ok(self.recent_blockhash)
+    }
+
+    /// There's no base chain to simulate against, so every transaction is
+    /// assumed to succeed. Operators are expected to review the file.
+    fn simulate_transaction(
+        &self,
+        _transaction: &SolanaTransaction,
+    ) -> Result<SimulationOutcome, PayTubeError> {
+        Ok(SimulationOutcome::default())
+    }
+
+    fn submit_transaction(
+        &self,
+        transaction: &SolanaTransaction,
+    ) -> Result<Signature, PayTubeError> {
+        let encoded = BASE64_STANDARD.encode(bincode::serialize(transaction)?);
+        let mut file = OpenOptions::new().append(true).open(&self.path)?;
+        writeln!(file, "{encoded}")?;
+        Ok(transaction.signatures[0])
+    }
+
+    /// Transactions are considered confirmed once written to disk.
+    fn confirm_transaction(
+        &self,
+        _signature: &Signature,
+        _recent_blockhash: &Hash,
+    ) -> Result<(), PayTubeError> {
+        Ok(())
+    }
+
+    fn accepts_partial_signatures(&self) -> bool {
+        self.partial_signing
+    }
+}
@@ -0,0 +1,115 @@
+//! PayTube's account sources, which provide the account loader with base
+//! chain state.
+//!
+//! The account loader doesn't care where accounts come from, so long as they
+//! reflect the base chain at the time the channel was opened. This makes it
+//! possible to run channels against a live cluster over RPC, against a fixed
+//! set of genesis accounts held in memory, or against state recorded to disk.
+
+use {
+    crate::error::PayTubeError,
+    solana_client::rpc_client::RpcClient,
+    solana_sdk::{
+        account::{Account, AccountSharedData},
+        pubkey::Pubkey,
+    },
+    std::{collections::HashMap, fs::File, io::BufWriter, path::Path, sync::Arc},
+};
+
+/// A source of base chain accounts.
+pub trait AccountSource {
+    /// Fetch an account, returning `None` if it does not exist.
+    ///
+    /// Any failure to reach the source is returned as an error, so it's never
+    /// mistaken for a missing account.
+    fn get_account(&self, pubkey: &Pubkey) -> Result<Option<AccountSharedData>, PayTubeError>;
+}
+
+/// Loads accounts from a live cluster over RPC.
+pub struct RpcAccountSource {
+    rpc_client: Arc<RpcClient>,
+}
+
+impl RpcAccountSource {
+    pub fn new(rpc_client: Arc<RpcClient>) -> Self {
+        Self { rpc_client }
+    }
+}
+
+impl AccountSource for RpcAccountSource {
+    fn get_account(&self, pubkey: &Pubkey) -> Result<Option<AccountSharedData>, PayTubeError> {
+        Ok(self
+            .rpc_client
+            .get_account_with_commitment(pubkey, self.rpc_client.commitment())?
+            .value
+            .map(Into::into))
+    }
+}
+
+/// Serves accounts from a fixed, in-memory set of genesis accounts.
+#[derive(Default)]
+pub struct InMemoryAccountSource {
+    accounts: HashMap<Pubkey, AccountSharedData>,
+}
+
+impl InMemoryAccountSource {
+    pub fn new(accounts: impl IntoIterator<Item = (Pubkey, AccountSharedData)>) -> Self {
+        Self {
+            accounts: accounts.into_iter().collect(),
+        }
+    }
+}
+
+impl AccountSource for InMemoryAccountSource {
+    fn get_account(&self, pubkey: &Pubkey) -> Result<Option<AccountSharedData>, PayTubeError> {
+        Ok(self.accounts.get(pubkey).cloned())
+    }
+}
+
+/// Serves accounts from a snapshot file on disk.
+///
+/// A snapshot is simply a bincode-serialized list of accounts, which can be
+/// recorded from any other account source (e.g. mainnet via RPC) with
+/// `SnapshotAccountSource::record`, then replayed offline.
+pub struct SnapshotAccountSource {
+    accounts: HashMap<Pubkey, AccountSharedData>,
+}
+
+impl SnapshotAccountSource {
+    /// Open a snapshot file, loading all of its accounts into memory.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, PayTubeError> {
+        let file = File::open(path)?;
+        let accounts: Vec<(Pubkey, Account)> = bincode::deserialize_from(file)?;
+        Ok(Self {
+            accounts: accounts
+                .into_iter()
+                .map(|(pubkey, account)| (pubkey, account.into()))
+                .collect(),
+        })
+    }
+
+    /// Record the given accounts from an account source to a snapshot file.
+    ///
+    /// Accounts which don't exist in the source are skipped.
+    pub fn record(
+        path: impl AsRef<Path>,
+        source: &dyn AccountSource,
+        pubkeys: &[Pubkey],
+    ) -> Result<(), PayTubeError> {
+        let mut accounts = vec![];
+        for pubkey in pubkeys {
+            if let Some(account) = source.get_account(pubkey)? {
+                accounts.push((*pubkey, Account::from(account)));
+            }
+        }
+        let file = BufWriter::new(File::create(path)?);
+        bincode::serialize_into(file, &accounts)?;
+        Ok(())
+    }
+}
+
+impl AccountSource for SnapshotAccountSource {
+    fn get_account(&self, pubkey: &Pubkey) -> Result<Option<AccountSharedData>, PayTubeError> {
+        Ok(self.accounts.get(pubkey).cloned())
+    }
+}
@@ -0,0 +1,168 @@
+//! Helpers for working with mints owned by either SPL Token or Token-2022.
+//!
+//! Token-2022 is a superset of SPL Token, so its instruction builders and
+//! state types are used for both programs, with the id of the program which
+//! owns the mint passed through.
+//!
+//! Token-2022 mints with the `TransferFee` extension withhold a fee from every
+//! transfer, so the recipient receives less than the sender sends. The fee is
+//! computed off-chain from the mint's fee config, exactly as Token-2022 does.
+
+use {
+    solana_sdk::{
+        account::{AccountSharedData, ReadableAccount, WritableAccount},
+        clock::Epoch,
+        instruction::Instruction as SolanaInstruction,
+        program_error::ProgramError,
+        pubkey::Pubkey,
+        rent::Rent,
+    },
+    spl_associated_token_account::{
+        get_associated_token_address_with_program_id,
+        instruction::create_associated_token_account_idempotent,
+    },
+    spl_token_2022::{
+        extension::{
+            transfer_fee::{instruction::transfer_checked_with_fee, TransferFeeConfig},
+            BaseStateWithExtensions, BaseStateWithExtensionsMut, ExtensionType,
+            StateWithExtensions, StateWithExtensionsMut,
+        },
+        state::{Account, AccountState, Mint},
+    },
+};
+
+/// The token program which owns a mint, and the details of the mint needed
+/// to transfer its tokens.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct MintInfo {
+    pub token_program_id: Pubkey,
+    pub decimals: u8,
+    /// The mint's transfer fee config, if it has the `TransferFee` extension.
+    pub transfer_fee_config: Option<TransferFeeConfig>,
+}
+
+impl MintInfo {
+    /// Decode a mint account, returning `None` if it isn't a mint owned by
+    /// SPL Token or Token-2022.
+    pub(crate) fn from_account(account: &AccountSharedData) -> Option<Self> {
+        let token_program_id = *account.owner();
+        if token_program_id != spl_token::id() && token_program_id != spl_token_2022::id() {
+            return None;
+        }
+        let mint = StateWithExtensions::<Mint>::unpack(account.data()).ok()?;
+        Some(Self {
+            token_program_id,
+            decimals: mint.base.decimals,
+            transfer_fee_config: mint.get_extension::<TransferFeeConfig>().ok().copied(),
+        })
+    }
+
+    /// The address of an owner's associated token account for the mint.
+    pub(crate) fn token_account_address(&self, owner: &Pubkey, mint: &Pubkey) -> Pubkey {
+        get_associated_token_address_with_program_id(owner, mint, &self.token_program_id)
+    }
+
+    /// Create an owner's associated token account for the mint, funded by
+    /// `payer`, unless it already exists.
+    pub(crate) fn create_token_account(
+        &self,
+        payer: &Pubkey,
+        owner: &Pubkey,
+        mint: &Pubkey,
+    ) -> SolanaInstruction {
+        create_associated_token_account_idempotent(payer, owner, mint, &self.token_program_id)
+    }
+
+    /// The fee withheld from a transfer of `amount` tokens during `epoch`.
+    pub(crate) fn transfer_fee(&self, epoch: Epoch, amount: u64) -> u64 {
+        self.transfer_fee_config
+            .and_then(|config| config.calculate_epoch_fee(epoch, amount))
+            .unwrap_or_default()
+    }
+
+    /// The fee withheld from a transfer during `epoch` which leaves the
+    /// recipient with `amount` tokens.
+    pub(crate) fn inverse_transfer_fee(&self, epoch: Epoch, amount: u64) -> u64 {
+        self.transfer_fee_config
+            .and_then(|config| config.get_epoch_fee(epoch).calculate_inverse_fee(amount))
+            .unwrap_or_default()
+    }
+
+    /// Transfer tokens of the mint from one token account to another.
+    ///
+    /// For mints with a transfer fee, `fee` must be the fee Token-2022 will
+    /// withhold, or the transfer fails.
+    pub(crate) fn transfer(
+        &self,
+        mint: &Pubkey,
+        source: &Pubkey,
+        destination: &Pubkey,
+        authority: &Pubkey,
+        amount: u64,
+        fee: u64,
+    ) -> Result<SolanaInstruction, ProgramError> {
+        if self.transfer_fee_config.is_some() {
+            return transfer_checked_with_fee(
+                &self.token_program_id,
+                source,
+                mint,
+                destination,
+                authority,
+                &[],
+                amount,
+                self.decimals,
+                fee,
+            );
+        }
+        spl_token_2022::instruction::transfer_checked(
+            &self.token_program_id,
+            source,
+            mint,
+            destination,
+            authority,
+            &[],
+            amount,
+            self.decimals,
+        )
+    }
+}
+
+/// An owner's empty associated token account for a mint, as the Associated
+/// Token Account program would create it, funded to be rent-exempt.
+///
+/// Token-2022 accounts carry every extension the mint requires of its
+/// accounts, such as `TransferFeeAmount` for a mint with a transfer fee.
+pub(crate) fn empty_token_account(
+    mint_account: &AccountSharedData,
+    owner: &Pubkey,
+    mint: &Pubkey,
+    rent: &Rent,
+) -> Result<AccountSharedData, ProgramError> {
+    let token_program_id = *mint_account.owner();
+    let mut extension_types = vec![];
+    if token_program_id == spl_token_2022::id() {
+        let mint_state = StateWithExtensions::<Mint>::unpack(mint_account.data())?;
+        extension_types =
+            ExtensionType::get_required_init_account_extensions(&mint_state.get_extension_types()?);
+        if !extension_types.contains(&ExtensionType::ImmutableOwner) {
+            extension_types.push(ExtensionType::ImmutableOwner);
+        }
+    }
+
+    let space = ExtensionType::try_calculate_account_len::<Account>(&extension_types)?;
+    let mut account = AccountSharedData::new(rent.minimum_balance(space), space, &token_program_id);
+    let mut state =
+        StateWithExtensionsMut::<Account>::unpack_uninitialized(account.data_as_mut_slice())?;
+    for extension_type in &extension_types {
+        state.init_account_extension_from_type(*extension_type)?;
+    }
+    state.base = Account {
+        mint: *mint,
+        owner: *owner,
+        state: AccountState::Initialized,
+        ..Account::default()
+    };
+    state.pack_base();
+    state.init_account_type()?;
+    Ok(account)
+}
@@ -5,79 +5,371 @@
 //! different transactions in their protocol, then convert the resulting state
 //! transitions into the necessary transactions for the base chain - in this
 //! case Solana.
+//!
+//! PayTube transactions are submitted to a channel inside a signed envelope.
+//! The sender signs a canonical serialization of the transfer with their
+//! ed25519 key, and the channel verifies the signature before the transfer is
+//! ever handed to the SVM.
+//!
+//! Each transaction also carries a per-sender nonce and an optional expiry,
+//! which channels use to ensure the same signed transfer can't be replayed,
+//! and names the channel it was signed for, so it can't be replayed into
+//! another channel either.
+//!
+//! SPL transfers are made between the sender's and recipient's associated
+//! token accounts for the mint, unless the transaction names other token
+//! accounts explicitly.
+//!
+//! Amounts are always in base units, but a transaction can also carry the
+//! decimals they're denominated in, so a UI amount such as "1.5" is never
+//! interpreted against the wrong mint.
 
 use {
+    crate::{error::PayTubeError, token::MintInfo},
     solana_sdk::{
-        instruction::Instruction as SolanaInstruction,
+        clock::{Epoch, UnixTimestamp},
+        program_error::ProgramError,
         pubkey::Pubkey,
+        signature::Signature,
+        signer::Signer,
         system_instruction,
         transaction::{
             SanitizedTransaction as SolanaSanitizedTransaction, Transaction as SolanaTransaction,
         },
     },
-    spl_associated_token_account::get_associated_token_address,
-    std::collections::HashSet,
+    std::collections::{HashMap, HashSet},
 };
 
+/// Domain separator prepended to the signed message, so a PayTube signature
+/// can never be mistaken for a signature over a Solana transaction.
+const SIGNING_DOMAIN: &[u8] = b"paytube-transaction-v1";
+
+/// Errors that cause a PayTube transaction to be rejected by a channel before
+/// it reaches the SVM.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum PayTubeTransactionError {
+    #[error("transaction signature does not match the sender")]
+    InvalidSignature,
+    #[error("transaction was signed for channel {channel}")]
+    WrongChannel { channel: Pubkey },
+    #[error("nonce {nonce} has already been used by the sender")]
+    DuplicateNonce { nonce: u64 },
+    #[error("nonce {nonce} is stale, the sender's last nonce was {last_nonce}")]
+    StaleNonce { nonce: u64, last_nonce: u64 },
+    #[error("transaction expired at {expires_at}")]
+    Expired { expires_at: UnixTimestamp },
+    #[error("{mint} is not an SPL Token or Token-2022 mint")]
+    InvalidMint { mint: Pubkey },
+    #[error("{token_account} is not a token account of the mint owned by the sender or recipient")]
+    InvalidTokenAccount { token_account: Pubkey },
+    #[error("amount is denominated in {decimals} decimals, but the mint has {expected}")]
+    InvalidDecimals { decimals: u8, expected: u8 },
+    #[error("{ui_amount} is not a valid amount with {decimals} decimals")]
+    InvalidUiAmount { ui_amount: String, decimals: u8 },
+    #[error("accounts could not be loaded from the base chain: {error}")]
+    AccountsUnavailable { error: String },
+}
+
 /// A simple PayTube transaction. Transfers SPL tokens or SOL from one account
 /// to another.
 ///
-/// A `None` value for `mint` represents native SOL.
+/// A `None` value for `mint` represents native SOL. Mints may be owned by
+/// either SPL Token or Token-2022.
+///
+/// The `channel` is the ID of the channel the transaction is submitted to.
+/// Any other channel refuses it.
+///
+/// The `nonce` is a per-sender sequence number, which must strictly increase
+/// with every transaction the sender submits to a channel. If `expires_at` is
+/// set, the channel will refuse the transaction after that Unix timestamp.
+///
+/// SPL transfers default to the sender's and recipient's associated token
+/// accounts. Either can be overridden with `source_token_account` or
+/// `destination_token_account`, which must be a token account of the mint
+/// owned by the sender or recipient respectively. The source may also be an
+/// account the sender is a delegate of, such as one owned by a PDA, as long as
+/// the delegation covers the amount.
+///
+/// If `decimals` is set, the channel refuses the transaction unless it matches
+/// the decimals of the mint, or of SOL. Use `with_ui_amount` to set both
+/// `amount` and `decimals` from a UI amount.
+///
+/// Build transactions with `new` and the `with_*` methods, rather than a
+/// struct literal, so they keep compiling as optional fields are added.
+#[derive(Clone, Debug)]
 pub struct PayTubeTransaction {
+    pub channel: Pubkey,
     pub mint: Option<Pubkey>,
     pub from: Pubkey,
     pub to: Pubkey,
     pub amount: u64,
+    pub nonce: u64,
+    pub expires_at: Option<UnixTimestamp>,
+    pub source_token_account: Option<Pubkey>,
+    pub destination_token_account: Option<Pubkey>,
+    pub decimals: Option<u8>,
 }
 
-impl From<&PayTubeTransaction> for SolanaInstruction {
-    fn from(value: &PayTubeTransaction) -> Self {
-        let PayTubeTransaction {
+impl PayTubeTransaction {
+    /// A transfer of `amount` SOL, or of a mint's tokens if `mint` is
+    /// provided, within `channel`. The transfer has a nonce of `0`, no expiry
+    /// or decimals, and is between the sender's and recipient's associated
+    /// token accounts.
+    pub fn new(
+        channel: Pubkey,
+        from: Pubkey,
+        to: Pubkey,
+        amount: u64,
+        mint: Option<Pubkey>,
+    ) -> Self {
+        Self {
+            channel,
             mint,
             from,
             to,
             amount,
-        } = value;
-        if let Some(mint) = mint {
-            let source_pubkey = get_associated_token_address(from, mint);
-            let destination_pubkey = get_associated_token_address(to, mint);
-            return spl_token::instruction::transfer(
-                &spl_token::id(),
-                &source_pubkey,
-                &destination_pubkey,
-                from,
-                &[],
-                *amount,
-            )
-            .unwrap();
+            nonce: 0,
+            expires_at: None,
+            source_token_account: None,
+            destination_token_account: None,
+            decimals: None,
+        }
+    }
+
+    /// Set the transaction's nonce.
+    pub fn with_nonce(mut self, nonce: u64) -> Self {
+        self.nonce = nonce;
+        self
+    }
+
+    /// Set the Unix timestamp after which the transaction expires.
+    pub fn with_expiry(mut self, expires_at: UnixTimestamp) -> Self {
+        self.expires_at = Some(expires_at);
+        self
+    }
+
+    /// Transfer from `token_account`, rather than the sender's associated
+    /// token account.
+    pub fn with_source_token_account(mut self, token_account: Pubkey) -> Self {
+        self.source_token_account = Some(token_account);
+        self
+    }
+
+    /// Transfer into `token_account`, rather than the recipient's associated
+    /// token account.
+    pub fn with_destination_token_account(mut self, token_account: Pubkey) -> Self {
+        self.destination_token_account = Some(token_account);
+        self
+    }
+
+    /// Set the decimals the transaction's amount is denominated in.
+    pub fn with_decimals(mut self, decimals: u8) -> Self {
+        self.decimals = Some(decimals);
+        self
+    }
+
+    /// The canonical serialization of the transaction, which is what the
+    /// sender signs.
+    ///
+    /// Layout: domain separator, channel, mint (a `0` byte for native SOL, or
+    /// a `1` byte followed by the mint address), `from`, `to`, the
+    /// little-endian `amount` and `nonce`, the expiry (a `0` byte for none, or
+    /// a `1` byte followed by the little-endian timestamp), the source and
+    /// destination token accounts (each a `0` byte for none, or a `1` byte
+    /// followed by the address), then the decimals (a `0` byte for none, or a
+    /// `1` byte followed by the decimals).
+    pub fn message_data(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(SIGNING_DOMAIN.len() + 6 + 32 * 6 + 8 * 3);
+        data.extend_from_slice(SIGNING_DOMAIN);
+        data.extend_from_slice(self.channel.as_ref());
+        match &self.mint {
+            Some(mint) => {
+                data.push(1);
+                data.extend_from_slice(mint.as_ref());
+            }
+            None => data.push(0),
+        }
+        data.extend_from_slice(self.from.as_ref());
+        data.extend_from_slice(self.to.as_ref());
+        data.extend_from_slice(&self.amount.to_le_bytes());
+        data.extend_from_slice(&self.nonce.to_le_bytes());
+        match &self.expires_at {
+            Some(expires_at) => {
+                data.push(1);
+                data.extend_from_slice(&expires_at.to_le_bytes());
+            }
+            None => data.push(0),
+        }
+        for token_account in [&self.source_token_account, &self.destination_token_account] {
+            match token_account {
+                Some(token_account) => {
+                    data.push(1);
+                    data.extend_from_slice(token_account.as_ref());
+                }
+                None => data.push(0),
+            }
+        }
+        match self.decimals {
+            Some(decimals) => {
+                data.push(1);
+                data.push(decimals);
+            }
+            None => data.push(0),
+        }
+        data
+    }
+
+    /// Set the transaction's amount from a UI amount, such as "1.5",
+    /// denominated in `decimals`.
+    pub fn with_ui_amount(
+        mut self,
+        ui_amount: &str,
+        decimals: u8,
+    ) -> Result<Self, PayTubeTransactionError> {
+        self.amount = spl_token_2022::try_ui_amount_into_amount(ui_amount.to_string(), decimals)
+            .map_err(|_| PayTubeTransactionError::InvalidUiAmount {
+                ui_amount: ui_amount.to_string(),
+                decimals,
+            })?;
+        self.decimals = Some(decimals);
+        Ok(self)
+    }
+
+    /// The transaction's amount as a UI amount, if it carries its decimals.
+    pub fn ui_amount(&self) -> Option<String> {
+        self.decimals.map(|decimals| {
+            spl_token_2022::amount_to_ui_amount_string_trimmed(self.amount, decimals)
+        })
+    }
+
+    /// The token accounts an SPL transfer of `mint` is made from and to.
+    pub(crate) fn token_accounts(&self, mint: &Pubkey, mint_info: &MintInfo) -> (Pubkey, Pubkey) {
+        (
+            self.source_token_account
+                .unwrap_or_else(|| mint_info.token_account_address(&self.from, mint)),
+            self.destination_token_account
+                .unwrap_or_else(|| mint_info.token_account_address(&self.to, mint)),
+        )
+    }
+
+    /// Sign the transaction, producing an envelope that can be submitted to a
+    /// PayTube channel. The signer must be the sender (`from`).
+    pub fn sign(self, signer: &dyn Signer) -> SignedPayTubeTransaction {
+        let signature = signer.sign_message(&self.message_data());
+        SignedPayTubeTransaction {
+            transaction: self,
+            signature,
         }
-        system_instruction::transfer(from, to, *amount)
     }
 }
 
-impl From<&PayTubeTransaction> for SolanaTransaction {
-    fn from(value: &PayTubeTransaction) -> Self {
-        SolanaTransaction::new_with_payer(&[SolanaInstruction::from(value)], Some(&value.from))
+/// A PayTube transaction, signed by its sender.
+#[derive(Clone, Debug)]
+pub struct SignedPayTubeTransaction {
+    pub transaction: PayTubeTransaction,
+    pub signature: Signature,
+}
+
+impl SignedPayTubeTransaction {
+    /// Verify the envelope's signature against the transaction's sender.
+    pub fn verify(&self) -> Result<(), PayTubeTransactionError> {
+        if self.signature.verify(
+            self.transaction.from.as_ref(),
+            &self.transaction.message_data(),
+        ) {
+            Ok(())
+        } else {
+            Err(PayTubeTransactionError::InvalidSignature)
+        }
     }
 }
 
-impl From<&PayTubeTransaction> for SolanaSanitizedTransaction {
-    fn from(value: &PayTubeTransaction) -> Self {
-        SolanaSanitizedTransaction::try_from_legacy_transaction(
-            SolanaTransaction::from(value),
-            &HashSet::new(),
-        )
-        .unwrap()
+/// Tracks the highest nonce seen from each sender, to protect a channel from
+/// replayed transactions.
+#[derive(Clone, Default)]
+pub(crate) struct ReplayGuard {
+    nonces: HashMap<Pubkey, u64>,
+}
+
+impl ReplayGuard {
+    /// Check a transaction's nonce and expiry, without consuming the nonce.
+    pub(crate) fn check(
+        &self,
+        transaction: &PayTubeTransaction,
+        now: UnixTimestamp,
+    ) -> Result<(), PayTubeTransactionError> {
+        if let Some(expires_at) = transaction.expires_at {
+            if now > expires_at {
+                return Err(PayTubeTransactionError::Expired { expires_at });
+            }
+        }
+
+        let nonce = transaction.nonce;
+        match self.nonces.get(&transaction.from) {
+            Some(&last_nonce) if nonce == last_nonce => {
+                Err(PayTubeTransactionError::DuplicateNonce { nonce })
+            }
+            Some(&last_nonce) if nonce < last_nonce => {
+                Err(PayTubeTransactionError::StaleNonce { nonce, last_nonce })
+            }
+            _ => Ok(()),
+        }
     }
+
+    /// Consume a checked transaction's nonce, once the transaction has been
+    /// accepted.
+    pub(crate) fn consume(&mut self, transaction: &PayTubeTransaction) {
+        self.nonces.insert(transaction.from, transaction.nonce);
+    }
+}
+
+/// Convert a PayTube transaction into the equivalent Solana transaction.
+///
+/// SPL transfers are made with the token program which owns the mint, found
+/// in the provided mints, between the transaction's token accounts, and carry
+/// the transfer fee for `epoch`, if any.
+fn create_solana_transaction(
+    transaction: &PayTubeTransaction,
+    mints: &HashMap<Pubkey, MintInfo>,
+    epoch: Epoch,
+) -> Result<SolanaTransaction, PayTubeError> {
+    let PayTubeTransaction {
+        mint,
+        from,
+        to,
+        amount,
+        ..
+    } = transaction;
+    let instruction = match mint {
+        Some(mint) => {
+            let mint_info = mints.get(mint).ok_or(ProgramError::InvalidAccountData)?;
+            let (source, destination) = transaction.token_accounts(mint, mint_info);
+            let fee = mint_info.transfer_fee(epoch, *amount);
+            mint_info.transfer(mint, &source, &destination, from, *amount, fee)?
+        }
+        None => system_instruction::transfer(from, to, *amount),
+    };
+    Ok(SolanaTransaction::new_with_payer(
+        &[instruction],
+        Some(from),
+    ))
 }
 
 /// Create a batch of Solana transactions, for the Solana SVM's transaction
 /// processor, from a batch of PayTube instructions.
-pub fn create_svm_transactions(
+pub(crate) fn create_svm_transactions(
     paytube_transactions: &[PayTubeTransaction],
-) -> Vec<SolanaSanitizedTransaction> {
+    mints: &HashMap<Pubkey, MintInfo>,
+    epoch: Epoch,
+) -> Result<Vec<SolanaSanitizedTransaction>, PayTubeError> {
     paytube_transactions
         .iter()
-        .map(SolanaSanitizedTransaction::from)
+        .map(|transaction| {
+            SolanaSanitizedTransaction::try_from_legacy_transaction(
+                create_solana_transaction(transaction, mints, epoch)?,
+                &HashSet::new(),
+            )
+            .map_err(PayTubeError::TransactionConversion)
+        })
         .collect()
 }
@@ -0,0 +1,35 @@
+mod setup;
+
+use {
+    paytube_svm::source::{AccountSource, InMemoryAccountSource, SnapshotAccountSource},
+    setup::{mint_account, system_account},
+    solana_sdk::pubkey::{new_rand, Pubkey},
+};
+
+#[test]
+fn test_snapshot_account_source() {
+    let alice_pubkey = Pubkey::new_unique();
+    let mint = Pubkey::new_unique();
+    let missing_pubkey = Pubkey::new_unique();
+
+    let genesis = InMemoryAccountSource::new([
+        (alice_pubkey, system_account(10_000_000)),
+        (mint, mint_account()),
+    ]);
+
+    let path = std::env::temp_dir().join(format!("paytube-snapshot-{}.bin", new_rand()));
+    SnapshotAccountSource::record(&path, &genesis, &[alice_pubkey, mint, missing_pubkey]).unwrap();
+
+    let snapshot = SnapshotAccountSource::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(
+        snapshot.get_account(&alice_pubkey).unwrap(),
+        genesis.get_account(&alice_pubkey).unwrap()
+    );
+    assert_eq!(
+        snapshot.get_account(&mint).unwrap(),
+        genesis.get_account(&mint).unwrap()
+    );
+    assert_eq!(snapshot.get_account(&missing_pubkey).unwrap(), None);
+}
@@ -0,0 +1,331 @@
+mod setup;
+
+use {
+    paytube_svm::{error::PayTubeError, transaction::PayTubeTransaction},
+    setup::{
+        get_token_amount, in_memory_base_chain, in_memory_channel, mint_account, system_account,
+        token_account,
+    },
+    solana_sdk::{
+        account::ReadableAccount, program_option::COption, program_pack::Pack, pubkey::Pubkey,
+        signature::Keypair, signer::Signer,
+    },
+    spl_associated_token_account::get_associated_token_address,
+    spl_token::state::Account as TokenAccount,
+};
+
+#[test]
+fn test_delegated_settlement() {
+    let mint = Pubkey::new_unique();
+
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+
+    let alice_pubkey = alice.pubkey();
+    let alice_token_account_pubkey = get_associated_token_address(&alice_pubkey, &mint);
+
+    let bob_pubkey = bob.pubkey();
+    let bob_token_account_pubkey = get_associated_token_address(&bob_pubkey, &mint);
+
+    let will_pubkey = Pubkey::new_unique();
+    let will_token_account_pubkey = get_associated_token_address(&will_pubkey, &mint);
+
+    // Alice delegates her token account to the payer, which is the channel's
+    // settlement authority. Bob doesn't, and Will only delegates part of his
+    // balance.
+    let base_chain = in_memory_base_chain([
+        (mint, mint_account()),
+        (payer_pubkey, system_account(10_000_000)),
+        (alice_pubkey, system_account(10_000_000)),
+        (
+            alice_token_account_pubkey,
+            token_account(&alice_pubkey, &mint, 10, Some((&payer_pubkey, 10))),
+        ),
+        (bob_pubkey, system_account(10_000_000)),
+        (
+            bob_token_account_pubkey,
+            token_account(&bob_pubkey, &mint, 10, None),
+        ),
+        (
+            will_token_account_pubkey,
+            token_account(&will_pubkey, &mint, 10, Some((&payer_pubkey, 5))),
+        ),
+    ]);
+
+    // Alice's key is only needed to revoke her delegation after close.
+    let mut paytube_channel = in_memory_channel(payer, &[&alice], &base_chain);
+    let channel_id = paytube_channel.id();
+
+    paytube_channel
+        .register_token_account(&alice_pubkey, &mint)
+        .unwrap();
+    assert!(matches!(
+        paytube_channel.register_token_account(&bob_pubkey, &mint),
+        Err(PayTubeError::Registration(_))
+    ));
+    assert!(matches!(
+        paytube_channel.register_token_account(&will_pubkey, &mint),
+        Err(PayTubeError::Registration(_))
+    ));
+
+    paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 7
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 7, Some(mint))
+                .sign(&alice),
+        ])
+        .unwrap();
+
+    // The settlement transfer is signed by the settlement authority alone.
+    let preview = paytube_channel.preview_settlement().unwrap();
+    assert_eq!(preview.transactions.len(), 1);
+    assert_eq!(
+        preview.transactions[0].message.signer_keys(),
+        vec![&payer_pubkey]
+    );
+
+    let report = paytube_channel.close().unwrap();
+    assert!(report.unrevoked_delegations.is_empty());
+
+    let alice_token_account = base_chain.get_account(&alice_token_account_pubkey).unwrap();
+    assert_eq!(get_token_amount(&alice_token_account), 3);
+    assert_eq!(
+        get_token_amount(&base_chain.get_account(&bob_token_account_pubkey).unwrap()),
+        17
+    );
+
+    // Alice's delegation was revoked.
+    let alice_token_account = TokenAccount::unpack(alice_token_account.data()).unwrap();
+    assert_eq!(alice_token_account.delegate, COption::None);
+    assert_eq!(alice_token_account.delegated_amount, 0);
+}
+
+#[test]
+fn test_delegated_settlement_without_owner_key() {
+    let mint = Pubkey::new_unique();
+
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+
+    let alice_pubkey = alice.pubkey();
+    let alice_token_account_pubkey = get_associated_token_address(&alice_pubkey, &mint);
+
+    let bob_pubkey = Pubkey::new_unique();
+    let bob_token_account_pubkey = get_associated_token_address(&bob_pubkey, &mint);
+
+    let base_chain = in_memory_base_chain([
+        (mint, mint_account()),
+        (payer_pubkey, system_account(10_000_000)),
+        (alice_pubkey, system_account(10_000_000)),
+        (
+            alice_token_account_pubkey,
+            token_account(&alice_pubkey, &mint, 10, Some((&payer_pubkey, 10))),
+        ),
+        (
+            bob_token_account_pubkey,
+            token_account(&bob_pubkey, &mint, 10, None),
+        ),
+    ]);
+
+    // The channel only holds the payer's key, so it can settle Alice's
+    // delegated account, but not revoke her delegation.
+    let mut paytube_channel = in_memory_channel(payer, &[], &base_chain);
+    let channel_id = paytube_channel.id();
+
+    paytube_channel
+        .register_token_account(&alice_pubkey, &mint)
+        .unwrap();
+
+    paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 7
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 7, Some(mint))
+                .sign(&alice),
+        ])
+        .unwrap();
+
+    // Settlement still lands, and Alice's delegation is reported for her to
+    // revoke herself.
+    let report = paytube_channel.close().unwrap();
+    assert_eq!(report.unrevoked_delegations.len(), 1);
+    assert_eq!(
+        report.unrevoked_delegations[0].token_account,
+        alice_token_account_pubkey
+    );
+    assert_eq!(report.unrevoked_delegations[0].owner, alice_pubkey);
+
+    let alice_token_account = base_chain.get_account(&alice_token_account_pubkey).unwrap();
+    assert_eq!(get_token_amount(&alice_token_account), 3);
+    assert_eq!(
+        get_token_amount(&base_chain.get_account(&bob_token_account_pubkey).unwrap()),
+        17
+    );
+
+    let alice_token_account = TokenAccount::unpack(alice_token_account.data()).unwrap();
+    assert_eq!(alice_token_account.delegate, COption::Some(payer_pubkey));
+}
+
+#[test]
+fn test_delegated_settlement_from_explicit_token_account() {
+    let mint = Pubkey::new_unique();
+    let other_mint = Pubkey::new_unique();
+
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+
+    // Alice transacts from an auxiliary token account, rather than her
+    // associated token account, and delegates it to the payer.
+    let alice_pubkey = alice.pubkey();
+    let alice_token_account_pubkey = Pubkey::new_unique();
+    let alice_other_token_account_pubkey = Pubkey::new_unique();
+
+    let bob_pubkey = bob.pubkey();
+    let bob_token_account_pubkey = get_associated_token_address(&bob_pubkey, &mint);
+
+    let base_chain = in_memory_base_chain([
+        (mint, mint_account()),
+        (other_mint, mint_account()),
+        (payer_pubkey, system_account(10_000_000)),
+        (alice_pubkey, system_account(10_000_000)),
+        (
+            alice_token_account_pubkey,
+            token_account(&alice_pubkey, &mint, 10, Some((&payer_pubkey, 10))),
+        ),
+        (
+            alice_other_token_account_pubkey,
+            token_account(&alice_pubkey, &other_mint, 10, Some((&payer_pubkey, 10))),
+        ),
+        (bob_pubkey, system_account(10_000_000)),
+        (
+            bob_token_account_pubkey,
+            token_account(&bob_pubkey, &mint, 10, None),
+        ),
+    ]);
+
+    let mut paytube_channel = in_memory_channel(payer, &[&alice], &base_chain);
+    let channel_id = paytube_channel.id();
+
+    // The account must hold the mint, and belong to the participant.
+    assert!(matches!(
+        paytube_channel.register_explicit_token_account(
+            &alice_other_token_account_pubkey,
+            &alice_pubkey,
+            &mint
+        ),
+        Err(PayTubeError::Registration(_))
+    ));
+    assert!(matches!(
+        paytube_channel.register_explicit_token_account(
+            &alice_token_account_pubkey,
+            &bob_pubkey,
+            &mint
+        ),
+        Err(PayTubeError::Registration(_))
+    ));
+    paytube_channel
+        .register_explicit_token_account(&alice_token_account_pubkey, &alice_pubkey, &mint)
+        .unwrap();
+
+    paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 4
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 4, Some(mint))
+                .with_source_token_account(alice_token_account_pubkey)
+                .sign(&alice),
+        ])
+        .unwrap();
+
+    // The settlement transfer is signed by the settlement authority alone.
+    let preview = paytube_channel.preview_settlement().unwrap();
+    assert_eq!(preview.transactions.len(), 1);
+    assert_eq!(
+        preview.transactions[0].message.signer_keys(),
+        vec![&payer_pubkey]
+    );
+
+    paytube_channel.close().unwrap();
+
+    let alice_token_account = base_chain.get_account(&alice_token_account_pubkey).unwrap();
+    assert_eq!(get_token_amount(&alice_token_account), 6);
+    assert_eq!(
+        get_token_amount(&base_chain.get_account(&bob_token_account_pubkey).unwrap()),
+        14
+    );
+
+    // Alice's delegation was revoked.
+    let alice_token_account = TokenAccount::unpack(alice_token_account.data()).unwrap();
+    assert_eq!(alice_token_account.delegate, COption::None);
+}
+
+#[test]
+fn test_delegated_settlement_from_pda_owned_token_account() {
+    let mint = Pubkey::new_unique();
+
+    let payer = Keypair::new();
+    let payer_pubkey = payer.pubkey();
+
+    // The token account is owned by a PDA, which can't sign, so the payer,
+    // as its delegate, sends from it.
+    let (pda, _) = Pubkey::find_program_address(&[b"vault"], &Pubkey::new_unique());
+    let pda_token_account_pubkey = Pubkey::new_unique();
+
+    let bob_pubkey = Pubkey::new_unique();
+    let bob_token_account_pubkey = get_associated_token_address(&bob_pubkey, &mint);
+
+    let base_chain = in_memory_base_chain([
+        (mint, mint_account()),
+        (payer_pubkey, system_account(10_000_000)),
+        (
+            pda_token_account_pubkey,
+            token_account(&pda, &mint, 10, Some((&payer_pubkey, 10))),
+        ),
+        (
+            bob_token_account_pubkey,
+            token_account(&bob_pubkey, &mint, 10, None),
+        ),
+    ]);
+
+    let mut paytube_channel = in_memory_channel(payer.insecure_clone(), &[], &base_chain);
+    let channel_id = paytube_channel.id();
+
+    paytube_channel
+        .register_explicit_token_account(&pda_token_account_pubkey, &pda, &mint)
+        .unwrap();
+
+    paytube_channel
+        .process_paytube_transfers(&[
+            // PDA -> Bob 4, sent by the payer as the PDA's delegate.
+            PayTubeTransaction::new(channel_id, payer_pubkey, bob_pubkey, 4, Some(mint))
+                .with_source_token_account(pda_token_account_pubkey)
+                .sign(&payer),
+        ])
+        .unwrap();
+
+    // Settlement lands, and the PDA's delegation is reported rather than
+    // revoked.
+    let report = paytube_channel.close().unwrap();
+    assert_eq!(report.unrevoked_delegations.len(), 1);
+    assert_eq!(
+        report.unrevoked_delegations[0].token_account,
+        pda_token_account_pubkey
+    );
+    assert_eq!(report.unrevoked_delegations[0].owner, pda);
+
+    let pda_token_account = base_chain.get_account(&pda_token_account_pubkey).unwrap();
+    assert_eq!(get_token_amount(&pda_token_account), 6);
+    assert_eq!(
+        get_token_amount(&base_chain.get_account(&bob_token_account_pubkey).unwrap()),
+        14
+    );
+
+    let pda_token_account = TokenAccount::unpack(pda_token_account.data()).unwrap();
+    assert_eq!(pda_token_account.delegate, COption::Some(payer_pubkey));
+}
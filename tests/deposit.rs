@@ -0,0 +1,339 @@
+mod setup;
+
+use {
+    paytube_svm::{
+        error::PayTubeError,
+        escrow::{self, FinalBalance},
+        transaction::PayTubeTransaction,
+    },
+    setup::{
+        escrow_channel_account, escrow_deposit_account, get_token_amount, in_memory_base_chain,
+        in_memory_channel, mint_account, system_account, token_account,
+    },
+    solana_sdk::{
+        account::AccountSharedData, message::Message, pubkey::Pubkey, signature::Keypair,
+        signer::Signer, system_instruction,
+    },
+    spl_associated_token_account::{
+        get_associated_token_address, instruction::create_associated_token_account_idempotent,
+    },
+};
+
+#[test]
+fn test_deposit_caps_token_spend() {
+    let mint = Pubkey::new_unique();
+
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+
+    let alice_pubkey = alice.pubkey();
+    let alice_token_account_pubkey = get_associated_token_address(&alice_pubkey, &mint);
+
+    let bob_pubkey = bob.pubkey();
+    let bob_token_account_pubkey = get_associated_token_address(&bob_pubkey, &mint);
+
+    // Alice and Bob commit their deposits by delegating them to the payer,
+    // which is the channel's settlement authority.
+    let base_chain = in_memory_base_chain([
+        (mint, mint_account()),
+        (payer_pubkey, system_account(10_000_000)),
+        (alice_pubkey, system_account(10_000_000)),
+        (
+            alice_token_account_pubkey,
+            token_account(&alice_pubkey, &mint, 10, Some((&payer_pubkey, 5))),
+        ),
+        (bob_pubkey, system_account(10_000_000)),
+        (
+            bob_token_account_pubkey,
+            token_account(&bob_pubkey, &mint, 10, Some((&payer_pubkey, 10))),
+        ),
+    ]);
+
+    let mut paytube_channel = in_memory_channel(payer, &[&alice, &bob], &base_chain);
+    let channel_id = paytube_channel.id();
+
+    // Alice only puts half of her tokens into the channel, and can't deposit
+    // more than she delegated. Bob can't deposit more than he has.
+    assert!(matches!(
+        paytube_channel.lock_deposit(&alice_pubkey, Some(&mint), 6),
+        Err(PayTubeError::Deposit(_))
+    ));
+    paytube_channel
+        .lock_deposit(&alice_pubkey, Some(&mint), 5)
+        .unwrap();
+    assert!(matches!(
+        paytube_channel.lock_deposit(&bob_pubkey, Some(&mint), 20),
+        Err(PayTubeError::Deposit(_))
+    ));
+    assert!(matches!(
+        paytube_channel.lock_deposit(&alice_pubkey, Some(&mint), 5),
+        Err(PayTubeError::Deposit(_))
+    ));
+
+    let report = paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 7, more than her deposit.
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 7, Some(mint))
+                .sign(&alice),
+            // Alice -> Bob 5
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 5, Some(mint))
+                .with_nonce(1)
+                .sign(&alice),
+        ])
+        .unwrap();
+    assert!(!report.receipts[0].is_success());
+    assert!(report.receipts[1].is_success());
+
+    // Once Bob's account has been used, he can no longer lock a deposit.
+    assert!(matches!(
+        paytube_channel.lock_deposit(&bob_pubkey, Some(&mint), 5),
+        Err(PayTubeError::Deposit(_))
+    ));
+
+    paytube_channel.close().unwrap();
+
+    // Ledger:
+    // Alice:   10 - 5  = 5
+    // Bob:     10 + 5  = 15
+    assert_eq!(
+        get_token_amount(&base_chain.get_account(&alice_token_account_pubkey).unwrap()),
+        5
+    );
+    assert_eq!(
+        get_token_amount(&base_chain.get_account(&bob_token_account_pubkey).unwrap()),
+        15
+    );
+}
+
+#[test]
+fn test_deposit_caps_native_sol_spend() {
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+    let alice_pubkey = alice.pubkey();
+    let bob_pubkey = bob.pubkey();
+
+    // Alice escrows 3_000_000 of her 10_000_000 in the channel's escrow.
+    let (escrow_channel, escrow_channel_account) = escrow_channel_account(&payer_pubkey, 0, 1);
+    let base_chain = in_memory_base_chain([
+        (payer_pubkey, system_account(10_000_000)),
+        (alice_pubkey, system_account(7_000_000)),
+        (bob_pubkey, system_account(10_000_000)),
+        (escrow_channel, escrow_channel_account),
+        escrow_deposit_account(&escrow_channel, &alice_pubkey, None, 3_000_000),
+    ]);
+
+    let mut paytube_channel =
+        in_memory_channel(payer, &[&alice], &base_chain).with_escrow_channel(escrow_channel);
+    let channel_id = paytube_channel.id();
+
+    // Bob hasn't escrowed anything, and Alice can't lock more than she
+    // escrowed.
+    assert!(matches!(
+        paytube_channel.lock_deposit(&bob_pubkey, None, 1_000_000),
+        Err(PayTubeError::Deposit(_))
+    ));
+    assert!(matches!(
+        paytube_channel.lock_deposit(&alice_pubkey, None, 3_000_001),
+        Err(PayTubeError::Deposit(_))
+    ));
+    paytube_channel
+        .lock_deposit(&alice_pubkey, None, 3_000_000)
+        .unwrap();
+
+    let report = paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 5_000_000, more than her deposit.
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 5_000_000, None)
+                .sign(&alice),
+            // Alice -> Bob 2_000_000
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 2_000_000, None)
+                .with_nonce(1)
+                .sign(&alice),
+        ])
+        .unwrap();
+    assert!(!report.receipts[0].is_success());
+    assert!(report.receipts[1].is_success());
+
+    // The escrow is closed first, paying Alice back the 1_000_000 left of her
+    // deposit and releasing the rest to the payer, who pays Bob. Alice's
+    // wallet isn't touched.
+    let preview = paytube_channel.preview_settlement().unwrap();
+    assert_eq!(preview.transactions.len(), 2);
+    assert_eq!(
+        preview.transactions[0].message.instructions[0].data,
+        escrow::close_channel(
+            &payer_pubkey,
+            &escrow_channel,
+            &[FinalBalance {
+                owner: alice_pubkey,
+                mint: None,
+                amount: 1_000_000,
+            }],
+        )
+        .data
+    );
+    assert_eq!(
+        preview.transactions[1].message.instructions,
+        Message::new(
+            &[system_instruction::transfer(
+                &payer_pubkey,
+                &bob_pubkey,
+                2_000_000
+            )],
+            Some(&payer_pubkey),
+        )
+        .instructions
+    );
+}
+
+#[test]
+fn test_escrow_close_creates_missing_depositor_token_account() {
+    let mint = Pubkey::new_unique();
+
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+
+    let alice_pubkey = alice.pubkey();
+    let alice_token_account_pubkey = get_associated_token_address(&alice_pubkey, &mint);
+
+    let bob_pubkey = bob.pubkey();
+    let bob_token_account_pubkey = get_associated_token_address(&bob_pubkey, &mint);
+
+    // Alice escrows 10 tokens in the channel's escrow.
+    let (escrow_channel, escrow_channel_account) = escrow_channel_account(&payer_pubkey, 0, 1);
+    let base_chain = in_memory_base_chain([
+        (mint, mint_account()),
+        (payer_pubkey, system_account(10_000_000)),
+        (alice_pubkey, system_account(10_000_000)),
+        (
+            alice_token_account_pubkey,
+            token_account(&alice_pubkey, &mint, 0, None),
+        ),
+        (bob_pubkey, system_account(10_000_000)),
+        (
+            bob_token_account_pubkey,
+            token_account(&bob_pubkey, &mint, 0, None),
+        ),
+        (escrow_channel, escrow_channel_account),
+        escrow_deposit_account(&escrow_channel, &alice_pubkey, Some(&mint), 10),
+    ]);
+
+    let mut paytube_channel =
+        in_memory_channel(payer, &[&alice], &base_chain).with_escrow_channel(escrow_channel);
+    let channel_id = paytube_channel.id();
+
+    paytube_channel
+        .lock_deposit(&alice_pubkey, Some(&mint), 10)
+        .unwrap();
+    paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 4
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 4, Some(mint))
+                .sign(&alice),
+        ])
+        .unwrap();
+
+    // Alice closes her empty token account on the base chain while the
+    // channel is still open.
+    base_chain.set_account(alice_token_account_pubkey, AccountSharedData::default());
+
+    // Closing the escrow pays Alice back to her token account, so it's
+    // recreated first, funded by the payer, along with the payer's own token
+    // account, which the rest of her deposit is released to.
+    let preview = paytube_channel.preview_settlement().unwrap();
+    assert_eq!(
+        preview.transactions[0].message.instructions,
+        Message::new(
+            &[
+                create_associated_token_account_idempotent(
+                    &payer_pubkey,
+                    &payer_pubkey,
+                    &mint,
+                    &spl_token::id(),
+                ),
+                create_associated_token_account_idempotent(
+                    &payer_pubkey,
+                    &alice_pubkey,
+                    &mint,
+                    &spl_token::id(),
+                ),
+                escrow::close_channel(
+                    &payer_pubkey,
+                    &escrow_channel,
+                    &[FinalBalance {
+                        owner: alice_pubkey,
+                        mint: Some(mint),
+                        amount: 6,
+                    }],
+                ),
+            ],
+            Some(&payer_pubkey),
+        )
+        .instructions
+    );
+}
+
+#[test]
+fn test_escrowed_deposit_is_not_drawn_from_wallet() {
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+    let alice_pubkey = alice.pubkey();
+
+    // Alice escrowed everything she had, leaving nothing in her wallet.
+    let (escrow_channel, escrow_channel_account) = escrow_channel_account(&payer_pubkey, 0, 1);
+    let base_chain = in_memory_base_chain([
+        (payer_pubkey, system_account(10_000_000)),
+        (alice_pubkey, system_account(0)),
+        (escrow_channel, escrow_channel_account),
+        escrow_deposit_account(&escrow_channel, &alice_pubkey, None, 10_000_000),
+    ]);
+
+    let mut paytube_channel =
+        in_memory_channel(payer, &[], &base_chain).with_escrow_channel(escrow_channel);
+
+    paytube_channel
+        .lock_deposit(&alice_pubkey, None, 10_000_000)
+        .unwrap();
+}
+
+#[test]
+fn test_escrow_must_be_operated_by_payer() {
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+    let alice_pubkey = alice.pubkey();
+
+    // The escrow channel belongs to another operator, so the payer can't
+    // close it.
+    let operator = Pubkey::new_unique();
+    let (escrow_channel, escrow_channel_account) = escrow_channel_account(&operator, 0, 1);
+    let base_chain = in_memory_base_chain([
+        (payer_pubkey, system_account(10_000_000)),
+        (alice_pubkey, system_account(7_000_000)),
+        (escrow_channel, escrow_channel_account),
+        escrow_deposit_account(&escrow_channel, &alice_pubkey, None, 3_000_000),
+    ]);
+
+    let mut paytube_channel =
+        in_memory_channel(payer, &[], &base_chain).with_escrow_channel(escrow_channel);
+    paytube_channel
+        .lock_deposit(&alice_pubkey, None, 3_000_000)
+        .unwrap();
+
+    assert!(matches!(
+        paytube_channel.preview_settlement(),
+        Err(PayTubeError::Settlement(_))
+    ));
+}
@@ -0,0 +1,765 @@
+mod setup;
+
+use {
+    paytube_escrow::error::PayTubeEscrowError,
+    paytube_svm::{
+        error::PayTubeError,
+        escrow::{
+            self, decode_channel, decode_deposit, find_channel_address, find_deposit_address,
+            find_vault_address, FinalBalance,
+        },
+        signer::LocalSigners,
+        sink::{SettlementSink, SimulationOutcome},
+        source::AccountSource,
+        transaction::PayTubeTransaction,
+        PayTubeChannel,
+    },
+    setup::{get_token_amount, mint_account, system_account, token_account},
+    solana_program_test::{
+        processor, BanksClient, BanksClientError, ProgramTest, ProgramTestContext,
+    },
+    solana_sdk::{
+        account::{Account, AccountSharedData, ReadableAccount},
+        clock::Clock,
+        hash::Hash,
+        instruction::{Instruction, InstructionError},
+        pubkey::Pubkey,
+        signature::{Keypair, Signature},
+        signer::Signer,
+        system_instruction,
+        transaction::{Transaction, TransactionError},
+    },
+    spl_associated_token_account::get_associated_token_address,
+    std::{future::Future, sync::Arc},
+    tokio::runtime::Runtime,
+};
+
+async fn process(
+    context: &mut ProgramTestContext,
+    instructions: &[Instruction],
+    signers: &[&Keypair],
+) -> Result<(), TransactionError> {
+    let mut all_signers = vec![&context.payer];
+    all_signers.extend_from_slice(signers);
+    let transaction = Transaction::new_signed_with_payer(
+        instructions,
+        Some(&context.payer.pubkey()),
+        &all_signers,
+        context.last_blockhash,
+    );
+    context
+        .banks_client
+        .process_transaction(transaction)
+        .await
+        .map_err(|err| err.unwrap())
+}
+
+async fn get_account(context: &mut ProgramTestContext, pubkey: &Pubkey) -> Option<Account> {
+    context.banks_client.get_account(*pubkey).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_escrow_channel() {
+    let mint = Pubkey::new_unique();
+
+    let operator = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    let operator_token_account_pubkey = get_associated_token_address(&operator.pubkey(), &mint);
+    let alice_token_account_pubkey = get_associated_token_address(&alice.pubkey(), &mint);
+    let bob_token_account_pubkey = get_associated_token_address(&bob.pubkey(), &mint);
+
+    let mut program_test = ProgramTest::new(
+        "paytube_escrow",
+        escrow::id(),
+        processor!(paytube_escrow::processor::process_instruction),
+    );
+    for (pubkey, account) in [
+        (mint, mint_account()),
+        (operator.pubkey(), system_account(10_000_000_000)),
+        (
+            operator_token_account_pubkey,
+            token_account(&operator.pubkey(), &mint, 1, None),
+        ),
+        (alice.pubkey(), system_account(10_000_000_000)),
+        (
+            alice_token_account_pubkey,
+            token_account(&alice.pubkey(), &mint, 10, None),
+        ),
+        (bob.pubkey(), system_account(10_000_000_000)),
+        (
+            bob_token_account_pubkey,
+            token_account(&bob.pubkey(), &mint, 10, None),
+        ),
+    ] {
+        program_test.add_account(pubkey, account.into());
+    }
+    let mut context = program_test.start_with_context().await;
+
+    // Open the channel.
+    let (channel, _) = find_channel_address(&operator.pubkey(), 0);
+    let (vault, _) = find_vault_address(&channel, &mint);
+    process(
+        &mut context,
+        &[
+            escrow::open_channel(&operator.pubkey(), 0, i64::MAX),
+            escrow::create_vault(&operator.pubkey(), &channel, &mint),
+        ],
+        &[&operator],
+    )
+    .await
+    .unwrap();
+
+    // Empty deposits are rejected.
+    let err = process(
+        &mut context,
+        &[escrow::deposit_native(
+            &alice.pubkey(),
+            &operator.pubkey(),
+            &channel,
+            0,
+        )],
+        &[&alice, &operator],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(PayTubeEscrowError::ZeroDeposit as u32)
+        )
+    );
+
+    // Alice deposits SOL and tokens, Bob deposits tokens. The operator sends a
+    // stray token straight to the vault, which isn't a deposit.
+    process(
+        &mut context,
+        &[spl_token::instruction::transfer(
+            &spl_token::id(),
+            &operator_token_account_pubkey,
+            &vault,
+            &operator.pubkey(),
+            &[],
+            1,
+        )
+        .unwrap()],
+        &[&operator],
+    )
+    .await
+    .unwrap();
+    process(
+        &mut context,
+        &[
+            escrow::deposit_native(&alice.pubkey(), &operator.pubkey(), &channel, 1_000_000),
+            escrow::deposit_token(&alice.pubkey(), &operator.pubkey(), &channel, &mint, 10),
+            escrow::deposit_token(&bob.pubkey(), &operator.pubkey(), &channel, &mint, 10),
+        ],
+        &[&alice, &bob, &operator],
+    )
+    .await
+    .unwrap();
+
+    let channel_account = get_account(&mut context, &channel).await.unwrap();
+    let channel_state = decode_channel(&AccountSharedData::from(channel_account)).unwrap();
+    assert_eq!(channel_state.operator, operator.pubkey());
+    assert_eq!(channel_state.num_deposits, 3);
+
+    let (alice_deposit, _) = find_deposit_address(&channel, &alice.pubkey(), Some(&mint));
+    let alice_deposit_account = get_account(&mut context, &alice_deposit).await.unwrap();
+    let alice_deposit_state =
+        decode_deposit(&AccountSharedData::from(alice_deposit_account)).unwrap();
+    assert_eq!(alice_deposit_state.owner, alice.pubkey());
+    assert_eq!(alice_deposit_state.mint, Some(mint));
+    assert_eq!(alice_deposit_state.amount, 10);
+
+    let alice_lamports = get_account(&mut context, &alice.pubkey())
+        .await
+        .unwrap()
+        .lamports;
+
+    // A final ledger which pays out more tokens than were deposited is
+    // rejected.
+    let err = process(
+        &mut context,
+        &[escrow::close_channel(
+            &operator.pubkey(),
+            &channel,
+            &[
+                FinalBalance {
+                    owner: alice.pubkey(),
+                    mint: None,
+                    amount: 1_000_000,
+                },
+                FinalBalance {
+                    owner: alice.pubkey(),
+                    mint: Some(mint),
+                    amount: 15,
+                },
+                FinalBalance {
+                    owner: bob.pubkey(),
+                    mint: Some(mint),
+                    amount: 15,
+                },
+            ],
+        )],
+        &[&operator],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(PayTubeEscrowError::LedgerMismatch as u32)
+        )
+    );
+
+    // Alice paid Bob 7 tokens over the lifetime of the channel.
+    process(
+        &mut context,
+        &[escrow::close_channel(
+            &operator.pubkey(),
+            &channel,
+            &[
+                FinalBalance {
+                    owner: alice.pubkey(),
+                    mint: None,
+                    amount: 1_000_000,
+                },
+                FinalBalance {
+                    owner: alice.pubkey(),
+                    mint: Some(mint),
+                    amount: 3,
+                },
+                FinalBalance {
+                    owner: bob.pubkey(),
+                    mint: Some(mint),
+                    amount: 17,
+                },
+            ],
+        )],
+        &[&operator],
+    )
+    .await
+    .unwrap();
+
+    let alice_token_account = get_account(&mut context, &alice_token_account_pubkey)
+        .await
+        .unwrap();
+    assert_eq!(get_token_amount(&alice_token_account.into()), 3);
+    let bob_token_account = get_account(&mut context, &bob_token_account_pubkey)
+        .await
+        .unwrap();
+    assert_eq!(get_token_amount(&bob_token_account.into()), 17);
+
+    // The stray token was swept back to the operator, and the vault closed.
+    let operator_token_account = get_account(&mut context, &operator_token_account_pubkey)
+        .await
+        .unwrap();
+    assert_eq!(get_token_amount(&operator_token_account.into()), 1);
+    assert!(get_account(&mut context, &vault).await.is_none());
+
+    // Alice gets back her SOL and the rent for both of her deposit accounts.
+    let (alice_native_deposit, _) = find_deposit_address(&channel, &alice.pubkey(), None);
+    assert!(get_account(&mut context, &alice_native_deposit)
+        .await
+        .is_none());
+    assert!(get_account(&mut context, &alice_deposit).await.is_none());
+    assert!(get_account(&mut context, &channel).await.is_none());
+    assert_eq!(
+        get_account(&mut context, &alice.pubkey())
+            .await
+            .unwrap()
+            .lamports,
+        10_000_000_000,
+    );
+    assert!(alice_lamports < 10_000_000_000);
+}
+
+#[tokio::test]
+async fn test_escrow_rejects_deposit_without_operator() {
+    let operator = Keypair::new();
+    let alice = Keypair::new();
+    let mallory = Keypair::new();
+
+    let mut program_test = ProgramTest::new(
+        "paytube_escrow",
+        escrow::id(),
+        processor!(paytube_escrow::processor::process_instruction),
+    );
+    for (pubkey, account) in [
+        (operator.pubkey(), system_account(10_000_000_000)),
+        (alice.pubkey(), system_account(10_000_000_000)),
+        (mallory.pubkey(), system_account(10_000_000_000)),
+    ] {
+        program_test.add_account(pubkey, account.into());
+    }
+    let mut context = program_test.start_with_context().await;
+
+    let (channel, _) = find_channel_address(&operator.pubkey(), 0);
+    process(
+        &mut context,
+        &[
+            escrow::open_channel(&operator.pubkey(), 0, i64::MAX),
+            escrow::deposit_native(&alice.pubkey(), &operator.pubkey(), &channel, 1_000_000),
+        ],
+        &[&operator, &alice],
+    )
+    .await
+    .unwrap();
+
+    // Mallory can't slip a deposit into the channel without the operator.
+    let err = process(
+        &mut context,
+        &[escrow::deposit_native(
+            &mallory.pubkey(),
+            &mallory.pubkey(),
+            &channel,
+            1,
+        )],
+        &[&mallory],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(PayTubeEscrowError::OperatorMismatch as u32)
+        )
+    );
+
+    // So the final ledger covering Alice's deposit alone still closes the
+    // channel.
+    process(
+        &mut context,
+        &[escrow::close_channel(
+            &operator.pubkey(),
+            &channel,
+            &[FinalBalance {
+                owner: alice.pubkey(),
+                mint: None,
+                amount: 1_000_000,
+            }],
+        )],
+        &[&operator],
+    )
+    .await
+    .unwrap();
+    assert!(get_account(&mut context, &channel).await.is_none());
+}
+
+#[tokio::test]
+async fn test_escrow_withdraw_after_expiry() {
+    let mint = Pubkey::new_unique();
+
+    let operator = Keypair::new();
+    let alice = Keypair::new();
+
+    let alice_token_account_pubkey = get_associated_token_address(&alice.pubkey(), &mint);
+
+    let mut program_test = ProgramTest::new(
+        "paytube_escrow",
+        escrow::id(),
+        processor!(paytube_escrow::processor::process_instruction),
+    );
+    for (pubkey, account) in [
+        (mint, mint_account()),
+        (operator.pubkey(), system_account(10_000_000_000)),
+        (alice.pubkey(), system_account(10_000_000_000)),
+        (
+            alice_token_account_pubkey,
+            token_account(&alice.pubkey(), &mint, 10, None),
+        ),
+    ] {
+        program_test.add_account(pubkey, account.into());
+    }
+    let mut context = program_test.start_with_context().await;
+
+    // Open a channel expiring in an hour, and have Alice deposit into it.
+    let clock = context.banks_client.get_sysvar::<Clock>().await.unwrap();
+    let expires_at = clock.unix_timestamp + 3600;
+    let (channel, _) = find_channel_address(&operator.pubkey(), 0);
+    process(
+        &mut context,
+        &[
+            escrow::open_channel(&operator.pubkey(), 0, expires_at),
+            escrow::create_vault(&operator.pubkey(), &channel, &mint),
+        ],
+        &[&operator],
+    )
+    .await
+    .unwrap();
+    process(
+        &mut context,
+        &[
+            escrow::deposit_native(&alice.pubkey(), &operator.pubkey(), &channel, 1_000_000),
+            escrow::deposit_token(&alice.pubkey(), &operator.pubkey(), &channel, &mint, 10),
+        ],
+        &[&alice, &operator],
+    )
+    .await
+    .unwrap();
+
+    // Alice can't withdraw while the operator may still close the channel.
+    let err = process(
+        &mut context,
+        &[escrow::withdraw(&alice.pubkey(), &channel, None)],
+        &[&alice],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(PayTubeEscrowError::ChannelNotExpired as u32)
+        )
+    );
+
+    // Once the channel expires, the operator can no longer close it, and she
+    // withdraws both deposits in full.
+    context.set_sysvar(&Clock {
+        unix_timestamp: expires_at,
+        ..clock
+    });
+    let err = process(
+        &mut context,
+        &[escrow::close_channel(
+            &operator.pubkey(),
+            &channel,
+            &[
+                FinalBalance {
+                    owner: alice.pubkey(),
+                    mint: None,
+                    amount: 1_000_000,
+                },
+                FinalBalance {
+                    owner: alice.pubkey(),
+                    mint: Some(mint),
+                    amount: 10,
+                },
+            ],
+        )],
+        &[&operator],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(PayTubeEscrowError::ChannelExpired as u32)
+        )
+    );
+    process(
+        &mut context,
+        &[
+            escrow::withdraw(&alice.pubkey(), &channel, None),
+            escrow::withdraw(&alice.pubkey(), &channel, Some(&mint)),
+        ],
+        &[&alice],
+    )
+    .await
+    .unwrap();
+
+    let alice_token_account = get_account(&mut context, &alice_token_account_pubkey)
+        .await
+        .unwrap();
+    assert_eq!(get_token_amount(&alice_token_account.into()), 10);
+    assert_eq!(
+        get_account(&mut context, &alice.pubkey())
+            .await
+            .unwrap()
+            .lamports,
+        10_000_000_000,
+    );
+
+    // The withdrawn deposits no longer need to appear in the final ledger.
+    let channel_account = get_account(&mut context, &channel).await.unwrap();
+    let channel_state = decode_channel(&AccountSharedData::from(channel_account)).unwrap();
+    assert_eq!(channel_state.num_deposits, 0);
+}
+
+/// The program test's bank, as the base chain a PayTube channel loads from
+/// and settles to.
+#[derive(Clone)]
+struct BanksBaseChain {
+    runtime: Arc<Runtime>,
+    banks_client: BanksClient,
+}
+
+impl BanksBaseChain {
+    fn call<T, F: Future<Output = Result<T, BanksClientError>>>(
+        &self,
+        request: impl FnOnce(BanksClient) -> F,
+    ) -> Result<T, PayTubeError> {
+        self.runtime
+            .block_on(request(self.banks_client.clone()))
+            .map_err(|err| PayTubeError::Settlement(err.to_string()))
+    }
+}
+
+impl AccountSource for BanksBaseChain {
+    fn get_account(&self, pubkey: &Pubkey) -> Result<Option<AccountSharedData>, PayTubeError> {
+        let pubkey = *pubkey;
+        let account =
+            self.call(move |mut client| async move { client.get_account(pubkey).await })?;
+        Ok(account.map(AccountSharedData::from))
+    }
+}
+
+impl SettlementSink for BanksBaseChain {
+    fn get_latest_blockhash(&self) -> Result<Hash, PayTubeError> {
+        self.call(|mut client| async move { client.get_latest_blockhash().await })
+    }
+
+    fn simulate_transaction(
+        &self,
+        transaction: &Transaction,
+    ) -> Result<SimulationOutcome, PayTubeError> {
+        let transaction = transaction.clone();
+        let simulation =
+            self.call(
+                move |mut client| async move { client.simulate_transaction(transaction).await },
+            )?;
+        Ok(SimulationOutcome {
+            error: simulation.result.and_then(Result::err),
+            logs: simulation
+                .simulation_details
+                .map(|details| details.logs)
+                .unwrap_or_default(),
+        })
+    }
+
+    fn submit_transaction(&self, transaction: &Transaction) -> Result<Signature, PayTubeError> {
+        let signature = transaction.signatures[0];
+        let transaction = transaction.clone();
+        self.call(move |mut client| async move { client.send_transaction(transaction).await })?;
+        Ok(signature)
+    }
+
+    fn get_transaction_status(
+        &self,
+        signature: &Signature,
+    ) -> Result<Option<Result<(), TransactionError>>, PayTubeError> {
+        let signature = *signature;
+        let status =
+            self.call(
+                move |mut client| async move { client.get_transaction_status(signature).await },
+            )?;
+        Ok(status.map(|status| status.err.map_or(Ok(()), Err)))
+    }
+}
+
+#[test]
+fn test_channel_settles_escrowed_deposit() {
+    let runtime = Arc::new(Runtime::new().unwrap());
+
+    let operator = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    let operator_pubkey = operator.pubkey();
+    let alice_pubkey = alice.pubkey();
+    let bob_pubkey = bob.pubkey();
+
+    let mut program_test = ProgramTest::new(
+        "paytube_escrow",
+        escrow::id(),
+        processor!(paytube_escrow::processor::process_instruction),
+    );
+    for (pubkey, account) in [
+        (operator_pubkey, system_account(10_000_000_000)),
+        (alice_pubkey, system_account(10_000_000_000)),
+        (bob_pubkey, system_account(10_000_000_000)),
+    ] {
+        program_test.add_account(pubkey, account.into());
+    }
+    let (banks_client, payer, recent_blockhash) = runtime.block_on(program_test.start());
+    let base_chain = BanksBaseChain {
+        runtime: runtime.clone(),
+        banks_client,
+    };
+
+    // Alice escrows 3_000_000 in a channel operated by the channel's payer.
+    let (escrow_channel, _) = find_channel_address(&operator_pubkey, 0);
+    let (deposit, _) = find_deposit_address(&escrow_channel, &alice_pubkey, None);
+    let transaction = Transaction::new_signed_with_payer(
+        &[
+            escrow::open_channel(&operator_pubkey, 0, i64::MAX),
+            escrow::deposit_native(&alice_pubkey, &operator_pubkey, &escrow_channel, 3_000_000),
+        ],
+        Some(&payer.pubkey()),
+        &[&payer, &operator, &alice],
+        recent_blockhash,
+    );
+    runtime
+        .block_on(
+            base_chain
+                .banks_client
+                .clone()
+                .process_transaction(transaction),
+        )
+        .unwrap();
+    let get_balance = |pubkey: &Pubkey| {
+        base_chain
+            .get_account(pubkey)
+            .unwrap()
+            .map_or(0, |account| account.lamports())
+    };
+    let alice_balance = get_balance(&alice_pubkey);
+    let bob_balance = get_balance(&bob_pubkey);
+    let deposit_rent = get_balance(&deposit);
+
+    let mut paytube_channel = PayTubeChannel::new_with_backends(
+        operator_pubkey,
+        Box::new(LocalSigners::from(vec![operator])),
+        Box::new(base_chain.clone()),
+        Box::new(base_chain.clone()),
+    )
+    .unwrap()
+    .with_escrow_channel(escrow_channel);
+    let channel_id = paytube_channel.id();
+
+    paytube_channel
+        .lock_deposit(&alice_pubkey, None, 3_000_000)
+        .unwrap();
+    paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 2_000_000
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 2_000_000, None)
+                .sign(&alice),
+        ])
+        .unwrap();
+
+    paytube_channel.close().unwrap();
+
+    // Alice is paid back what's left of her deposit, along with its rent, and
+    // Bob is paid by the operator out of what the escrow released.
+    assert_eq!(
+        get_balance(&alice_pubkey),
+        alice_balance + 1_000_000 + deposit_rent
+    );
+    assert_eq!(get_balance(&bob_pubkey), bob_balance + 2_000_000);
+    assert_eq!(get_balance(&escrow_channel), 0);
+    assert_eq!(get_balance(&deposit), 0);
+}
+
+#[test]
+fn test_channel_settlement_incomplete_after_escrow_closed() {
+    let runtime = Arc::new(Runtime::new().unwrap());
+
+    let operator = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    let operator_pubkey = operator.pubkey();
+    let alice_pubkey = alice.pubkey();
+    let bob_pubkey = bob.pubkey();
+    let carol_pubkey = Pubkey::new_unique();
+
+    let mut program_test = ProgramTest::new(
+        "paytube_escrow",
+        escrow::id(),
+        processor!(paytube_escrow::processor::process_instruction),
+    );
+    for (pubkey, account) in [
+        (operator_pubkey, system_account(10_000_000_000)),
+        (alice_pubkey, system_account(10_000_000_000)),
+        (bob_pubkey, system_account(10_000_000_000)),
+    ] {
+        program_test.add_account(pubkey, account.into());
+    }
+    let (banks_client, payer, recent_blockhash) = runtime.block_on(program_test.start());
+    let base_chain = BanksBaseChain {
+        runtime: runtime.clone(),
+        banks_client,
+    };
+    let process_transaction = |instruction: Instruction, signers: &[&Keypair]| {
+        let signers = [&payer].into_iter().chain(signers.iter().copied());
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction],
+            Some(&payer.pubkey()),
+            &signers.collect::<Vec<_>>(),
+            recent_blockhash,
+        );
+        runtime
+            .block_on(
+                base_chain
+                    .banks_client
+                    .clone()
+                    .process_transaction(transaction),
+            )
+            .unwrap();
+    };
+    let get_balance = |pubkey: &Pubkey| {
+        base_chain
+            .get_account(pubkey)
+            .unwrap()
+            .map_or(0, |account| account.lamports())
+    };
+
+    // Alice escrows 3_000_000 in a channel operated by the channel's payer,
+    // while Bob pays out of his wallet.
+    let (escrow_channel, _) = find_channel_address(&operator_pubkey, 0);
+    process_transaction(
+        escrow::open_channel(&operator_pubkey, 0, i64::MAX),
+        &[&operator],
+    );
+    process_transaction(
+        escrow::deposit_native(&alice_pubkey, &operator_pubkey, &escrow_channel, 3_000_000),
+        &[&alice, &operator],
+    );
+
+    let mut paytube_channel = PayTubeChannel::new_with_backends(
+        operator_pubkey,
+        Box::new(LocalSigners::from(vec![operator, bob.insecure_clone()])),
+        Box::new(base_chain.clone()),
+        Box::new(base_chain.clone()),
+    )
+    .unwrap()
+    .with_escrow_channel(escrow_channel);
+    let channel_id = paytube_channel.id();
+
+    paytube_channel
+        .lock_deposit(&alice_pubkey, None, 3_000_000)
+        .unwrap();
+    paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 2_000_000
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 2_000_000, None)
+                .sign(&alice),
+            // Bob -> Carol 3_000_000
+            PayTubeTransaction::new(channel_id, bob_pubkey, carol_pubkey, 3_000_000, None)
+                .sign(&bob),
+        ])
+        .unwrap();
+
+    // Bob empties his wallet on the base chain while the channel is open.
+    let bob_balance = get_balance(&bob_pubkey);
+    process_transaction(
+        system_instruction::transfer(&bob_pubkey, &operator_pubkey, bob_balance),
+        &[&bob],
+    );
+
+    // The bank can't simulate the transfers against the state closing the
+    // escrow leaves behind, so the escrow is closed before Bob's transfer
+    // fails simulation.
+    let Err(PayTubeError::SettlementIncomplete(report)) = paytube_channel.close() else {
+        panic!("expected the settlement to be left incomplete");
+    };
+    assert!(report.failures.iter().all(|failure| failure.index > 0));
+    assert_eq!(get_balance(&escrow_channel), 0);
+    assert_eq!(get_balance(&carol_pubkey), 0);
+
+    // Once Bob can pay again, the rest of the settlement lands.
+    process_transaction(
+        system_instruction::transfer(&payer.pubkey(), &bob_pubkey, bob_balance),
+        &[],
+    );
+    paytube_channel.resume_settlement().unwrap();
+    assert_eq!(get_balance(&carol_pubkey), 3_000_000);
+}
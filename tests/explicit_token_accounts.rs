@@ -0,0 +1,201 @@
+mod setup;
+
+use {
+    paytube_svm::{
+        report::TransactionStatus,
+        transaction::{PayTubeTransaction, PayTubeTransactionError},
+        NetDelta,
+    },
+    setup::{
+        get_token_amount, in_memory_base_chain, in_memory_channel, mint_account, system_account,
+        token_account,
+    },
+    solana_sdk::{
+        account::ReadableAccount, program_pack::Pack, pubkey::Pubkey, signature::Keypair,
+        signer::Signer,
+    },
+    spl_associated_token_account::get_associated_token_address,
+    spl_token::state::Account as TokenAccount,
+};
+
+#[test]
+fn test_explicit_token_accounts() {
+    let mint = Pubkey::new_unique();
+
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+
+    // Alice transacts from an auxiliary token account, rather than her
+    // associated token account.
+    let alice_pubkey = alice.pubkey();
+    let alice_token_account_pubkey = Pubkey::new_unique();
+
+    let bob_pubkey = bob.pubkey();
+    let bob_token_account_pubkey = get_associated_token_address(&bob_pubkey, &mint);
+
+    let base_chain = in_memory_base_chain([
+        (mint, mint_account()),
+        (payer_pubkey, system_account(10_000_000)),
+        (alice_pubkey, system_account(10_000_000)),
+        (
+            alice_token_account_pubkey,
+            token_account(&alice_pubkey, &mint, 10, None),
+        ),
+        (bob_pubkey, system_account(10_000_000)),
+        (
+            bob_token_account_pubkey,
+            token_account(&bob_pubkey, &mint, 10, None),
+        ),
+    ]);
+
+    let mut paytube_channel = in_memory_channel(payer, &[&alice, &bob], &base_chain);
+    let channel_id = paytube_channel.id();
+
+    let report = paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 4, from Alice's auxiliary token account.
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 4, Some(mint))
+                .with_source_token_account(alice_token_account_pubkey)
+                .sign(&alice),
+            // Alice -> Bob 4, from Bob's token account, which isn't hers.
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 4, Some(mint))
+                .with_nonce(1)
+                .with_source_token_account(bob_token_account_pubkey)
+                .sign(&alice),
+            // Bob -> Alice 1, into Alice's auxiliary token account.
+            PayTubeTransaction::new(channel_id, bob_pubkey, alice_pubkey, 1, Some(mint))
+                .with_destination_token_account(alice_token_account_pubkey)
+                .sign(&bob),
+        ])
+        .unwrap();
+    assert!(report.receipts[0].is_success());
+    assert_eq!(
+        report.receipts[1].status,
+        TransactionStatus::Rejected(PayTubeTransactionError::InvalidTokenAccount {
+            token_account: bob_token_account_pubkey,
+        })
+    );
+    assert!(report.receipts[2].is_success());
+
+    let preview = paytube_channel.preview_settlement().unwrap();
+    assert_eq!(preview.net_deltas, {
+        let mut net_deltas = vec![
+            NetDelta {
+                mint: Some(mint),
+                owner: alice_pubkey,
+                token_account: Some(alice_token_account_pubkey),
+                amount: -3,
+            },
+            NetDelta {
+                mint: Some(mint),
+                owner: bob_pubkey,
+                token_account: Some(bob_token_account_pubkey),
+                amount: 3,
+            },
+        ];
+        net_deltas.sort_by_key(|net_delta| net_delta.owner);
+        net_deltas
+    });
+
+    paytube_channel.close().unwrap();
+
+    // Ledger:
+    // Alice:   10 - 4 + 1  = 7
+    // Bob:     10 + 4 - 1  = 13
+    assert_eq!(
+        get_token_amount(&base_chain.get_account(&alice_token_account_pubkey).unwrap()),
+        7
+    );
+    assert_eq!(
+        get_token_amount(&base_chain.get_account(&bob_token_account_pubkey).unwrap()),
+        13
+    );
+}
+
+#[test]
+fn test_delegate_sends_from_token_account_it_does_not_own() {
+    let mint = Pubkey::new_unique();
+
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+
+    // A treasury token account owned by a PDA, which can never sign, has
+    // delegated 6 of its tokens to Alice.
+    let (treasury_pubkey, _) = Pubkey::find_program_address(&[b"treasury"], &Pubkey::new_unique());
+    let treasury_token_account_pubkey = get_associated_token_address(&treasury_pubkey, &mint);
+
+    let alice_pubkey = alice.pubkey();
+
+    let bob_pubkey = bob.pubkey();
+    let bob_token_account_pubkey = get_associated_token_address(&bob_pubkey, &mint);
+
+    let base_chain = in_memory_base_chain([
+        (mint, mint_account()),
+        (payer_pubkey, system_account(10_000_000)),
+        (alice_pubkey, system_account(10_000_000)),
+        (
+            treasury_token_account_pubkey,
+            token_account(&treasury_pubkey, &mint, 10, Some((&alice_pubkey, 6))),
+        ),
+        (bob_pubkey, system_account(10_000_000)),
+        (
+            bob_token_account_pubkey,
+            token_account(&bob_pubkey, &mint, 10, None),
+        ),
+    ]);
+
+    // Only the payer and Alice, as the treasury's delegate, can sign.
+    let mut paytube_channel = in_memory_channel(payer, &[&alice], &base_chain);
+    let channel_id = paytube_channel.id();
+
+    let report = paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 7 from the treasury, more than was delegated to her.
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 7, Some(mint))
+                .with_source_token_account(treasury_token_account_pubkey)
+                .sign(&alice),
+            // Alice -> Bob 4 from the treasury.
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 4, Some(mint))
+                .with_nonce(1)
+                .with_source_token_account(treasury_token_account_pubkey)
+                .sign(&alice),
+        ])
+        .unwrap();
+    assert_eq!(
+        report.receipts[0].status,
+        TransactionStatus::Rejected(PayTubeTransactionError::InvalidTokenAccount {
+            token_account: treasury_token_account_pubkey,
+        })
+    );
+    assert!(report.receipts[1].is_success());
+
+    paytube_channel.close().unwrap();
+
+    // Ledger:
+    // Treasury:    10 - 4  = 6
+    // Bob:         10 + 4  = 14
+    //
+    // The treasury delegated to Alice rather than to the settlement authority,
+    // so its delegation isn't the channel's to revoke, and what remains of it
+    // is left in place.
+    let treasury_token_account = base_chain
+        .get_account(&treasury_token_account_pubkey)
+        .unwrap();
+    assert_eq!(get_token_amount(&treasury_token_account), 6);
+    assert_eq!(
+        TokenAccount::unpack(treasury_token_account.data())
+            .unwrap()
+            .delegated_amount,
+        2
+    );
+    assert_eq!(
+        get_token_amount(&base_chain.get_account(&bob_token_account_pubkey).unwrap()),
+        14
+    );
+}
@@ -0,0 +1,465 @@
+mod setup;
+
+use {
+    paytube_svm::{
+        error::PayTubeError,
+        harness::InMemoryBaseChain,
+        report::TransactionStatus,
+        signer::LocalSigners,
+        source::AccountSource,
+        transaction::{PayTubeTransaction, PayTubeTransactionError},
+        PayTubeChannel, ProcessingMode,
+    },
+    setup::{
+        get_token_amount, in_memory_base_chain, in_memory_channel, mint_account, rent_account,
+        system_account, token_account,
+    },
+    solana_sdk::{
+        account::{AccountSharedData, ReadableAccount},
+        pubkey::Pubkey,
+        signature::Keypair,
+        signer::Signer,
+        sysvar,
+    },
+    spl_associated_token_account::get_associated_token_address,
+    std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// An account source which fails to load one account while `failing` is set.
+struct FailingAccountSource {
+    base_chain: InMemoryBaseChain,
+    pubkey: Pubkey,
+    failing: Arc<AtomicBool>,
+}
+
+impl AccountSource for FailingAccountSource {
+    fn get_account(&self, pubkey: &Pubkey) -> Result<Option<AccountSharedData>, PayTubeError> {
+        if *pubkey == self.pubkey && self.failing.load(Ordering::Relaxed) {
+            return Err(std::io::Error::other("connection reset").into());
+        }
+        Ok(self.base_chain.get_account(pubkey))
+    }
+}
+
+#[test]
+fn test_in_memory_native_sol() {
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let will = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+    let alice_pubkey = alice.pubkey();
+    let bob_pubkey = bob.pubkey();
+    let will_pubkey = will.pubkey();
+
+    let base_chain = in_memory_base_chain([
+        (payer_pubkey, system_account(10_000_000)),
+        (alice_pubkey, system_account(10_000_000)),
+        (bob_pubkey, system_account(10_000_000)),
+        (will_pubkey, system_account(10_000_000)),
+    ]);
+
+    let mut paytube_channel = in_memory_channel(payer, &[&alice, &bob, &will], &base_chain);
+    let channel_id = paytube_channel.id();
+
+    paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 2_000_000
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 2_000_000, None)
+                .sign(&alice),
+            // Bob -> Will 5_000_000
+            PayTubeTransaction::new(channel_id, bob_pubkey, will_pubkey, 5_000_000, None)
+                .sign(&bob),
+            // Will -> Alice 1_000_000
+            PayTubeTransaction::new(channel_id, will_pubkey, alice_pubkey, 1_000_000, None)
+                .sign(&will),
+        ])
+        .unwrap();
+
+    // Nothing is settled until the channel is closed.
+    assert_eq!(base_chain.get_balance(&alice_pubkey), 10_000_000);
+
+    paytube_channel.close().unwrap();
+
+    // Ledger:
+    // Alice:   10_000_000 - 2_000_000 + 1_000_000  = 9_000_000
+    // Bob:     10_000_000 + 2_000_000 - 5_000_000  = 7_000_000
+    // Will:    10_000_000 + 5_000_000 - 1_000_000  = 14_000_000
+    assert_eq!(base_chain.get_balance(&alice_pubkey), 9_000_000);
+    assert_eq!(base_chain.get_balance(&bob_pubkey), 7_000_000);
+    assert_eq!(base_chain.get_balance(&will_pubkey), 14_000_000);
+}
+
+#[test]
+fn test_in_memory_rejects_transfer_for_another_channel() {
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+    let alice_pubkey = alice.pubkey();
+    let bob_pubkey = bob.pubkey();
+
+    let base_chain = in_memory_base_chain([
+        (payer_pubkey, system_account(10_000_000)),
+        (alice_pubkey, system_account(10_000_000)),
+        (bob_pubkey, system_account(10_000_000)),
+    ]);
+
+    // Two channels run by the same payer.
+    let mut paytube_channel = in_memory_channel(payer.insecure_clone(), &[&alice], &base_chain);
+    let mut other_paytube_channel = in_memory_channel(payer, &[&alice], &base_chain);
+    let channel_id = paytube_channel.id();
+    assert_ne!(channel_id, other_paytube_channel.id());
+
+    // Alice -> Bob 2_000_000
+    let transfer =
+        PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 2_000_000, None).sign(&alice);
+
+    let report = paytube_channel
+        .process_paytube_transfers(&[transfer.clone()])
+        .unwrap();
+    assert!(report.receipts[0].is_success());
+
+    // Bob replays Alice's transfer into the other channel, which has never
+    // seen her nonce.
+    let report = other_paytube_channel
+        .process_paytube_transfers(&[transfer])
+        .unwrap();
+    assert_eq!(
+        report.receipts[0].status,
+        TransactionStatus::Rejected(PayTubeTransactionError::WrongChannel {
+            channel: channel_id,
+        })
+    );
+
+    other_paytube_channel.close().unwrap();
+    paytube_channel.close().unwrap();
+
+    assert_eq!(base_chain.get_balance(&alice_pubkey), 8_000_000);
+    assert_eq!(base_chain.get_balance(&bob_pubkey), 12_000_000);
+}
+
+#[test]
+fn test_in_memory_batched_shared_recipient() {
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let carol = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+    let alice_pubkey = alice.pubkey();
+    let bob_pubkey = bob.pubkey();
+    let carol_pubkey = carol.pubkey();
+
+    let base_chain = in_memory_base_chain([
+        (payer_pubkey, system_account(10_000_000)),
+        (alice_pubkey, system_account(10_000_000)),
+        (bob_pubkey, system_account(10_000_000)),
+        (carol_pubkey, system_account(10_000_000)),
+    ]);
+
+    let mut paytube_channel = in_memory_channel(payer, &[&alice, &bob, &carol], &base_chain)
+        .with_processing_mode(ProcessingMode::Batched);
+    let channel_id = paytube_channel.id();
+
+    // Both payers credit Bob in the same batch.
+    let report = paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 2_000_000
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 2_000_000, None)
+                .sign(&alice),
+            // Carol -> Bob 3_000_000
+            PayTubeTransaction::new(channel_id, carol_pubkey, bob_pubkey, 3_000_000, None)
+                .sign(&carol),
+        ])
+        .unwrap();
+    assert!(report.receipts.iter().all(|receipt| receipt.is_success()));
+
+    // Bob can spend both credits, so neither was lost from his balance in
+    // the channel.
+    let report = paytube_channel
+        .process_paytube_transfers(&[
+            // Bob -> Alice 14_000_000
+            PayTubeTransaction::new(channel_id, bob_pubkey, alice_pubkey, 14_000_000, None)
+                .sign(&bob),
+        ])
+        .unwrap();
+    assert!(report.receipts[0].is_success());
+
+    paytube_channel.close().unwrap();
+
+    // Ledger:
+    // Alice:   10_000_000 - 2_000_000 + 14_000_000             = 22_000_000
+    // Bob:     10_000_000 + 2_000_000 + 3_000_000 - 14_000_000 = 1_000_000
+    // Carol:   10_000_000 - 3_000_000                          = 7_000_000
+    assert_eq!(base_chain.get_balance(&alice_pubkey), 22_000_000);
+    assert_eq!(base_chain.get_balance(&bob_pubkey), 1_000_000);
+    assert_eq!(base_chain.get_balance(&carol_pubkey), 7_000_000);
+}
+
+#[test]
+fn test_in_memory_account_source_failure_mid_batch() {
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let carol = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+    let alice_pubkey = alice.pubkey();
+    let bob_pubkey = bob.pubkey();
+    let carol_pubkey = carol.pubkey();
+
+    let base_chain = in_memory_base_chain([
+        (payer_pubkey, system_account(10_000_000)),
+        (alice_pubkey, system_account(10_000_000)),
+        (bob_pubkey, system_account(10_000_000)),
+        (carol_pubkey, system_account(10_000_000)),
+    ]);
+
+    // Carol's account can't be loaded until the source recovers.
+    let failing = Arc::new(AtomicBool::new(true));
+    let mut paytube_channel = PayTubeChannel::new_with_backends(
+        payer_pubkey,
+        Box::new(LocalSigners::from(vec![payer, alice.insecure_clone()])),
+        Box::new(FailingAccountSource {
+            base_chain: base_chain.clone(),
+            pubkey: carol_pubkey,
+            failing: failing.clone(),
+        }),
+        Box::new(base_chain.clone()),
+    )
+    .unwrap();
+    let channel_id = paytube_channel.id();
+
+    // Alice -> Carol 3_000_000
+    let transfer_to_carol =
+        PayTubeTransaction::new(channel_id, alice_pubkey, carol_pubkey, 3_000_000, None)
+            .with_nonce(1)
+            .sign(&alice);
+
+    // Both transfers are from Alice, so they're executed one after the other.
+    let report = paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 2_000_000
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 2_000_000, None)
+                .sign(&alice),
+            transfer_to_carol.clone(),
+        ])
+        .unwrap();
+
+    // The first transfer is committed, and the second is rejected.
+    assert!(report.receipts[0].is_success());
+    assert!(matches!(
+        report.receipts[1].status,
+        TransactionStatus::Rejected(PayTubeTransactionError::AccountsUnavailable { .. })
+    ));
+
+    // Its nonce wasn't consumed, so it can be submitted again once the source
+    // recovers.
+    failing.store(false, Ordering::Relaxed);
+    let report = paytube_channel
+        .process_paytube_transfers(&[transfer_to_carol])
+        .unwrap();
+    assert!(report.receipts[0].is_success());
+
+    paytube_channel.close().unwrap();
+
+    assert_eq!(base_chain.get_balance(&alice_pubkey), 5_000_000);
+    assert_eq!(base_chain.get_balance(&bob_pubkey), 12_000_000);
+    assert_eq!(base_chain.get_balance(&carol_pubkey), 13_000_000);
+}
+
+#[test]
+fn test_in_memory_spl_tokens() {
+    let mint = Pubkey::new_unique();
+
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+
+    let alice_pubkey = alice.pubkey();
+    let alice_token_account_pubkey = get_associated_token_address(&alice_pubkey, &mint);
+
+    let bob_pubkey = bob.pubkey();
+    let bob_token_account_pubkey = get_associated_token_address(&bob_pubkey, &mint);
+
+    let base_chain = in_memory_base_chain([
+        (mint, mint_account()),
+        (payer_pubkey, system_account(10_000_000)),
+        (alice_pubkey, system_account(10_000_000)),
+        (
+            alice_token_account_pubkey,
+            token_account(&alice_pubkey, &mint, 10, None),
+        ),
+        (bob_pubkey, system_account(10_000_000)),
+        (
+            bob_token_account_pubkey,
+            token_account(&bob_pubkey, &mint, 10, None),
+        ),
+    ]);
+
+    let mut paytube_channel = in_memory_channel(payer, &[&alice, &bob], &base_chain);
+    let channel_id = paytube_channel.id();
+
+    paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 7
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 7, Some(mint))
+                .sign(&alice),
+            // Alice -> Bob 7
+            // Alice only has 3 left, so this one must fail.
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 7, Some(mint))
+                .with_nonce(1)
+                .sign(&alice),
+            // Bob -> Alice 2
+            PayTubeTransaction::new(channel_id, bob_pubkey, alice_pubkey, 2, Some(mint)).sign(&bob),
+        ])
+        .unwrap();
+
+    paytube_channel.close().unwrap();
+
+    // Ledger:
+    // Alice:   10 - 7 + 2  = 5
+    // Bob:     10 + 7 - 2  = 15
+    assert_eq!(
+        get_token_amount(&base_chain.get_account(&alice_token_account_pubkey).unwrap()),
+        5
+    );
+    assert_eq!(
+        get_token_amount(&base_chain.get_account(&bob_token_account_pubkey).unwrap()),
+        15
+    );
+}
+
+#[test]
+fn test_in_memory_settlement_creates_token_accounts() {
+    let mint = Pubkey::new_unique();
+
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+
+    let alice_pubkey = alice.pubkey();
+    let alice_token_account_pubkey = get_associated_token_address(&alice_pubkey, &mint);
+
+    let bob_pubkey = bob.pubkey();
+    let bob_token_account_pubkey = get_associated_token_address(&bob_pubkey, &mint);
+
+    let base_chain = in_memory_base_chain([
+        (sysvar::rent::id(), rent_account()),
+        (mint, mint_account()),
+        (payer_pubkey, system_account(10_000_000)),
+        (alice_pubkey, system_account(10_000_000)),
+        (
+            alice_token_account_pubkey,
+            token_account(&alice_pubkey, &mint, 10, None),
+        ),
+        (bob_pubkey, system_account(10_000_000)),
+        (
+            bob_token_account_pubkey,
+            token_account(&bob_pubkey, &mint, 10, None),
+        ),
+    ]);
+
+    let mut paytube_channel = in_memory_channel(payer, &[&alice, &bob], &base_chain);
+    let channel_id = paytube_channel.id();
+
+    paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 7
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 7, Some(mint))
+                .sign(&alice),
+        ])
+        .unwrap();
+
+    // Bob closes his token account on the base chain while the channel is
+    // still open.
+    base_chain.set_account(bob_token_account_pubkey, AccountSharedData::default());
+
+    let preview = paytube_channel.preview_settlement().unwrap();
+    assert_eq!(preview.transactions.len(), 1);
+    assert_eq!(
+        preview.transactions[0].message.instructions.len(),
+        2,
+        "the transfer should be preceded by creating Bob's token account"
+    );
+
+    paytube_channel.close().unwrap();
+
+    // Bob's token account is recreated, funded by the payer, and receives his
+    // settled balance.
+    let bob_token_account = base_chain.get_account(&bob_token_account_pubkey).unwrap();
+    assert_eq!(bob_token_account.owner(), &spl_token::id());
+    assert_eq!(get_token_amount(&bob_token_account), 7);
+    assert_eq!(
+        get_token_amount(&base_chain.get_account(&alice_token_account_pubkey).unwrap()),
+        3
+    );
+    assert!(base_chain.get_balance(&payer_pubkey) < 10_000_000 - bob_token_account.lamports());
+}
+
+#[test]
+fn test_in_memory_pays_recipient_without_token_account() {
+    let mint = Pubkey::new_unique();
+
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+
+    let alice_pubkey = alice.pubkey();
+    let alice_token_account_pubkey = get_associated_token_address(&alice_pubkey, &mint);
+
+    // Will has never had a token account for the mint, nor any SOL.
+    let will_pubkey = Pubkey::new_unique();
+    let will_token_account_pubkey = get_associated_token_address(&will_pubkey, &mint);
+
+    let base_chain = in_memory_base_chain([
+        (sysvar::rent::id(), rent_account()),
+        (mint, mint_account()),
+        (payer_pubkey, system_account(10_000_000)),
+        (alice_pubkey, system_account(10_000_000)),
+        (
+            alice_token_account_pubkey,
+            token_account(&alice_pubkey, &mint, 10, None),
+        ),
+    ]);
+
+    let mut paytube_channel = in_memory_channel(payer, &[&alice], &base_chain);
+    let channel_id = paytube_channel.id();
+
+    let report = paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Will 7
+            PayTubeTransaction::new(channel_id, alice_pubkey, will_pubkey, 7, Some(mint))
+                .sign(&alice),
+        ])
+        .unwrap();
+    assert!(report.receipts[0].is_success());
+
+    // Will's token account is created at settlement, before he's paid.
+    let preview = paytube_channel.preview_settlement().unwrap();
+    assert_eq!(preview.transactions.len(), 1);
+    assert_eq!(preview.transactions[0].message.instructions.len(), 2);
+
+    paytube_channel.close().unwrap();
+
+    // Will's token account is funded by the payer.
+    let will_token_account = base_chain.get_account(&will_token_account_pubkey).unwrap();
+    assert_eq!(will_token_account.owner(), &spl_token::id());
+    assert_eq!(get_token_amount(&will_token_account), 7);
+    assert_eq!(
+        get_token_amount(&base_chain.get_account(&alice_token_account_pubkey).unwrap()),
+        3
+    );
+    assert!(base_chain.get_balance(&payer_pubkey) < 10_000_000 - will_token_account.lamports());
+}
@@ -1,7 +1,12 @@
 mod setup;
 
 use {
-    paytube_svm::{transaction::PayTubeTransaction, PayTubeChannel},
+    paytube_svm::{
+        report::TransactionStatus,
+        signer::LocalSigners,
+        transaction::{PayTubeTransaction, PayTubeTransactionError},
+        PayTubeChannel,
+    },
     setup::{system_account, TestValidatorContext},
     solana_sdk::{signature::Keypair, signer::Signer},
 };
@@ -28,38 +33,109 @@ fn test_native_sol() {
 
     let rpc_client = test_validator.get_rpc_client();
 
-    let paytube_channel = PayTubeChannel::new(vec![payer, alice, bob, will], rpc_client);
-
-    paytube_channel.process_paytube_transfers(&[
-        // Alice -> Bob 2_000_000
-        PayTubeTransaction {
-            from: alice_pubkey,
-            to: bob_pubkey,
-            amount: 2_000_000,
-            mint: None,
-        },
-        // Bob -> Will 5_000_000
-        PayTubeTransaction {
-            from: bob_pubkey,
-            to: will_pubkey,
-            amount: 5_000_000,
-            mint: None,
-        },
-        // Alice -> Bob 2_000_000
-        PayTubeTransaction {
-            from: alice_pubkey,
-            to: bob_pubkey,
-            amount: 2_000_000,
-            mint: None,
-        },
-        // Will -> Alice 1_000_000
-        PayTubeTransaction {
-            from: will_pubkey,
-            to: alice_pubkey,
-            amount: 1_000_000,
-            mint: None,
-        },
-    ]);
+    let mut paytube_channel = PayTubeChannel::new(
+        payer.pubkey(),
+        Box::new(LocalSigners::from(vec![
+            payer,
+            alice.insecure_clone(),
+            bob.insecure_clone(),
+            will.insecure_clone(),
+        ])),
+        rpc_client,
+    )
+    .unwrap();
+    let channel_id = paytube_channel.id();
+
+    paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 2_000_000
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 2_000_000, None)
+                .sign(&alice),
+            // Bob -> Will 5_000_000
+            PayTubeTransaction::new(channel_id, bob_pubkey, will_pubkey, 5_000_000, None)
+                .sign(&bob),
+            // Alice -> Bob 2_000_000
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 2_000_000, None)
+                .with_nonce(1)
+                .sign(&alice),
+            // Will -> Alice 1_000_000
+            PayTubeTransaction::new(channel_id, will_pubkey, alice_pubkey, 1_000_000, None)
+                .sign(&will),
+        ])
+        .unwrap();
+
+    paytube_channel.close().unwrap();
+
+    // Ledger:
+    // Alice:   10_000_000 - 2_000_000 - 2_000_000 + 1_000_000  = 7_000_000
+    // Bob:     10_000_000 + 2_000_000 - 5_000_000 + 2_000_000  = 9_000_000
+    // Will:    10_000_000 + 5_000_000 - 1_000_000              = 14_000_000
+    let rpc_client = test_validator.get_rpc_client();
+    assert_eq!(rpc_client.get_balance(&alice_pubkey).unwrap(), 7_000_000);
+    assert_eq!(rpc_client.get_balance(&bob_pubkey).unwrap(), 9_000_000);
+    assert_eq!(rpc_client.get_balance(&will_pubkey).unwrap(), 14_000_000);
+}
+
+#[test]
+fn test_native_sol_across_batches() {
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let will = Keypair::new();
+
+    let alice_pubkey = alice.pubkey();
+    let bob_pubkey = bob.pubkey();
+    let will_pubkey = will.pubkey();
+
+    let accounts = vec![
+        (alice_pubkey, system_account(10_000_000)),
+        (bob_pubkey, system_account(10_000_000)),
+        (will_pubkey, system_account(10_000_000)),
+    ];
+
+    let context = TestValidatorContext::start_with_accounts(accounts);
+    let test_validator = &context.test_validator;
+    let payer = context.payer.insecure_clone();
+
+    let rpc_client = test_validator.get_rpc_client();
+
+    let mut paytube_channel = PayTubeChannel::new(
+        payer.pubkey(),
+        Box::new(LocalSigners::from(vec![
+            payer,
+            alice.insecure_clone(),
+            bob.insecure_clone(),
+            will.insecure_clone(),
+        ])),
+        rpc_client,
+    )
+    .unwrap();
+    let channel_id = paytube_channel.id();
+
+    paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 2_000_000
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 2_000_000, None)
+                .sign(&alice),
+            // Bob -> Will 5_000_000
+            PayTubeTransaction::new(channel_id, bob_pubkey, will_pubkey, 5_000_000, None)
+                .sign(&bob),
+        ])
+        .unwrap();
+
+    // The channel stays open, so a second batch builds on the first.
+    paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 2_000_000
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 2_000_000, None)
+                .with_nonce(1)
+                .sign(&alice),
+            // Will -> Alice 1_000_000
+            PayTubeTransaction::new(channel_id, will_pubkey, alice_pubkey, 1_000_000, None)
+                .sign(&will),
+        ])
+        .unwrap();
+
+    paytube_channel.close().unwrap();
 
     // Ledger:
     // Alice:   10_000_000 - 2_000_000 - 2_000_000 + 1_000_000  = 7_000_000
@@ -70,3 +146,352 @@ fn test_native_sol() {
     assert_eq!(rpc_client.get_balance(&bob_pubkey).unwrap(), 9_000_000);
     assert_eq!(rpc_client.get_balance(&will_pubkey).unwrap(), 14_000_000);
 }
+
+#[test]
+fn test_native_sol_overdraft_across_batches() {
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    let alice_pubkey = alice.pubkey();
+    let bob_pubkey = bob.pubkey();
+
+    let accounts = vec![
+        (alice_pubkey, system_account(10_000_000)),
+        (bob_pubkey, system_account(10_000_000)),
+    ];
+
+    let context = TestValidatorContext::start_with_accounts(accounts);
+    let test_validator = &context.test_validator;
+    let payer = context.payer.insecure_clone();
+
+    let rpc_client = test_validator.get_rpc_client();
+
+    let mut paytube_channel = PayTubeChannel::new(
+        payer.pubkey(),
+        Box::new(LocalSigners::from(vec![
+            payer,
+            alice.insecure_clone(),
+            bob.insecure_clone(),
+        ])),
+        rpc_client,
+    )
+    .unwrap();
+    let channel_id = paytube_channel.id();
+
+    // Alice -> Bob 6_000_000
+    paytube_channel
+        .process_paytube_transfers(&[PayTubeTransaction::new(
+            channel_id,
+            alice_pubkey,
+            bob_pubkey,
+            6_000_000,
+            None,
+        )
+        .sign(&alice)])
+        .unwrap();
+
+    // Alice -> Bob 6_000_000
+    // Alice only has 4_000_000 left in the channel, so this one must fail.
+    paytube_channel
+        .process_paytube_transfers(&[PayTubeTransaction::new(
+            channel_id,
+            alice_pubkey,
+            bob_pubkey,
+            6_000_000,
+            None,
+        )
+        .with_nonce(1)
+        .sign(&alice)])
+        .unwrap();
+
+    paytube_channel.close().unwrap();
+
+    // Ledger:
+    // Alice:   10_000_000 - 6_000_000  = 4_000_000
+    // Bob:     10_000_000 + 6_000_000  = 16_000_000
+    let rpc_client = test_validator.get_rpc_client();
+    assert_eq!(rpc_client.get_balance(&alice_pubkey).unwrap(), 4_000_000);
+    assert_eq!(rpc_client.get_balance(&bob_pubkey).unwrap(), 16_000_000);
+}
+
+#[test]
+fn test_native_sol_overdraft_within_batch() {
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let will = Keypair::new();
+
+    let alice_pubkey = alice.pubkey();
+    let bob_pubkey = bob.pubkey();
+    let will_pubkey = will.pubkey();
+
+    let accounts = vec![
+        (alice_pubkey, system_account(10_000_000)),
+        (bob_pubkey, system_account(10_000_000)),
+        (will_pubkey, system_account(10_000_000)),
+    ];
+
+    let context = TestValidatorContext::start_with_accounts(accounts);
+    let test_validator = &context.test_validator;
+    let payer = context.payer.insecure_clone();
+
+    let rpc_client = test_validator.get_rpc_client();
+
+    let mut paytube_channel = PayTubeChannel::new(
+        payer.pubkey(),
+        Box::new(LocalSigners::from(vec![
+            payer,
+            alice.insecure_clone(),
+            bob.insecure_clone(),
+            will.insecure_clone(),
+        ])),
+        rpc_client,
+    )
+    .unwrap();
+    let channel_id = paytube_channel.id();
+
+    let report = paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 6_000_000
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 6_000_000, None)
+                .sign(&alice),
+            // Alice -> Will 6_000_000
+            // Sees Alice's first transfer, so this one must fail.
+            PayTubeTransaction::new(channel_id, alice_pubkey, will_pubkey, 6_000_000, None)
+                .with_nonce(1)
+                .sign(&alice),
+        ])
+        .unwrap();
+
+    // The first transfer pays the fee for its one signature. The second one
+    // failed, so it was never committed and paid nothing.
+    assert!(report.receipts[0].is_success());
+    assert_eq!(report.receipts[0].fee, 5_000);
+    assert!(matches!(
+        report.receipts[1].status,
+        TransactionStatus::Failed(_)
+    ));
+    assert_eq!(report.receipts[1].fee, 0);
+
+    paytube_channel.close().unwrap();
+
+    // Ledger:
+    // Alice:   10_000_000 - 6_000_000  = 4_000_000
+    // Bob:     10_000_000 + 6_000_000  = 16_000_000
+    // Will:    10_000_000              = 10_000_000
+    let rpc_client = test_validator.get_rpc_client();
+    assert_eq!(rpc_client.get_balance(&alice_pubkey).unwrap(), 4_000_000);
+    assert_eq!(rpc_client.get_balance(&bob_pubkey).unwrap(), 16_000_000);
+    assert_eq!(rpc_client.get_balance(&will_pubkey).unwrap(), 10_000_000);
+}
+
+#[test]
+fn test_native_sol_rejects_forged_transfer() {
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    let alice_pubkey = alice.pubkey();
+    let bob_pubkey = bob.pubkey();
+
+    let accounts = vec![
+        (alice_pubkey, system_account(10_000_000)),
+        (bob_pubkey, system_account(10_000_000)),
+    ];
+
+    let context = TestValidatorContext::start_with_accounts(accounts);
+    let test_validator = &context.test_validator;
+    let payer = context.payer.insecure_clone();
+
+    let rpc_client = test_validator.get_rpc_client();
+
+    let mut paytube_channel = PayTubeChannel::new(
+        payer.pubkey(),
+        Box::new(LocalSigners::from(vec![
+            payer,
+            alice.insecure_clone(),
+            bob.insecure_clone(),
+        ])),
+        rpc_client,
+    )
+    .unwrap();
+    let channel_id = paytube_channel.id();
+
+    let report = paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 2_000_000
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 2_000_000, None)
+                .sign(&alice),
+            // Alice -> Bob 5_000_000, but signed by Bob.
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 5_000_000, None)
+                .with_nonce(1)
+                .sign(&bob),
+        ])
+        .unwrap();
+
+    assert!(report.receipts[0].is_success());
+    assert_eq!(
+        report.receipts[1].status,
+        TransactionStatus::Rejected(PayTubeTransactionError::InvalidSignature),
+    );
+
+    paytube_channel.close().unwrap();
+
+    // Ledger:
+    // Alice:   10_000_000 - 2_000_000  = 8_000_000
+    // Bob:     10_000_000 + 2_000_000  = 12_000_000
+    let rpc_client = test_validator.get_rpc_client();
+    assert_eq!(rpc_client.get_balance(&alice_pubkey).unwrap(), 8_000_000);
+    assert_eq!(rpc_client.get_balance(&bob_pubkey).unwrap(), 12_000_000);
+}
+
+#[test]
+fn test_native_sol_rejects_replayed_transfer() {
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    let alice_pubkey = alice.pubkey();
+    let bob_pubkey = bob.pubkey();
+
+    let accounts = vec![
+        (alice_pubkey, system_account(10_000_000)),
+        (bob_pubkey, system_account(10_000_000)),
+    ];
+
+    let context = TestValidatorContext::start_with_accounts(accounts);
+    let test_validator = &context.test_validator;
+    let payer = context.payer.insecure_clone();
+
+    let rpc_client = test_validator.get_rpc_client();
+
+    let mut paytube_channel = PayTubeChannel::new(
+        payer.pubkey(),
+        Box::new(LocalSigners::from(vec![
+            payer,
+            alice.insecure_clone(),
+            bob.insecure_clone(),
+        ])),
+        rpc_client,
+    )
+    .unwrap();
+    let channel_id = paytube_channel.id();
+
+    // Alice -> Bob 2_000_000
+    let transfer = PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 2_000_000, None)
+        .with_nonce(5)
+        .sign(&alice);
+
+    assert!(paytube_channel
+        .process_paytube_transfers(&[transfer.clone()])
+        .unwrap()
+        .receipts[0]
+        .is_success());
+
+    let report = paytube_channel
+        .process_paytube_transfers(&[
+            // The same transfer again.
+            transfer,
+            // Alice -> Bob 1_000_000, with an older nonce.
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 1_000_000, None)
+                .with_nonce(4)
+                .sign(&alice),
+            // Alice -> Bob 1_000_000, which has already expired.
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 1_000_000, None)
+                .with_nonce(6)
+                .with_expiry(0)
+                .sign(&alice),
+        ])
+        .unwrap();
+
+    assert_eq!(
+        report
+            .receipts
+            .into_iter()
+            .map(|receipt| receipt.status)
+            .collect::<Vec<_>>(),
+        vec![
+            TransactionStatus::Rejected(PayTubeTransactionError::DuplicateNonce { nonce: 5 }),
+            TransactionStatus::Rejected(PayTubeTransactionError::StaleNonce {
+                nonce: 4,
+                last_nonce: 5,
+            }),
+            TransactionStatus::Rejected(PayTubeTransactionError::Expired { expires_at: 0 }),
+        ]
+    );
+
+    paytube_channel.close().unwrap();
+
+    // Ledger:
+    // Alice:   10_000_000 - 2_000_000  = 8_000_000
+    // Bob:     10_000_000 + 2_000_000  = 12_000_000
+    let rpc_client = test_validator.get_rpc_client();
+    assert_eq!(rpc_client.get_balance(&alice_pubkey).unwrap(), 8_000_000);
+    assert_eq!(rpc_client.get_balance(&bob_pubkey).unwrap(), 12_000_000);
+}
+
+#[test]
+fn test_native_sol_cycle_nets_to_nothing() {
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let will = Keypair::new();
+
+    let alice_pubkey = alice.pubkey();
+    let bob_pubkey = bob.pubkey();
+    let will_pubkey = will.pubkey();
+
+    let accounts = vec![
+        (alice_pubkey, system_account(10_000_000)),
+        (bob_pubkey, system_account(10_000_000)),
+        (will_pubkey, system_account(10_000_000)),
+    ];
+
+    let context = TestValidatorContext::start_with_accounts(accounts);
+    let test_validator = &context.test_validator;
+    let payer = context.payer.insecure_clone();
+    let payer_pubkey = payer.pubkey();
+
+    let rpc_client = test_validator.get_rpc_client();
+    let payer_balance = rpc_client.get_balance(&payer_pubkey).unwrap();
+
+    let mut paytube_channel = PayTubeChannel::new(
+        payer.pubkey(),
+        Box::new(LocalSigners::from(vec![
+            payer,
+            alice.insecure_clone(),
+            bob.insecure_clone(),
+            will.insecure_clone(),
+        ])),
+        rpc_client,
+    )
+    .unwrap();
+    let channel_id = paytube_channel.id();
+
+    paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 3_000_000
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 3_000_000, None)
+                .sign(&alice),
+            // Bob -> Will 3_000_000
+            PayTubeTransaction::new(channel_id, bob_pubkey, will_pubkey, 3_000_000, None)
+                .sign(&bob),
+            // Will -> Alice 3_000_000
+            PayTubeTransaction::new(channel_id, will_pubkey, alice_pubkey, 3_000_000, None)
+                .sign(&will),
+        ])
+        .unwrap();
+
+    paytube_channel.close().unwrap();
+
+    // Ledger:
+    // Alice:   10_000_000 - 3_000_000 + 3_000_000  = 10_000_000
+    // Bob:     10_000_000 + 3_000_000 - 3_000_000  = 10_000_000
+    // Will:    10_000_000 + 3_000_000 - 3_000_000  = 10_000_000
+    //
+    // Nothing needs to be settled, so the payer doesn't pay any fees.
+    let rpc_client = test_validator.get_rpc_client();
+    assert_eq!(rpc_client.get_balance(&alice_pubkey).unwrap(), 10_000_000);
+    assert_eq!(rpc_client.get_balance(&bob_pubkey).unwrap(), 10_000_000);
+    assert_eq!(rpc_client.get_balance(&will_pubkey).unwrap(), 10_000_000);
+    assert_eq!(
+        rpc_client.get_balance(&payer_pubkey).unwrap(),
+        payer_balance
+    );
+}
@@ -0,0 +1,71 @@
+mod setup;
+
+use {
+    paytube_svm::transaction::PayTubeTransaction,
+    setup::{in_memory_base_chain, in_memory_channel, system_account},
+    solana_sdk::{packet::PACKET_DATA_SIZE, signature::Keypair, signer::Signer},
+};
+
+#[test]
+fn test_settlement_packing() {
+    let payer = Keypair::new();
+    let will = Keypair::new();
+    let debtors = (0..12).map(|_| Keypair::new()).collect::<Vec<_>>();
+
+    let payer_pubkey = payer.pubkey();
+    let will_pubkey = will.pubkey();
+
+    let base_chain = in_memory_base_chain(
+        [payer_pubkey, will_pubkey]
+            .into_iter()
+            .chain(debtors.iter().map(|debtor| debtor.pubkey()))
+            .map(|pubkey| (pubkey, system_account(10_000_000))),
+    );
+
+    let signers = debtors.iter().chain([&will]).collect::<Vec<_>>();
+    let mut paytube_channel = in_memory_channel(payer, &signers, &base_chain);
+    let channel_id = paytube_channel.id();
+
+    // Every debtor pays Will, so every settlement transfer has a distinct
+    // signer.
+    paytube_channel
+        .process_paytube_transfers(
+            &debtors
+                .iter()
+                .map(|debtor| {
+                    PayTubeTransaction::new(
+                        channel_id,
+                        debtor.pubkey(),
+                        will_pubkey,
+                        1_000_000,
+                        None,
+                    )
+                    .sign(debtor)
+                })
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+
+    let preview = paytube_channel.preview_settlement().unwrap();
+
+    // Every transfer is packed, and every transaction fits in a packet.
+    assert!(preview.transactions.len() > 1);
+    assert_eq!(
+        preview
+            .transactions
+            .iter()
+            .map(|transaction| transaction.message.instructions.len())
+            .sum::<usize>(),
+        debtors.len()
+    );
+    for transaction in &preview.transactions {
+        assert!(bincode::serialized_size(transaction).unwrap() <= PACKET_DATA_SIZE as u64);
+    }
+
+    paytube_channel.close().unwrap();
+
+    for debtor in &debtors {
+        assert_eq!(base_chain.get_balance(&debtor.pubkey()), 9_000_000);
+    }
+    assert_eq!(base_chain.get_balance(&will_pubkey), 22_000_000);
+}
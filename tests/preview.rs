@@ -0,0 +1,73 @@
+mod setup;
+
+use {
+    paytube_svm::{transaction::PayTubeTransaction, NetDelta},
+    setup::{in_memory_base_chain, in_memory_channel, system_account},
+    solana_sdk::{signature::Keypair, signer::Signer},
+};
+
+#[test]
+fn test_preview_settlement() {
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+    let alice_pubkey = alice.pubkey();
+    let bob_pubkey = bob.pubkey();
+
+    let base_chain = in_memory_base_chain([
+        (payer_pubkey, system_account(10_000_000)),
+        (alice_pubkey, system_account(10_000_000)),
+        (bob_pubkey, system_account(10_000_000)),
+    ]);
+
+    let mut paytube_channel = in_memory_channel(payer, &[&alice, &bob], &base_chain);
+    let channel_id = paytube_channel.id();
+
+    paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 2_000_000
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 2_000_000, None)
+                .sign(&alice),
+        ])
+        .unwrap();
+
+    let preview = paytube_channel.preview_settlement().unwrap();
+
+    // One transaction, signed by the payer and Alice.
+    assert_eq!(preview.transactions.len(), 1);
+    assert_eq!(preview.estimated_fee, 2 * 5_000);
+    assert_eq!(preview.net_deltas, {
+        let mut net_deltas = vec![
+            NetDelta {
+                mint: None,
+                owner: alice_pubkey,
+                token_account: None,
+                amount: -2_000_000,
+            },
+            NetDelta {
+                mint: None,
+                owner: bob_pubkey,
+                token_account: None,
+                amount: 2_000_000,
+            },
+        ];
+        net_deltas.sort_by_key(|net_delta| net_delta.owner);
+        net_deltas
+    });
+
+    // Nothing was sent.
+    assert_eq!(base_chain.get_balance(&alice_pubkey), 10_000_000);
+    assert_eq!(base_chain.get_balance(&bob_pubkey), 10_000_000);
+    assert_eq!(base_chain.get_balance(&payer_pubkey), 10_000_000);
+
+    paytube_channel.close().unwrap();
+
+    assert_eq!(base_chain.get_balance(&alice_pubkey), 8_000_000);
+    assert_eq!(base_chain.get_balance(&bob_pubkey), 12_000_000);
+    assert_eq!(
+        base_chain.get_balance(&payer_pubkey),
+        10_000_000 - preview.estimated_fee
+    );
+}
@@ -0,0 +1,568 @@
+mod setup;
+
+use {
+    paytube_svm::{
+        error::PayTubeError,
+        harness::InMemoryBaseChain,
+        journal::{JournalStatus, SettlementJournal},
+        signer::LocalSigners,
+        sink::{SettlementSink, SimulationOutcome},
+        transaction::PayTubeTransaction,
+        PayTubeChannel,
+    },
+    setup::{get_token_amount, in_memory_base_chain, mint_account, system_account, token_account},
+    solana_sdk::{
+        account::ReadableAccount,
+        hash::Hash,
+        program_option::COption,
+        program_pack::Pack,
+        pubkey::{new_rand, Pubkey},
+        signature::{Keypair, Signature},
+        signer::Signer,
+        transaction::{Transaction as SolanaTransaction, TransactionError},
+    },
+    spl_associated_token_account::get_associated_token_address,
+    spl_token::state::Account as TokenAccount,
+    std::{cell::Cell, path::PathBuf},
+};
+
+/// A sink which loses its connection on the first submission, either before
+/// or after the transaction reaches the base chain.
+struct FlakySettlementSink {
+    base_chain: InMemoryBaseChain,
+    land_before_failing: bool,
+    failed: Cell<bool>,
+}
+
+impl SettlementSink for FlakySettlementSink {
+    fn get_latest_blockhash(&self) -> Result<Hash, PayTubeError> {
+        self.base_chain.get_latest_blockhash()
+    }
+
+    fn simulate_transaction(
+        &self,
+        transaction: &SolanaTransaction,
+    ) -> Result<SimulationOutcome, PayTubeError> {
+        self.base_chain.simulate_transaction(transaction)
+    }
+
+    fn submit_transaction(
+        &self,
+        transaction: &SolanaTransaction,
+    ) -> Result<Signature, PayTubeError> {
+        if self.failed.replace(true) {
+            return self.base_chain.submit_transaction(transaction);
+        }
+        if self.land_before_failing {
+            self.base_chain.submit_transaction(transaction)?;
+        }
+        Err(PayTubeError::Settlement("connection lost".to_string()))
+    }
+
+    fn get_transaction_status(
+        &self,
+        signature: &Signature,
+    ) -> Result<Option<Result<(), TransactionError>>, PayTubeError> {
+        self.base_chain.get_transaction_status(signature)
+    }
+
+    fn is_blockhash_valid(&self, blockhash: &Hash) -> Result<bool, PayTubeError> {
+        self.base_chain.is_blockhash_valid(blockhash)
+    }
+
+    fn confirm_transaction(
+        &self,
+        signature: &Signature,
+        recent_blockhash: &Hash,
+    ) -> Result<(), PayTubeError> {
+        self.base_chain
+            .confirm_transaction(signature, recent_blockhash)
+    }
+}
+
+/// A sink reporting that every transaction already landed on the base chain,
+/// but failed.
+struct FailedSettlementSink {
+    base_chain: InMemoryBaseChain,
+}
+
+impl SettlementSink for FailedSettlementSink {
+    fn get_latest_blockhash(&self) -> Result<Hash, PayTubeError> {
+        self.base_chain.get_latest_blockhash()
+    }
+
+    fn simulate_transaction(
+        &self,
+        transaction: &SolanaTransaction,
+    ) -> Result<SimulationOutcome, PayTubeError> {
+        self.base_chain.simulate_transaction(transaction)
+    }
+
+    fn submit_transaction(
+        &self,
+        transaction: &SolanaTransaction,
+    ) -> Result<Signature, PayTubeError> {
+        self.base_chain.submit_transaction(transaction)
+    }
+
+    fn get_transaction_status(
+        &self,
+        _signature: &Signature,
+    ) -> Result<Option<Result<(), TransactionError>>, PayTubeError> {
+        Ok(Some(Err(TransactionError::InsufficientFundsForFee)))
+    }
+}
+
+/// A sink on which a previously dropped transaction lands just as its
+/// blockhash expires.
+struct ExpiringSettlementSink {
+    base_chain: InMemoryBaseChain,
+    dropped: SolanaTransaction,
+}
+
+impl SettlementSink for ExpiringSettlementSink {
+    fn get_latest_blockhash(&self) -> Result<Hash, PayTubeError> {
+        Ok(Hash::new_unique())
+    }
+
+    fn simulate_transaction(
+        &self,
+        transaction: &SolanaTransaction,
+    ) -> Result<SimulationOutcome, PayTubeError> {
+        self.base_chain.simulate_transaction(transaction)
+    }
+
+    fn submit_transaction(
+        &self,
+        transaction: &SolanaTransaction,
+    ) -> Result<Signature, PayTubeError> {
+        self.base_chain.submit_transaction(transaction)
+    }
+
+    fn get_transaction_status(
+        &self,
+        signature: &Signature,
+    ) -> Result<Option<Result<(), TransactionError>>, PayTubeError> {
+        self.base_chain.get_transaction_status(signature)
+    }
+
+    fn is_blockhash_valid(&self, _blockhash: &Hash) -> Result<bool, PayTubeError> {
+        self.base_chain.submit_transaction(&self.dropped)?;
+        Ok(false)
+    }
+}
+
+/// Settle Alice -> Bob 2_000_000 through a flaky sink, which fails partway
+/// through.
+fn interrupted_settlement(
+    land_before_failing: bool,
+) -> (InMemoryBaseChain, Vec<Keypair>, Pubkey, Pubkey, PathBuf) {
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    let alice_pubkey = alice.pubkey();
+    let bob_pubkey = bob.pubkey();
+
+    let base_chain = in_memory_base_chain([
+        (payer.pubkey(), system_account(10_000_000)),
+        (alice_pubkey, system_account(10_000_000)),
+        (bob_pubkey, system_account(10_000_000)),
+    ]);
+
+    let path = std::env::temp_dir().join(format!("paytube-journal-{}.bin", new_rand()));
+    let keys = vec![payer, alice, bob];
+
+    let mut paytube_channel = PayTubeChannel::new_with_backends(
+        keys[0].pubkey(),
+        Box::new(LocalSigners::from(
+            keys.iter()
+                .map(|key| key.insecure_clone())
+                .collect::<Vec<_>>(),
+        )),
+        Box::new(base_chain.clone()),
+        Box::new(FlakySettlementSink {
+            base_chain: base_chain.clone(),
+            land_before_failing,
+            failed: Cell::new(false),
+        }),
+    )
+    .unwrap()
+    .with_settlement_journal(&path);
+    let channel_id = paytube_channel.id();
+
+    paytube_channel
+        .process_paytube_transfers(&[PayTubeTransaction::new(
+            channel_id,
+            alice_pubkey,
+            bob_pubkey,
+            2_000_000,
+            None,
+        )
+        .sign(&keys[1])])
+        .unwrap();
+
+    assert!(paytube_channel.close().is_err());
+
+    // The transaction was journaled, but never confirmed.
+    let journal = SettlementJournal::open(&path).unwrap();
+    assert_eq!(journal.entries().len(), 1);
+    assert_eq!(journal.entries()[0].status, JournalStatus::Submitted);
+
+    (base_chain, keys, alice_pubkey, bob_pubkey, path)
+}
+
+#[test]
+fn test_resume_settlement_after_transaction_landed() {
+    let (base_chain, keys, alice_pubkey, bob_pubkey, path) = interrupted_settlement(true);
+
+    assert_eq!(base_chain.get_balance(&alice_pubkey), 8_000_000);
+    assert_eq!(base_chain.get_balance(&bob_pubkey), 12_000_000);
+
+    // A fresh channel picks up the journal, and sees the transaction landed.
+    PayTubeChannel::new_with_backends(
+        keys[0].pubkey(),
+        Box::new(LocalSigners::from(keys)),
+        Box::new(base_chain.clone()),
+        Box::new(base_chain.clone()),
+    )
+    .unwrap()
+    .with_settlement_journal(&path)
+    .resume_settlement()
+    .unwrap();
+
+    // Nothing was sent twice.
+    assert_eq!(base_chain.get_balance(&alice_pubkey), 8_000_000);
+    assert_eq!(base_chain.get_balance(&bob_pubkey), 12_000_000);
+    assert!(SettlementJournal::open(&path).unwrap().is_complete());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_resume_settlement_after_transaction_dropped() {
+    let (base_chain, keys, alice_pubkey, bob_pubkey, path) = interrupted_settlement(false);
+
+    assert_eq!(base_chain.get_balance(&alice_pubkey), 10_000_000);
+    assert_eq!(base_chain.get_balance(&bob_pubkey), 10_000_000);
+
+    // A fresh channel picks up the journal, and resends the transaction,
+    // without any transfers being submitted to it again.
+    PayTubeChannel::new_with_backends(
+        keys[0].pubkey(),
+        Box::new(LocalSigners::from(keys)),
+        Box::new(base_chain.clone()),
+        Box::new(base_chain.clone()),
+    )
+    .unwrap()
+    .with_settlement_journal(&path)
+    .resume_settlement()
+    .unwrap();
+
+    assert_eq!(base_chain.get_balance(&alice_pubkey), 8_000_000);
+    assert_eq!(base_chain.get_balance(&bob_pubkey), 12_000_000);
+    assert!(SettlementJournal::open(&path).unwrap().is_complete());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_resume_settlement_in_same_process() {
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    let alice_pubkey = alice.pubkey();
+    let bob_pubkey = bob.pubkey();
+
+    let base_chain = in_memory_base_chain([
+        (payer.pubkey(), system_account(10_000_000)),
+        (alice_pubkey, system_account(10_000_000)),
+        (bob_pubkey, system_account(10_000_000)),
+    ]);
+
+    // The channel has no journal file, so the settlement in progress is only
+    // held by the channel itself.
+    let mut paytube_channel = PayTubeChannel::new_with_backends(
+        payer.pubkey(),
+        Box::new(LocalSigners::from(vec![payer, alice.insecure_clone()])),
+        Box::new(base_chain.clone()),
+        Box::new(FlakySettlementSink {
+            base_chain: base_chain.clone(),
+            land_before_failing: true,
+            failed: Cell::new(false),
+        }),
+    )
+    .unwrap();
+    let channel_id = paytube_channel.id();
+
+    let transfer =
+        PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 2_000_000, None).sign(&alice);
+    paytube_channel
+        .process_paytube_transfers(&[transfer.clone()])
+        .unwrap();
+
+    assert!(paytube_channel.close().is_err());
+
+    // Once closing has started, the channel refuses any more transfers.
+    assert!(matches!(
+        paytube_channel.process_paytube_transfers(&[PayTubeTransaction::new(
+            channel_id,
+            alice_pubkey,
+            bob_pubkey,
+            1_000_000,
+            None,
+        )
+        .with_nonce(1)
+        .sign(&alice)]),
+        Err(PayTubeError::ChannelClosed)
+    ));
+
+    // The same channel resumes, and sees the transaction landed.
+    paytube_channel.resume_settlement().unwrap();
+
+    // Nothing was sent twice.
+    assert_eq!(base_chain.get_balance(&alice_pubkey), 8_000_000);
+    assert_eq!(base_chain.get_balance(&bob_pubkey), 12_000_000);
+}
+
+#[test]
+fn test_resume_settlement_revokes_delegations() {
+    let mint = Pubkey::new_unique();
+
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+
+    let alice_pubkey = alice.pubkey();
+    let alice_token_account_pubkey = get_associated_token_address(&alice_pubkey, &mint);
+
+    let bob_pubkey = Pubkey::new_unique();
+    let bob_token_account_pubkey = get_associated_token_address(&bob_pubkey, &mint);
+
+    let base_chain = in_memory_base_chain([
+        (mint, mint_account()),
+        (payer_pubkey, system_account(10_000_000)),
+        (alice_pubkey, system_account(10_000_000)),
+        (
+            alice_token_account_pubkey,
+            token_account(&alice_pubkey, &mint, 10, Some((&payer_pubkey, 10))),
+        ),
+        (
+            bob_token_account_pubkey,
+            token_account(&bob_pubkey, &mint, 10, None),
+        ),
+    ]);
+
+    let path = std::env::temp_dir().join(format!("paytube-journal-{}.bin", new_rand()));
+    let keys = vec![payer, alice];
+
+    let mut paytube_channel = PayTubeChannel::new_with_backends(
+        payer_pubkey,
+        Box::new(LocalSigners::from(
+            keys.iter()
+                .map(|key| key.insecure_clone())
+                .collect::<Vec<_>>(),
+        )),
+        Box::new(base_chain.clone()),
+        Box::new(FlakySettlementSink {
+            base_chain: base_chain.clone(),
+            land_before_failing: false,
+            failed: Cell::new(false),
+        }),
+    )
+    .unwrap()
+    .with_settlement_journal(&path);
+    let channel_id = paytube_channel.id();
+
+    paytube_channel
+        .register_token_account(&alice_pubkey, &mint)
+        .unwrap();
+    paytube_channel
+        .process_paytube_transfers(&[PayTubeTransaction::new(
+            channel_id,
+            alice_pubkey,
+            bob_pubkey,
+            7,
+            Some(mint),
+        )
+        .sign(&keys[1])])
+        .unwrap();
+
+    assert!(paytube_channel.close().is_err());
+
+    // The revocation was journaled after the settlement transfer.
+    assert_eq!(SettlementJournal::open(&path).unwrap().entries().len(), 2);
+
+    // A fresh channel, which never registered Alice's token account, still
+    // revokes her delegation once the settlement lands.
+    PayTubeChannel::new_with_backends(
+        payer_pubkey,
+        Box::new(LocalSigners::from(keys)),
+        Box::new(base_chain.clone()),
+        Box::new(base_chain.clone()),
+    )
+    .unwrap()
+    .with_settlement_journal(&path)
+    .resume_settlement()
+    .unwrap();
+
+    let alice_token_account = base_chain.get_account(&alice_token_account_pubkey).unwrap();
+    assert_eq!(get_token_amount(&alice_token_account), 3);
+    assert_eq!(
+        get_token_amount(&base_chain.get_account(&bob_token_account_pubkey).unwrap()),
+        17
+    );
+    let alice_token_account = TokenAccount::unpack(alice_token_account.data()).unwrap();
+    assert_eq!(alice_token_account.delegate, COption::None);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_resume_settlement_after_transaction_landed_late() {
+    let (base_chain, keys, alice_pubkey, bob_pubkey, path) = interrupted_settlement(false);
+    let dropped = SettlementJournal::open(&path).unwrap().entries()[0]
+        .transaction
+        .clone();
+
+    // The dropped transaction lands while resuming, so it's never re-signed.
+    PayTubeChannel::new_with_backends(
+        keys[0].pubkey(),
+        Box::new(LocalSigners::from(keys)),
+        Box::new(base_chain.clone()),
+        Box::new(ExpiringSettlementSink {
+            base_chain: base_chain.clone(),
+            dropped,
+        }),
+    )
+    .unwrap()
+    .with_settlement_journal(&path)
+    .resume_settlement()
+    .unwrap();
+
+    // Nothing was sent twice.
+    assert_eq!(base_chain.get_balance(&alice_pubkey), 8_000_000);
+    assert_eq!(base_chain.get_balance(&bob_pubkey), 12_000_000);
+    assert!(SettlementJournal::open(&path).unwrap().is_complete());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_close_rejects_journal_for_another_ledger() {
+    let (base_chain, keys, alice_pubkey, bob_pubkey, path) = interrupted_settlement(false);
+
+    // A channel with a different ledger refuses to resume the journal.
+    let mut paytube_channel = PayTubeChannel::new_with_backends(
+        keys[0].pubkey(),
+        Box::new(LocalSigners::from(
+            keys.iter()
+                .map(|key| key.insecure_clone())
+                .collect::<Vec<_>>(),
+        )),
+        Box::new(base_chain.clone()),
+        Box::new(base_chain.clone()),
+    )
+    .unwrap()
+    .with_settlement_journal(&path);
+    let channel_id = paytube_channel.id();
+
+    paytube_channel
+        .process_paytube_transfers(&[PayTubeTransaction::new(
+            channel_id,
+            bob_pubkey,
+            alice_pubkey,
+            1_000_000,
+            None,
+        )
+        .sign(&keys[2])])
+        .unwrap();
+
+    assert!(matches!(
+        paytube_channel.close(),
+        Err(PayTubeError::Settlement(_))
+    ));
+
+    // Nothing was sent.
+    assert_eq!(base_chain.get_balance(&alice_pubkey), 10_000_000);
+    assert_eq!(base_chain.get_balance(&bob_pubkey), 10_000_000);
+    assert!(!SettlementJournal::open(&path).unwrap().is_complete());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_resume_settlement_after_transaction_failed() {
+    let (base_chain, keys, alice_pubkey, bob_pubkey, path) = interrupted_settlement(false);
+
+    // The transaction landed but failed, so it's reported rather than resent.
+    let result = PayTubeChannel::new_with_backends(
+        keys[0].pubkey(),
+        Box::new(LocalSigners::from(keys)),
+        Box::new(base_chain.clone()),
+        Box::new(FailedSettlementSink {
+            base_chain: base_chain.clone(),
+        }),
+    )
+    .unwrap()
+    .with_settlement_journal(&path)
+    .resume_settlement();
+
+    assert!(matches!(result, Err(PayTubeError::Settlement(_))));
+    assert_eq!(base_chain.get_balance(&alice_pubkey), 10_000_000);
+    assert_eq!(base_chain.get_balance(&bob_pubkey), 10_000_000);
+    assert!(!SettlementJournal::open(&path).unwrap().is_complete());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_settlement_transaction_failed() {
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    let alice_pubkey = alice.pubkey();
+    let bob_pubkey = bob.pubkey();
+
+    let base_chain = in_memory_base_chain([
+        (payer.pubkey(), system_account(10_000_000)),
+        (alice_pubkey, system_account(10_000_000)),
+        (bob_pubkey, system_account(10_000_000)),
+    ]);
+
+    let path = std::env::temp_dir().join(format!("paytube-journal-{}.bin", new_rand()));
+
+    let mut paytube_channel = PayTubeChannel::new_with_backends(
+        payer.pubkey(),
+        Box::new(LocalSigners::from(vec![
+            payer,
+            alice.insecure_clone(),
+            bob.insecure_clone(),
+        ])),
+        Box::new(base_chain.clone()),
+        Box::new(FailedSettlementSink {
+            base_chain: base_chain.clone(),
+        }),
+    )
+    .unwrap()
+    .with_settlement_journal(&path);
+    let channel_id = paytube_channel.id();
+
+    paytube_channel
+        .process_paytube_transfers(&[PayTubeTransaction::new(
+            channel_id,
+            alice_pubkey,
+            bob_pubkey,
+            2_000_000,
+            None,
+        )
+        .sign(&alice)])
+        .unwrap();
+
+    // The sink reports the transaction as failed, so it's never confirmed.
+    assert!(matches!(
+        paytube_channel.close(),
+        Err(PayTubeError::Settlement(_))
+    ));
+    let journal = SettlementJournal::open(&path).unwrap();
+    assert_eq!(journal.entries()[0].status, JournalStatus::Submitted);
+    assert!(!journal.is_complete());
+    std::fs::remove_file(&path).unwrap();
+}
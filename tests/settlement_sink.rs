@@ -0,0 +1,156 @@
+mod setup;
+
+use {
+    base64::{engine::general_purpose::STANDARD as BASE64_STANDARD, Engine},
+    paytube_svm::{
+        signer::LocalSigners, sink::FileSettlementSink, transaction::PayTubeTransaction,
+        PayTubeChannel,
+    },
+    setup::{in_memory_base_chain, system_account},
+    solana_sdk::{
+        hash::Hash,
+        pubkey::new_rand,
+        signature::{Keypair, Signature},
+        signer::Signer,
+        system_instruction,
+        transaction::Transaction as SolanaTransaction,
+    },
+};
+
+#[test]
+fn test_file_settlement_sink() {
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+    let alice_pubkey = alice.pubkey();
+    let bob_pubkey = bob.pubkey();
+
+    let base_chain = in_memory_base_chain([
+        (payer_pubkey, system_account(10_000_000)),
+        (alice_pubkey, system_account(10_000_000)),
+        (bob_pubkey, system_account(10_000_000)),
+    ]);
+
+    let path = std::env::temp_dir().join(format!("paytube-settlement-{}.txt", new_rand()));
+    let recent_blockhash = Hash::new_unique();
+    let settlement_sink = FileSettlementSink::new(&path, recent_blockhash).unwrap();
+
+    let mut paytube_channel = PayTubeChannel::new_with_backends(
+        payer.pubkey(),
+        Box::new(LocalSigners::from(vec![
+            payer,
+            alice.insecure_clone(),
+            bob.insecure_clone(),
+        ])),
+        Box::new(base_chain.clone()),
+        Box::new(settlement_sink),
+    )
+    .unwrap();
+    let channel_id = paytube_channel.id();
+
+    paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 2_000_000
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 2_000_000, None)
+                .sign(&alice),
+        ])
+        .unwrap();
+
+    paytube_channel.close().unwrap();
+
+    // Nothing was broadcast.
+    assert_eq!(base_chain.get_balance(&alice_pubkey), 10_000_000);
+    assert_eq!(base_chain.get_balance(&bob_pubkey), 10_000_000);
+
+    // The would-be settlement transaction was written to disk instead.
+    let contents = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let transactions = contents
+        .lines()
+        .map(|line| {
+            bincode::deserialize::<SolanaTransaction>(&BASE64_STANDARD.decode(line).unwrap())
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(transactions.len(), 1);
+
+    let transaction = &transactions[0];
+    assert!(transaction.verify().is_ok());
+    assert_eq!(transaction.message.recent_blockhash, recent_blockhash);
+    assert_eq!(
+        transaction.message.instructions[0].data,
+        system_instruction::transfer(&alice_pubkey, &bob_pubkey, 2_000_000).data,
+    );
+}
+
+#[test]
+fn test_file_settlement_sink_partial_signing() {
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+    let alice_pubkey = alice.pubkey();
+    let bob_pubkey = bob.pubkey();
+
+    let base_chain = in_memory_base_chain([
+        (payer_pubkey, system_account(10_000_000)),
+        (alice_pubkey, system_account(10_000_000)),
+        (bob_pubkey, system_account(10_000_000)),
+    ]);
+
+    let path = std::env::temp_dir().join(format!("paytube-settlement-{}.txt", new_rand()));
+    let recent_blockhash = Hash::new_unique();
+    let settlement_sink = FileSettlementSink::new(&path, recent_blockhash)
+        .unwrap()
+        .with_partial_signing();
+
+    // The channel only holds the payer's key.
+    let mut paytube_channel = PayTubeChannel::new_with_backends(
+        payer_pubkey,
+        Box::new(LocalSigners::from(vec![payer.insecure_clone()])),
+        Box::new(base_chain.clone()),
+        Box::new(settlement_sink),
+    )
+    .unwrap();
+    let channel_id = paytube_channel.id();
+
+    paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 2_000_000
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 2_000_000, None)
+                .sign(&alice),
+        ])
+        .unwrap();
+
+    paytube_channel.close().unwrap();
+
+    let contents = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let mut transactions = contents
+        .lines()
+        .map(|line| {
+            bincode::deserialize::<SolanaTransaction>(&BASE64_STANDARD.decode(line).unwrap())
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(transactions.len(), 1);
+
+    // The payer signed, and Alice's signature was left for her to add.
+    let transaction = &mut transactions[0];
+    let message_data = transaction.message_data();
+    assert_eq!(
+        transaction.message.account_keys[..2],
+        [payer_pubkey, alice_pubkey]
+    );
+    assert!(transaction.signatures[0].verify(payer_pubkey.as_ref(), &message_data));
+    assert_eq!(transaction.signatures[1], Signature::default());
+    assert!(transaction.verify().is_err());
+
+    transaction.partial_sign(&[&alice], recent_blockhash);
+    assert!(transaction.verify().is_ok());
+}
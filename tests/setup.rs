@@ -1,16 +1,32 @@
 #![allow(unused)]
 
 use {
+    paytube_svm::{
+        escrow::{self, Channel, Deposit},
+        harness::InMemoryBaseChain,
+        signer::LocalSigners,
+        PayTubeChannel,
+    },
     solana_sdk::{
-        account::{Account, AccountSharedData, ReadableAccount},
+        account::{
+            create_account_shared_data_for_test, Account, AccountSharedData, ReadableAccount,
+            WritableAccount,
+        },
+        clock::Clock,
         epoch_schedule::EpochSchedule,
         program_pack::Pack,
         pubkey::Pubkey,
+        rent::Rent,
         signature::Keypair,
+        signer::Signer,
         system_program,
     },
     solana_test_validator::{TestValidator, TestValidatorGenesis},
     spl_token::state::{Account as TokenAccount, Mint},
+    spl_token_2022::extension::{
+        transfer_fee::{TransferFee, TransferFeeAmount, TransferFeeConfig},
+        BaseStateWithExtensionsMut, ExtensionType, StateWithExtensions, StateWithExtensionsMut,
+    },
 };
 
 const SLOTS_PER_EPOCH: u64 = 50;
@@ -48,6 +64,52 @@ pub fn get_token_account_balance(token_account: Account) -> u64 {
     state.amount
 }
 
+/// The amount held by an SPL Token or Token-2022 account, including one with
+/// extensions.
+pub fn get_token_amount(token_account: &AccountSharedData) -> u64 {
+    let state = StateWithExtensions::<spl_token_2022::state::Account>::unpack(token_account.data())
+        .unwrap();
+    state.base.amount
+}
+
+/// The SPL program accounts (Token, Token-2022, Associated Token Account, ...)
+/// for seeding an in-memory base chain.
+pub fn spl_program_accounts() -> Vec<(Pubkey, AccountSharedData)> {
+    solana_program_test::programs::spl_programs(&Rent::default())
+}
+
+/// An in-memory base chain with the SPL programs deployed, seeded with
+/// `accounts`.
+pub fn in_memory_base_chain(
+    accounts: impl IntoIterator<Item = (Pubkey, AccountSharedData)>,
+) -> InMemoryBaseChain {
+    let base_chain = InMemoryBaseChain::new(spl_program_accounts()).unwrap();
+    for (pubkey, account) in accounts {
+        base_chain.set_account(pubkey, account);
+    }
+    base_chain
+}
+
+/// A channel which loads accounts from and settles to `base_chain`, holding
+/// the keys of the payer and `signers`.
+pub fn in_memory_channel(
+    payer: Keypair,
+    signers: &[&Keypair],
+    base_chain: &InMemoryBaseChain,
+) -> PayTubeChannel {
+    let payer_pubkey = payer.pubkey();
+    let keys = std::iter::once(payer)
+        .chain(signers.iter().map(|signer| signer.insecure_clone()))
+        .collect::<Vec<_>>();
+    PayTubeChannel::new_with_backends(
+        payer_pubkey,
+        Box::new(LocalSigners::from(keys)),
+        Box::new(base_chain.clone()),
+        Box::new(base_chain.clone()),
+    )
+    .unwrap()
+}
+
 pub fn mint_account() -> AccountSharedData {
     let data = {
         let mut data = [0; Mint::LEN];
@@ -72,7 +134,14 @@ pub fn system_account(lamports: u64) -> AccountSharedData {
     AccountSharedData::new(lamports, 0, &system_program::id())
 }
 
-pub fn token_account(owner: &Pubkey, mint: &Pubkey, amount: u64) -> AccountSharedData {
+/// An SPL Token account, with an optional delegate and the amount delegated
+/// to it.
+pub fn token_account(
+    owner: &Pubkey,
+    mint: &Pubkey,
+    amount: u64,
+    delegate: Option<(&Pubkey, u64)>,
+) -> AccountSharedData {
     let data = {
         let mut data = [0; TokenAccount::LEN];
         TokenAccount::pack(
@@ -80,6 +149,8 @@ pub fn token_account(owner: &Pubkey, mint: &Pubkey, amount: u64) -> AccountShare
                 mint: *mint,
                 owner: *owner,
                 amount,
+                delegate: delegate.map(|(delegate, _)| *delegate).into(),
+                delegated_amount: delegate.map_or(0, |(_, delegated_amount)| delegated_amount),
                 state: spl_token::state::AccountState::Initialized,
                 ..Default::default()
             },
@@ -92,3 +163,140 @@ pub fn token_account(owner: &Pubkey, mint: &Pubkey, amount: u64) -> AccountShare
     account.set_data_from_slice(&data);
     account
 }
+
+/// An escrow channel operated by `operator`, holding `num_deposits` deposits.
+pub fn escrow_channel_account(
+    operator: &Pubkey,
+    channel_id: u64,
+    num_deposits: u32,
+) -> (Pubkey, AccountSharedData) {
+    let (address, bump) = escrow::find_channel_address(operator, channel_id);
+    let data = {
+        let mut data = [0; Channel::LEN];
+        Channel::pack(
+            Channel {
+                is_initialized: true,
+                operator: *operator,
+                channel_id,
+                bump,
+                num_deposits,
+                expires_at: i64::MAX,
+            },
+            &mut data,
+        )
+        .unwrap();
+        data
+    };
+    let mut account = AccountSharedData::new(100_000_000, data.len(), &escrow::id());
+    account.set_data_from_slice(&data);
+    (address, account)
+}
+
+/// An owner's deposit of SOL, or of a mint's tokens, into an escrow channel.
+pub fn escrow_deposit_account(
+    channel: &Pubkey,
+    owner: &Pubkey,
+    mint: Option<&Pubkey>,
+    amount: u64,
+) -> (Pubkey, AccountSharedData) {
+    let data = {
+        let mut data = [0; Deposit::LEN];
+        Deposit::pack(
+            Deposit {
+                is_initialized: true,
+                channel: *channel,
+                owner: *owner,
+                mint: mint.copied(),
+                amount,
+            },
+            &mut data,
+        )
+        .unwrap();
+        data
+    };
+    let mut account = AccountSharedData::new(100_000_000, data.len(), &escrow::id());
+    account.set_data_from_slice(&data);
+    let (address, _) = escrow::find_deposit_address(channel, owner, mint);
+    (address, account)
+}
+
+/// Hand an SPL Token mint or token account over to Token-2022, which shares
+/// the same base account layouts.
+pub fn token_2022(mut account: AccountSharedData) -> AccountSharedData {
+    account.set_owner(spl_token_2022::id());
+    account
+}
+
+/// A Token-2022 mint with the `TransferFee` extension.
+pub fn transfer_fee_mint_account(
+    transfer_fee_basis_points: u16,
+    maximum_fee: u64,
+) -> AccountSharedData {
+    let space = ExtensionType::try_calculate_account_len::<spl_token_2022::state::Mint>(&[
+        ExtensionType::TransferFeeConfig,
+    ])
+    .unwrap();
+    let mut data = vec![0; space];
+    let mut state =
+        StateWithExtensionsMut::<spl_token_2022::state::Mint>::unpack_uninitialized(&mut data)
+            .unwrap();
+    let transfer_fee = TransferFee {
+        epoch: 0.into(),
+        maximum_fee: maximum_fee.into(),
+        transfer_fee_basis_points: transfer_fee_basis_points.into(),
+    };
+    *state.init_extension::<TransferFeeConfig>(true).unwrap() = TransferFeeConfig {
+        older_transfer_fee: transfer_fee,
+        newer_transfer_fee: transfer_fee,
+        ..Default::default()
+    };
+    state.base = spl_token_2022::state::Mint {
+        supply: 100_000_000,
+        decimals: 0,
+        is_initialized: true,
+        ..Default::default()
+    };
+    state.pack_base();
+    state.init_account_type().unwrap();
+
+    let mut account = AccountSharedData::new(100_000_000, data.len(), &spl_token_2022::id());
+    account.set_data_from_slice(&data);
+    account
+}
+
+/// A Token-2022 token account for a mint with the `TransferFee` extension.
+pub fn transfer_fee_token_account(owner: &Pubkey, mint: &Pubkey, amount: u64) -> AccountSharedData {
+    let space = ExtensionType::try_calculate_account_len::<spl_token_2022::state::Account>(&[
+        ExtensionType::TransferFeeAmount,
+    ])
+    .unwrap();
+    let mut data = vec![0; space];
+    let mut state =
+        StateWithExtensionsMut::<spl_token_2022::state::Account>::unpack_uninitialized(&mut data)
+            .unwrap();
+    state.init_extension::<TransferFeeAmount>(true).unwrap();
+    state.base = spl_token_2022::state::Account {
+        mint: *mint,
+        owner: *owner,
+        amount,
+        state: spl_token_2022::state::AccountState::Initialized,
+        ..Default::default()
+    };
+    state.pack_base();
+    state.init_account_type().unwrap();
+
+    let mut account = AccountSharedData::new(100_000_000, data.len(), &spl_token_2022::id());
+    account.set_data_from_slice(&data);
+    account
+}
+
+/// The clock sysvar, which Token-2022 reads the current transfer fee by.
+pub fn clock_account() -> AccountSharedData {
+    create_account_shared_data_for_test(&Clock::default())
+}
+
+/// The rent sysvar, which the Associated Token Account program reads when
+/// creating token accounts.
+pub fn rent_account() -> AccountSharedData {
+    create_account_shared_data_for_test(&Rent::default())
+}
@@ -0,0 +1,173 @@
+mod setup;
+
+use {
+    base64::{engine::general_purpose::STANDARD as BASE64_STANDARD, Engine},
+    paytube_svm::{
+        error::PayTubeError,
+        signer::{
+            serve_remote_signer, FileKeystore, InteractiveSigner, LocalSigners, RemoteSigner,
+            SignerProvider,
+        },
+        transaction::PayTubeTransaction,
+        PayTubeChannel,
+    },
+    setup::{in_memory_base_chain, in_memory_channel, system_account},
+    solana_sdk::{
+        pubkey::{new_rand, Pubkey},
+        signature::Keypair,
+        signer::Signer,
+    },
+    std::{io::Cursor, net::TcpListener, thread},
+};
+
+/// Settle Alice -> Bob 2_000_000, signing with the provided signer provider.
+fn settle_with(payer: &Keypair, alice: &Keypair, signer_provider: Box<dyn SignerProvider>) {
+    let bob = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+    let alice_pubkey = alice.pubkey();
+    let bob_pubkey = bob.pubkey();
+
+    let base_chain = in_memory_base_chain([
+        (payer_pubkey, system_account(10_000_000)),
+        (alice_pubkey, system_account(10_000_000)),
+        (bob_pubkey, system_account(10_000_000)),
+    ]);
+
+    let mut paytube_channel = PayTubeChannel::new_with_backends(
+        payer_pubkey,
+        signer_provider,
+        Box::new(base_chain.clone()),
+        Box::new(base_chain.clone()),
+    )
+    .unwrap();
+    let channel_id = paytube_channel.id();
+
+    paytube_channel
+        .process_paytube_transfers(&[PayTubeTransaction::new(
+            channel_id,
+            alice_pubkey,
+            bob_pubkey,
+            2_000_000,
+            None,
+        )
+        .sign(alice)])
+        .unwrap();
+
+    paytube_channel.close().unwrap();
+
+    assert_eq!(base_chain.get_balance(&alice_pubkey), 8_000_000);
+    assert_eq!(base_chain.get_balance(&bob_pubkey), 12_000_000);
+}
+
+#[test]
+fn test_file_keystore() {
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+
+    let path = std::env::temp_dir().join(format!("paytube-keystore-{}", new_rand()));
+    std::fs::create_dir_all(&path).unwrap();
+    let keystore = FileKeystore::new(&path);
+    keystore.insert(&payer).unwrap();
+    keystore.insert(&alice).unwrap();
+
+    // Keys missing from the keystore can't be signed for.
+    let stranger = Pubkey::new_unique();
+    assert!(matches!(
+        keystore.sign_message(&stranger, b"message"),
+        Err(PayTubeError::MissingSigner(pubkey)) if pubkey == stranger
+    ));
+
+    settle_with(&payer, &alice, Box::new(keystore));
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn test_remote_signer() {
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+
+    // A stand-in for the remote signer process, holding the keys.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let keys = vec![payer.insecure_clone(), alice.insecure_clone()];
+    let server = thread::spawn(move || {
+        let signers = LocalSigners::from(keys);
+        let (stream, _) = listener.accept().unwrap();
+        serve_remote_signer(stream, &signers).unwrap();
+    });
+
+    settle_with(
+        &payer,
+        &alice,
+        Box::new(RemoteSigner::connect(addr).unwrap()),
+    );
+
+    server.join().unwrap();
+}
+
+#[test]
+fn test_interactive_signer() {
+    let alice = Keypair::new();
+    let message = b"settlement message";
+    let signature = alice.sign_message(message);
+
+    let mut output = vec![];
+    let interactive_signer =
+        InteractiveSigner::new(Cursor::new(format!("{signature}\n\n")), &mut output);
+
+    // Alice signs the first prompt, and declines the second.
+    assert_eq!(
+        interactive_signer
+            .sign_message(&alice.pubkey(), message)
+            .unwrap(),
+        signature
+    );
+    assert!(matches!(
+        interactive_signer.sign_message(&alice.pubkey(), message),
+        Err(PayTubeError::MissingSigner(pubkey)) if pubkey == alice.pubkey()
+    ));
+
+    drop(interactive_signer);
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains(&alice.pubkey().to_string()));
+    assert!(output.contains(&BASE64_STANDARD.encode(message)));
+}
+
+#[test]
+fn test_settlement_missing_signer() {
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+    let alice_pubkey = alice.pubkey();
+    let bob_pubkey = bob.pubkey();
+
+    let base_chain = in_memory_base_chain([
+        (payer_pubkey, system_account(10_000_000)),
+        (alice_pubkey, system_account(10_000_000)),
+        (bob_pubkey, system_account(10_000_000)),
+    ]);
+
+    // Alice's key isn't given to the channel.
+    let mut paytube_channel = in_memory_channel(payer, &[&bob], &base_chain);
+    let channel_id = paytube_channel.id();
+
+    paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 2_000_000
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 2_000_000, None)
+                .sign(&alice),
+        ])
+        .unwrap();
+
+    let Err(PayTubeError::MissingSigner(pubkey)) = paytube_channel.close() else {
+        panic!("expected settlement to fail with a missing signer");
+    };
+    assert_eq!(pubkey, alice_pubkey);
+
+    // Nothing was sent.
+    assert_eq!(base_chain.get_balance(&alice_pubkey), 10_000_000);
+    assert_eq!(base_chain.get_balance(&bob_pubkey), 10_000_000);
+}
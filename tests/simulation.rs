@@ -0,0 +1,114 @@
+mod setup;
+
+use {
+    paytube_svm::{error::PayTubeError, sink::SettlementSink, transaction::PayTubeTransaction},
+    setup::{in_memory_base_chain, in_memory_channel, system_account},
+    solana_sdk::{
+        hash::Hash, pubkey::Pubkey, signature::Keypair, signer::Signer, system_instruction,
+        transaction::Transaction,
+    },
+};
+
+#[test]
+fn test_settlement_aborts_on_failed_simulation() {
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let will = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+    let alice_pubkey = alice.pubkey();
+    let bob_pubkey = bob.pubkey();
+    let will_pubkey = will.pubkey();
+
+    let base_chain = in_memory_base_chain([
+        (payer_pubkey, system_account(10_000_000)),
+        (alice_pubkey, system_account(10_000_000)),
+        (bob_pubkey, system_account(10_000_000)),
+        (will_pubkey, system_account(10_000_000)),
+    ]);
+
+    let mut paytube_channel = in_memory_channel(payer, &[&alice, &bob, &will], &base_chain);
+    let channel_id = paytube_channel.id();
+
+    paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Will 2_000_000
+            PayTubeTransaction::new(channel_id, alice_pubkey, will_pubkey, 2_000_000, None)
+                .sign(&alice),
+            // Bob -> Will 3_000_000
+            PayTubeTransaction::new(channel_id, bob_pubkey, will_pubkey, 3_000_000, None)
+                .sign(&bob),
+        ])
+        .unwrap();
+
+    // Alice spends most of her SOL on the base chain while the channel is
+    // open, so she can no longer cover her debt.
+    base_chain.set_account(alice_pubkey, system_account(1_000_000));
+
+    let Err(PayTubeError::SimulationFailed(report)) = paytube_channel.close() else {
+        panic!("expected settlement simulation to fail");
+    };
+    assert_eq!(report.failures.len(), 1);
+    assert_eq!(report.failures[0].index, 0);
+
+    // Nothing was sent.
+    assert_eq!(base_chain.get_balance(&payer_pubkey), 10_000_000);
+    assert_eq!(base_chain.get_balance(&alice_pubkey), 1_000_000);
+    assert_eq!(base_chain.get_balance(&bob_pubkey), 10_000_000);
+    assert_eq!(base_chain.get_balance(&will_pubkey), 10_000_000);
+}
+
+#[test]
+fn test_in_memory_base_chain_simulates_transactions_in_sequence() {
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+    let alice_pubkey = alice.pubkey();
+    let bob_pubkey = Pubkey::new_unique();
+
+    let base_chain = in_memory_base_chain([(payer_pubkey, system_account(10_000_000))]);
+
+    // The payer funds Alice, who pays Bob out of what she was sent.
+    let transactions = [
+        Transaction::new_signed_with_payer(
+            &[system_instruction::transfer(
+                &payer_pubkey,
+                &alice_pubkey,
+                5_000_000,
+            )],
+            Some(&payer_pubkey),
+            &[&payer],
+            Hash::default(),
+        ),
+        Transaction::new_signed_with_payer(
+            &[system_instruction::transfer(
+                &alice_pubkey,
+                &bob_pubkey,
+                2_000_000,
+            )],
+            Some(&payer_pubkey),
+            &[&payer, &alice],
+            Hash::default(),
+        ),
+    ];
+
+    // Alone, Alice's transfer fails, but in sequence it spends what the
+    // payer's transfer sent her.
+    assert!(base_chain
+        .simulate_transaction(&transactions[1])
+        .unwrap()
+        .error
+        .is_some());
+    let outcomes = base_chain
+        .simulate_transactions_in_sequence(&transactions)
+        .unwrap()
+        .unwrap();
+    assert!(outcomes.iter().all(|outcome| outcome.error.is_none()));
+
+    // Nothing was committed.
+    assert_eq!(base_chain.get_balance(&payer_pubkey), 10_000_000);
+    assert_eq!(base_chain.get_balance(&alice_pubkey), 0);
+    assert_eq!(base_chain.get_balance(&bob_pubkey), 0);
+}
@@ -0,0 +1,222 @@
+mod setup;
+
+use {
+    paytube_svm::{
+        report::TransactionStatus,
+        transaction::{PayTubeTransaction, PayTubeTransactionError},
+    },
+    setup::{
+        clock_account, get_token_amount, in_memory_base_chain, in_memory_channel, mint_account,
+        rent_account, system_account, token_2022, token_account, transfer_fee_mint_account,
+        transfer_fee_token_account,
+    },
+    solana_sdk::{pubkey::Pubkey, signature::Keypair, signer::Signer, sysvar},
+    spl_associated_token_account::get_associated_token_address_with_program_id,
+};
+
+#[test]
+fn test_in_memory_token_2022() {
+    let mint = Pubkey::new_unique();
+
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+
+    let alice_pubkey = alice.pubkey();
+    let alice_token_account_pubkey =
+        get_associated_token_address_with_program_id(&alice_pubkey, &mint, &spl_token_2022::id());
+
+    let bob_pubkey = bob.pubkey();
+    let bob_token_account_pubkey =
+        get_associated_token_address_with_program_id(&bob_pubkey, &mint, &spl_token_2022::id());
+
+    let base_chain = in_memory_base_chain([
+        (mint, token_2022(mint_account())),
+        (payer_pubkey, system_account(10_000_000)),
+        (alice_pubkey, system_account(10_000_000)),
+        (
+            alice_token_account_pubkey,
+            token_2022(token_account(&alice_pubkey, &mint, 10, None)),
+        ),
+        (bob_pubkey, system_account(10_000_000)),
+        (
+            bob_token_account_pubkey,
+            token_2022(token_account(&bob_pubkey, &mint, 10, None)),
+        ),
+    ]);
+
+    let mut paytube_channel = in_memory_channel(payer, &[&alice, &bob], &base_chain);
+    let channel_id = paytube_channel.id();
+
+    let unknown_mint = Pubkey::new_unique();
+    let report = paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 4
+            PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 4, Some(mint))
+                .sign(&alice),
+            // Bob -> Alice 1, of a mint which doesn't exist.
+            PayTubeTransaction::new(channel_id, bob_pubkey, alice_pubkey, 1, Some(unknown_mint))
+                .sign(&bob),
+        ])
+        .unwrap();
+    assert!(report.receipts[0].is_success());
+    assert_eq!(
+        report.receipts[1].status,
+        TransactionStatus::Rejected(PayTubeTransactionError::InvalidMint { mint: unknown_mint })
+    );
+
+    paytube_channel.close().unwrap();
+
+    // Ledger:
+    // Alice:   10 - 4  = 6
+    // Bob:     10 + 4  = 14
+    assert_eq!(
+        get_token_amount(&base_chain.get_account(&alice_token_account_pubkey).unwrap()),
+        6
+    );
+    assert_eq!(
+        get_token_amount(&base_chain.get_account(&bob_token_account_pubkey).unwrap()),
+        14
+    );
+}
+
+#[test]
+fn test_in_memory_token_2022_transfer_fee() {
+    let mint = Pubkey::new_unique();
+
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let will = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+    let [alice_token_account_pubkey, bob_token_account_pubkey, will_token_account_pubkey] =
+        [&alice, &bob, &will].map(|owner| {
+            get_associated_token_address_with_program_id(
+                &owner.pubkey(),
+                &mint,
+                &spl_token_2022::id(),
+            )
+        });
+
+    // A 1% transfer fee.
+    let base_chain = in_memory_base_chain([
+        (sysvar::clock::id(), clock_account()),
+        (mint, transfer_fee_mint_account(100, 1_000_000)),
+        (payer_pubkey, system_account(10_000_000)),
+        (alice.pubkey(), system_account(10_000_000)),
+        (
+            alice_token_account_pubkey,
+            transfer_fee_token_account(&alice.pubkey(), &mint, 10_000),
+        ),
+        (bob.pubkey(), system_account(10_000_000)),
+        (
+            bob_token_account_pubkey,
+            transfer_fee_token_account(&bob.pubkey(), &mint, 10_000),
+        ),
+        (will.pubkey(), system_account(10_000_000)),
+        (
+            will_token_account_pubkey,
+            transfer_fee_token_account(&will.pubkey(), &mint, 10_000),
+        ),
+    ]);
+
+    let mut paytube_channel = in_memory_channel(payer, &[&alice, &bob], &base_chain);
+    let channel_id = paytube_channel.id();
+
+    let report = paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 1_000, of which Bob receives 990.
+            PayTubeTransaction::new(channel_id, alice.pubkey(), bob.pubkey(), 1_000, Some(mint))
+                .sign(&alice),
+            // Bob -> Will 500, of which Will receives 495.
+            PayTubeTransaction::new(channel_id, bob.pubkey(), will.pubkey(), 500, Some(mint))
+                .sign(&bob),
+        ])
+        .unwrap();
+    assert!(report.receipts.iter().all(|receipt| receipt.is_success()));
+
+    let preview = paytube_channel.preview_settlement().unwrap();
+    assert_eq!(preview.withheld_fees.get(&mint), Some(&15));
+
+    paytube_channel.close().unwrap();
+
+    // Settlement transfers are grossed up so every creditor receives exactly
+    // what they were credited in the channel. The fees withheld in the
+    // channel exceed those withheld at settlement, so Alice keeps the rest.
+    //
+    // Ledger:
+    // Alice:   10_000 - (495 + 500)    = 9_005
+    // Bob:     10_000 + 990 - 500      = 10_490
+    // Will:    10_000 + 495            = 10_495
+    assert_eq!(
+        get_token_amount(&base_chain.get_account(&alice_token_account_pubkey).unwrap()),
+        9_005
+    );
+    assert_eq!(
+        get_token_amount(&base_chain.get_account(&bob_token_account_pubkey).unwrap()),
+        10_490
+    );
+    assert_eq!(
+        get_token_amount(&base_chain.get_account(&will_token_account_pubkey).unwrap()),
+        10_495
+    );
+}
+
+#[test]
+fn test_in_memory_token_2022_transfer_fee_to_recipient_without_token_account() {
+    let mint = Pubkey::new_unique();
+
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+    let will_pubkey = Pubkey::new_unique();
+    let [alice_token_account_pubkey, will_token_account_pubkey] = [alice.pubkey(), will_pubkey]
+        .map(|owner| {
+            get_associated_token_address_with_program_id(&owner, &mint, &spl_token_2022::id())
+        });
+
+    // A 1% transfer fee. Will has never had a token account for the mint.
+    let base_chain = in_memory_base_chain([
+        (sysvar::clock::id(), clock_account()),
+        (sysvar::rent::id(), rent_account()),
+        (mint, transfer_fee_mint_account(100, 1_000_000)),
+        (payer_pubkey, system_account(10_000_000)),
+        (alice.pubkey(), system_account(10_000_000)),
+        (
+            alice_token_account_pubkey,
+            transfer_fee_token_account(&alice.pubkey(), &mint, 10_000),
+        ),
+    ]);
+
+    let mut paytube_channel = in_memory_channel(payer, &[&alice], &base_chain);
+    let channel_id = paytube_channel.id();
+
+    // The account opened for Will in the channel carries the mint's transfer
+    // fee extension, so the fee can be withheld from his transfer.
+    let report = paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Will 1_000, of which Will receives 990.
+            PayTubeTransaction::new(channel_id, alice.pubkey(), will_pubkey, 1_000, Some(mint))
+                .sign(&alice),
+        ])
+        .unwrap();
+    assert!(report.receipts[0].is_success());
+
+    paytube_channel.close().unwrap();
+
+    // Ledger:
+    // Alice:   10_000 - 1_000  = 9_000
+    // Will:    0 + 990         = 990
+    assert_eq!(
+        get_token_amount(&base_chain.get_account(&alice_token_account_pubkey).unwrap()),
+        9_000
+    );
+    assert_eq!(
+        get_token_amount(&base_chain.get_account(&will_token_account_pubkey).unwrap()),
+        990
+    );
+}
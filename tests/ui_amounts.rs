@@ -0,0 +1,109 @@
+mod setup;
+
+use {
+    paytube_svm::{
+        report::TransactionStatus,
+        transaction::{PayTubeTransaction, PayTubeTransactionError},
+    },
+    setup::{
+        get_token_amount, in_memory_base_chain, in_memory_channel, mint_account, system_account,
+        token_account,
+    },
+    solana_sdk::{pubkey::Pubkey, signature::Keypair, signer::Signer},
+    spl_associated_token_account::get_associated_token_address,
+};
+
+#[test]
+fn test_ui_amounts() {
+    let mint = Pubkey::new_unique();
+
+    let payer = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    let payer_pubkey = payer.pubkey();
+
+    let alice_pubkey = alice.pubkey();
+    let alice_token_account_pubkey = get_associated_token_address(&alice_pubkey, &mint);
+
+    let bob_pubkey = bob.pubkey();
+    let bob_token_account_pubkey = get_associated_token_address(&bob_pubkey, &mint);
+
+    let base_chain = in_memory_base_chain([
+        (mint, mint_account()),
+        (payer_pubkey, system_account(10_000_000)),
+        (alice_pubkey, system_account(10_000_000)),
+        (
+            alice_token_account_pubkey,
+            token_account(&alice_pubkey, &mint, 10, None),
+        ),
+        (bob_pubkey, system_account(10_000_000)),
+        (
+            bob_token_account_pubkey,
+            token_account(&bob_pubkey, &mint, 10, None),
+        ),
+    ]);
+
+    let mut paytube_channel = in_memory_channel(payer, &[&alice, &bob], &base_chain);
+    let channel_id = paytube_channel.id();
+
+    let transfer = |mint: Option<Pubkey>, nonce: u64| {
+        PayTubeTransaction::new(channel_id, alice_pubkey, bob_pubkey, 0, mint).with_nonce(nonce)
+    };
+
+    // The mint has no decimals, so it can't represent fractional amounts.
+    assert_eq!(
+        transfer(Some(mint), 0)
+            .with_ui_amount("1.5", 0)
+            .unwrap_err(),
+        PayTubeTransactionError::InvalidUiAmount {
+            ui_amount: "1.5".to_string(),
+            decimals: 0,
+        }
+    );
+
+    let sol_transfer = transfer(None, 0).with_ui_amount("0.002", 9).unwrap();
+    assert_eq!(sol_transfer.amount, 2_000_000);
+    assert_eq!(sol_transfer.ui_amount().as_deref(), Some("0.002"));
+
+    let report = paytube_channel
+        .process_paytube_transfers(&[
+            // Alice -> Bob 0.002 SOL
+            sol_transfer.sign(&alice),
+            // Alice -> Bob 7 tokens
+            transfer(Some(mint), 1)
+                .with_ui_amount("7", 0)
+                .unwrap()
+                .sign(&alice),
+            // Alice -> Bob 1 token, denominated in the wrong decimals.
+            transfer(Some(mint), 2)
+                .with_ui_amount("1", 6)
+                .unwrap()
+                .sign(&alice),
+        ])
+        .unwrap();
+    assert!(report.receipts[0].is_success());
+    assert!(report.receipts[1].is_success());
+    assert_eq!(
+        report.receipts[2].status,
+        TransactionStatus::Rejected(PayTubeTransactionError::InvalidDecimals {
+            decimals: 6,
+            expected: 0,
+        })
+    );
+
+    paytube_channel.close().unwrap();
+
+    // Ledger:
+    // Alice:   10 - 7  = 3
+    // Bob:     10 + 7  = 17
+    assert_eq!(base_chain.get_balance(&bob_pubkey), 12_000_000);
+    assert_eq!(
+        get_token_amount(&base_chain.get_account(&alice_token_account_pubkey).unwrap()),
+        3
+    );
+    assert_eq!(
+        get_token_amount(&base_chain.get_account(&bob_token_account_pubkey).unwrap()),
+        17
+    );
+}